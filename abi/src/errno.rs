@@ -0,0 +1,108 @@
+// Error codes of the syscalls. A failing syscall returns the negated code.
+
+#[repr(i64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    NotFound = 2,
+    IoError = 5,
+    ArgumentListTooLong = 7,
+    InvalidExecutable = 8,
+    BadFileDescriptor = 9,
+    NoChild = 10,
+    OutOfMemory = 12,
+    BadAddress = 14,
+    Busy = 16,
+    AlreadyExists = 17,
+    CrossDevice = 18,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    InvalidArgument = 22,
+    TooManyOpenFiles = 24,
+    FileTooLarge = 27,
+    NoSpace = 28,
+    NameTooLong = 36,
+    NoSyscall = 38,
+    DirectoryNotEmpty = 39,
+    NotSupported = 95,
+}
+
+// return values above this are negated error codes
+const MAX_ERRNO: u64 = 4095;
+
+impl Errno {
+    pub fn from_code(code: i64) -> Option<Errno> {
+        let errno = match code {
+            2 => Errno::NotFound,
+            5 => Errno::IoError,
+            7 => Errno::ArgumentListTooLong,
+            8 => Errno::InvalidExecutable,
+            9 => Errno::BadFileDescriptor,
+            10 => Errno::NoChild,
+            12 => Errno::OutOfMemory,
+            14 => Errno::BadAddress,
+            16 => Errno::Busy,
+            17 => Errno::AlreadyExists,
+            18 => Errno::CrossDevice,
+            20 => Errno::NotADirectory,
+            21 => Errno::IsADirectory,
+            22 => Errno::InvalidArgument,
+            24 => Errno::TooManyOpenFiles,
+            27 => Errno::FileTooLarge,
+            28 => Errno::NoSpace,
+            36 => Errno::NameTooLong,
+            38 => Errno::NoSyscall,
+            39 => Errno::DirectoryNotEmpty,
+            95 => Errno::NotSupported,
+            _ => return None,
+        };
+        Some(errno)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Errno::NotFound => "no such file or directory",
+            Errno::IoError => "input/output error",
+            Errno::ArgumentListTooLong => "argument list too long",
+            Errno::InvalidExecutable => "not an executable",
+            Errno::BadFileDescriptor => "bad file descriptor",
+            Errno::NoChild => "no such child process",
+            Errno::OutOfMemory => "out of memory",
+            Errno::BadAddress => "bad address",
+            Errno::Busy => "device or resource busy",
+            Errno::AlreadyExists => "file exists",
+            Errno::CrossDevice => "invalid cross-device link",
+            Errno::NotADirectory => "not a directory",
+            Errno::IsADirectory => "is a directory",
+            Errno::InvalidArgument => "invalid argument",
+            Errno::TooManyOpenFiles => "too many open files",
+            Errno::FileTooLarge => "file too large",
+            Errno::NoSpace => "no space left on device",
+            Errno::NameTooLong => "file name too long",
+            Errno::NoSyscall => "function not implemented",
+            Errno::DirectoryNotEmpty => "directory not empty",
+            Errno::NotSupported => "operation not supported",
+        }
+    }
+}
+
+impl core::fmt::Display for Errno {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Raw syscall return value of a result
+pub fn encode(result: Result<u64, Errno>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+pub fn decode(ret: u64) -> Result<u64, Errno> {
+    if ret > u64::MAX - MAX_ERRNO {
+        Err(Errno::from_code(-(ret as i64)).unwrap_or(Errno::IoError))
+    } else {
+        Ok(ret)
+    }
+}
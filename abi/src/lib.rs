@@ -0,0 +1,9 @@
+// Interface between the kernel and the user programs. Both sides depend on this
+// crate, so the syscall numbers, arguments and error codes cannot drift apart.
+#![no_std]
+
+pub mod errno;
+pub mod syscall;
+
+pub use errno::Errno;
+pub use syscall::*;
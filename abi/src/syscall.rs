@@ -0,0 +1,170 @@
+// Declares the syscall numbers once, along with the list the kernel builds its
+// dispatch table from
+macro_rules! syscalls {
+    ($($name:ident = $number:expr,)*) => {
+        #[repr(u64)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum SyscallNumber {
+            $($name = $number,)*
+        }
+
+        impl SyscallNumber {
+            pub const ALL: &'static [SyscallNumber] = &[$(SyscallNumber::$name,)*];
+            pub const COUNT: usize = SyscallNumber::ALL.len();
+
+            pub fn from_number(number: u64) -> Option<SyscallNumber> {
+                match number {
+                    $($number => Some(SyscallNumber::$name),)*
+                    _ => None,
+                }
+            }
+
+            // Position in `ALL`, which is where the kernel keeps the handler
+            pub const fn index(self) -> usize {
+                let mut index = 0;
+                while index < SyscallNumber::COUNT {
+                    if SyscallNumber::ALL[index] as u64 == self as u64 {
+                        return index;
+                    }
+                    index += 1;
+                }
+                unreachable!()
+            }
+        }
+    };
+}
+
+syscalls! {
+    Write = 1,
+    Exit = 2,
+    Read = 3,
+    RemoveChar = 4,
+    Brk = 6,
+    CheckFsEntryExists = 8,
+    GenerateTaskId = 9,
+    ListDir = 10,
+    MakeDir = 11,
+    MakeFile = 12,
+    WriteFile = 13,
+    ReadFile = 14,
+    RemoveDir = 15,
+    RemoveFile = 16,
+    Run = 17,
+    ClearScreen = 18,
+    Yield = 19,
+    Wait = 20,
+    GetPid = 21,
+    Open = 22,
+    Close = 23,
+    ReadFd = 24,
+    WriteFd = 25,
+    Seek = 26,
+    Fstat = 27,
+    Sync = 28,
+    Shutdown = 29,
+    Mount = 30,
+    ListDisks = 31,
+    Stat = 32,
+    Rename = 33,
+    Check = 34,
+}
+
+// Arguments of a syscall, passed in rdi, rsi, rdx and r8. The number goes in rax,
+// which also holds the return value afterwards.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyscallArgs {
+    pub arg1: u64,
+    pub arg2: u64,
+    pub arg3: u64,
+    pub arg4: u64,
+}
+
+impl SyscallArgs {
+    pub const fn new(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> SyscallArgs {
+        SyscallArgs { arg1, arg2, arg3, arg4 }
+    }
+}
+
+// Argument of `Run`, passed by pointer in arg1. The argument and environment
+// blocks hold NUL terminated strings back to back, environment entries are
+// `KEY=VALUE`. By convention the first argument is the path of the program.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunArgs {
+    pub path_ptr: u64,
+    pub path_len: u64,
+    pub argv_ptr: u64,
+    pub argv_len: u64,
+    pub env_ptr: u64,
+    pub env_len: u64,
+}
+
+// Argument of `Check`, passed by pointer in arg1. The report goes to the output buffer,
+// a line per problem found and a summary line last.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckArgs {
+    pub disk_ptr: u64,
+    pub disk_len: u64,
+    pub output_ptr: u64,
+    pub output_len: u64,
+    pub flags: u64,
+}
+
+// Flags of `Check`
+pub const CHECK_REPAIR: u64 = 1;
+
+// Flags of `Wait` (arg3)
+pub const WAIT_NOHANG: u64 = 1;     // return 0 instead of blocking while the child runs
+
+// Flags of `Open`
+pub const OPEN_READ: u64 = 1;
+pub const OPEN_WRITE: u64 = 2;
+pub const OPEN_CREATE: u64 = 4;     // create the file if it does not exist
+pub const OPEN_TRUNCATE: u64 = 8;   // empty the file when it is opened for writing
+pub const OPEN_APPEND: u64 = 16;    // every write goes to the end of the file
+
+// Where the offset of `Seek` counts from
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+pub const STAT_FILE: u64 = 1;
+pub const STAT_DIRECTORY: u64 = 2;
+
+// Filled by `Fstat` (pointer in arg2) and `Stat` (pointer in arg3)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub file_type: u64,
+    pub size: u64,
+    pub created: DateTime,
+    pub modified: DateTime,
+    pub accessed: DateTime,
+}
+
+// Calendar time of the kernel clock, all zero when the file system does not know it.
+// Fields are in order of significance, so comparing two compares the times.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn is_known(&self) -> bool {
+        self.year != 0
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
@@ -0,0 +1,542 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::{ addr_of, addr_of_mut };
+use core::sync::atomic::{ fence, AtomicBool, AtomicU32, AtomicUsize, Ordering };
+use core::task::{ Context, Poll };
+use futures_util::task::AtomicWaker;
+
+use abi::Errno;
+use crate::ata;
+use crate::fat32::{ AsyncBlockDevice, BlockDevice, BlockFuture };
+use crate::interrupts::set_irq_handler;
+use crate::memory;
+use crate::multitasking;
+use crate::pci;
+use crate::println;
+
+// Driver of the SATA drives behind an AHCI controller (class 01:06), which is what
+// QEMU's q35 machine has instead of IDE. Every port runs a single command at a time,
+// in slot 0, without NCQ. As in `ata`, transfers are futures woken by the interrupt of
+// the controller, and poll the port when it has no line the IDT has a stub for.
+
+// Registers of the controller, in the memory at BAR5 (ABAR)
+// 0x00: Capabilities
+// 0x04: Global control (bit 31 AHCI enable, bit 1 interrupt enable)
+// 0x08: Interrupt status, a bit per port
+// 0x0C: Ports implemented
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+
+const GHC_AE: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+
+// Registers of a port, at 0x100 + port * 0x80
+// 0x00/0x04: Command list base address (1 KiB aligned)
+// 0x08/0x0C: Received FIS base address (256 byte aligned)
+// 0x10: Interrupt status
+// 0x14: Interrupt enable
+// 0x18: Command and status
+// 0x20: Task file data (status in bits 0-7, error in 8-15)
+// 0x24: Signature of the attached device
+// 0x28: SATA status (bits 0-3: 3 when a device is there and talking)
+// 0x30: SATA error
+// 0x38: Command issue, a bit per slot
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0C;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;		// process the command list
+const CMD_SUD: u32 = 1 << 1;	// spin up
+const CMD_FRE: u32 = 1 << 4;	// receive FISes
+const CMD_FR: u32 = 1 << 14;	// FIS receive running
+const CMD_CR: u32 = 1 << 15;	// command list running
+
+const IS_DHRS: u32 = 1 << 0;	// register FIS from the device, the command is done
+const IS_ERRORS: u32 = 1 << 27 | 1 << 28 | 1 << 29 | 1 << 30;	// interface, bus, task file
+
+const TFD_ERR: u32 = 0x01;
+const TFD_DF: u32 = 0x20;
+
+const SSTS_PRESENT: u32 = 3;
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_H2D: u8 = 0x27;
+const FIS_COMMAND: u8 = 0x80;	// the FIS carries a command
+const FIS_LBA: u8 = 1 << 6;
+
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_IDENTIFY: u8 = 0xEC;
+
+// Ports driven, the ICH9 of q35 has 6
+const MAX_PORTS: usize = 6;
+
+// Sectors moved by one command, also the size of the DMA buffers
+const MAX_SECTORS: usize = 64;
+
+// Polls of a register before giving up on the controller
+const TIMEOUT: usize = 1_000_000;
+
+// Command header, the first of the 32 slots of the command list
+// 0-4: FIS length in dwords, 6: write
+#[repr(C)]
+struct CommandHeader {
+	flags: u16,
+	prdt_length: u16,
+	transferred: u32,
+	table: u64,
+	reserved: [u32; 4],
+}
+
+// A single PRD entry: physical address and byte count - 1
+#[repr(C)]
+struct PrdEntry {
+	address: u64,
+	reserved: u32,
+	count: u32,
+}
+
+#[repr(C, align(128))]
+struct CommandTable {
+	fis: [u8; 64],
+	atapi: [u8; 16],
+	reserved: [u8; 48],
+	prdt: [PrdEntry; 1],
+}
+
+// What the controller reads and writes for a port
+#[repr(C, align(1024))]
+struct PortMemory {
+	list: [CommandHeader; 32],
+	received: [u8; 256],
+	table: CommandTable,
+}
+
+impl PortMemory {
+	const fn new() -> Self {
+		PortMemory {
+			list: [const { CommandHeader { flags: 0, prdt_length: 0, transferred: 0, table: 0, reserved: [0; 4] } }; 32],
+			received: [0; 256],
+			table: CommandTable { fis: [0; 64], atapi: [0; 16], reserved: [0; 48], prdt: [PrdEntry { address: 0, reserved: 0, count: 0 }] },
+		}
+	}
+}
+
+// Aligned to its size, so it is one physical range
+#[repr(C, align(32768))]
+struct DmaBuffer([u8; MAX_SECTORS * 512]);
+
+static mut PORT_MEMORY: [PortMemory; MAX_PORTS] = [const { PortMemory::new() }; MAX_PORTS];
+static mut DMA_BUFFERS: [DmaBuffer; MAX_PORTS] = [const { DmaBuffer([0u8; MAX_SECTORS * 512]) }; MAX_PORTS];
+
+// What the IRQ handler and the transfers of a port share
+struct PortState {
+	irq_received: AtomicBool,
+	irq_status: AtomicU32,
+	waker: AtomicWaker,
+	// Held by the transfer using the port, its slot and its DMA buffer
+	busy: AtomicBool,
+}
+
+impl PortState {
+	const fn new() -> Self {
+		PortState {
+			irq_received: AtomicBool::new(false),
+			irq_status: AtomicU32::new(0),
+			waker: AtomicWaker::new(),
+			busy: AtomicBool::new(false),
+		}
+	}
+}
+
+static PORTS: [PortState; MAX_PORTS] = [const { PortState::new() }; MAX_PORTS];
+
+// Registers of the controller, identity mapped
+static ABAR: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+// A drive found by IDENTIFY
+pub struct Drive {
+	pub device: AhciDevice,
+	pub model: String,
+	pub sectors: u64,
+}
+
+#[derive(Copy, Clone)]
+pub struct AhciDevice {
+	port: usize,
+	// Order in which the drive was found, for its name
+	index: usize,
+}
+
+// Sets up the first AHCI controller and looks for ATA drives on its ports. ATAPI
+// drives are skipped.
+pub fn detect() -> Vec<Drive> {
+	let controller = match pci::find_class(0x01, 0x06) {
+		Some(controller) => controller,
+		None => return Vec::new(),
+	};
+	let abar = (controller.bar(5) & 0xFFFF_FFF0) as usize;
+	controller.enable();
+	memory::map_mmio(abar, 0x100 + 32 * 0x80);
+	ABAR.store(abar, Ordering::SeqCst);
+	hba_write(HBA_GHC, hba_read(HBA_GHC) | GHC_AE);
+
+	let implemented = hba_read(HBA_PI);
+	let mut drives = Vec::new();
+	for port in 0..32 {
+		if implemented & (1 << port) == 0 || port_read(port, PORT_SSTS) & 0x0F != SSTS_PRESENT {
+			continue;
+		}
+		if port_read(port, PORT_SIG) != SIG_ATA {
+			continue;
+		}
+		if port >= MAX_PORTS {
+			println!("AHCI: port {} is not supported", port);
+			continue;
+		}
+
+		let device = AhciDevice { port, index: drives.len() };
+		if !device.init() {
+			println!("AHCI: port {} does not respond", port);
+			continue;
+		}
+		let identify = match device.identify() {
+			Some(identify) => identify,
+			None => continue,
+		};
+		// 48 bit LBA in words 100-103 when word 83 says it is supported
+		let sectors = if identify[83] & (1 << 10) != 0 {
+			identify[100..104].iter().rev().fold(0u64, |sectors, &word| sectors << 16 | word as u64)
+		} else {
+			identify[60] as u64 | (identify[61] as u64) << 16
+		};
+		drives.push(Drive { device, model: ata::model_string(&identify), sectors });
+	}
+
+	// the line is only usable if the IDT has a stub for it
+	let line = controller.read(0x3C) as u8;
+	if !drives.is_empty() && matches!(line, 9 | 10 | 11) {
+		set_irq_handler(line, handle_irq);
+		for drive in drives.iter() {
+			port_write(drive.device.port, PORT_IS, u32::MAX);
+			port_write(drive.device.port, PORT_IE, IS_DHRS | IS_ERRORS);
+		}
+		INTERRUPTS.store(true, Ordering::SeqCst);
+		hba_write(HBA_GHC, hba_read(HBA_GHC) | GHC_IE);
+	} else if !drives.is_empty() {
+		println!("AHCI: no usable interrupt line ({}), polling the disks", line);
+	}
+	drives
+}
+
+impl AhciDevice {
+	// Name in the style of Linux: sda, sdb, ...
+	pub fn name(&self) -> String {
+		format!("sd{}", (b'a' + self.index as u8) as char)
+	}
+
+	pub async fn read(&self, lba: u32, buf: &mut [u8]) -> Result<(), Errno> {
+		if buf.len() % 512 != 0 {
+			return Err(Errno::InvalidArgument);
+		}
+		let _lock = PortLock::acquire(self.port).await;
+		for (index, chunk) in buf.chunks_mut(MAX_SECTORS * 512).enumerate() {
+			let lba = lba as u64 + (index * MAX_SECTORS) as u64;
+			self.transfer(ATA_READ_DMA_EXT, lba, chunk.len() / 512, false).await?;
+			chunk.copy_from_slice(&dma_buffer(self.port)[..chunk.len()]);
+		}
+		Ok(())
+	}
+
+	pub async fn write(&self, lba: u32, buf: &[u8]) -> Result<(), Errno> {
+		if buf.len() % 512 != 0 {
+			return Err(Errno::InvalidArgument);
+		}
+		let _lock = PortLock::acquire(self.port).await;
+		for (index, chunk) in buf.chunks(MAX_SECTORS * 512).enumerate() {
+			let lba = lba as u64 + (index * MAX_SECTORS) as u64;
+			dma_buffer(self.port)[..chunk.len()].copy_from_slice(chunk);
+			self.transfer(ATA_WRITE_DMA_EXT, lba, chunk.len() / 512, true).await?;
+		}
+		Ok(())
+	}
+
+	fn read_register(&self, register: usize) -> u32 {
+		port_read(self.port, register)
+	}
+
+	fn write_register(&self, register: usize, value: u32) {
+		port_write(self.port, register, value)
+	}
+
+	// Points the port at its command list and received FIS area and starts it
+	fn init(&self) -> bool {
+		let active_table = &memory::controller().active_table;
+		let memory = unsafe { addr_of_mut!(PORT_MEMORY[self.port]) };
+		let physical = |address: usize| active_table.translate(address).map(|address| address as u64);
+		let (list, received, table, buffer) = unsafe {
+			match (
+				physical(addr_of!((*memory).list) as usize),
+				physical(addr_of!((*memory).received) as usize),
+				physical(addr_of!((*memory).table) as usize),
+				physical(addr_of!(DMA_BUFFERS[self.port]) as usize),
+			) {
+				(Some(list), Some(received), Some(table), Some(buffer)) => (list, received, table, buffer),
+				_ => return false,
+			}
+		};
+
+		if !self.stop() {
+			return false;
+		}
+		unsafe {
+			addr_of_mut!((*memory).list[0].table).write_volatile(table);
+			addr_of_mut!((*memory).table.prdt[0].address).write_volatile(buffer);
+		}
+		self.write_register(PORT_CLB, list as u32);
+		self.write_register(PORT_CLBU, (list >> 32) as u32);
+		self.write_register(PORT_FB, received as u32);
+		self.write_register(PORT_FBU, (received >> 32) as u32);
+		self.write_register(PORT_SERR, u32::MAX);
+		self.write_register(PORT_IS, u32::MAX);
+		self.start()
+	}
+
+	fn stop(&self) -> bool {
+		self.write_register(PORT_CMD, self.read_register(PORT_CMD) & !CMD_ST);
+		if !self.wait_clear(CMD_CR) {
+			return false;
+		}
+		self.write_register(PORT_CMD, self.read_register(PORT_CMD) & !CMD_FRE);
+		self.wait_clear(CMD_FR)
+	}
+
+	fn start(&self) -> bool {
+		if !self.wait_clear(CMD_CR) {
+			return false;
+		}
+		self.write_register(PORT_CMD, self.read_register(PORT_CMD) | CMD_SUD | CMD_FRE);
+		self.write_register(PORT_CMD, self.read_register(PORT_CMD) | CMD_ST);
+		true
+	}
+
+	// Waits for the bits of the command register to clear
+	fn wait_clear(&self, bits: u32) -> bool {
+		for _ in 0..TIMEOUT {
+			if self.read_register(PORT_CMD) & bits == 0 {
+				return true;
+			}
+			core::hint::spin_loop();
+		}
+		false
+	}
+
+	// Restarts the port after an error, which stops it from processing commands
+	fn recover(&self) {
+		self.stop();
+		self.write_register(PORT_SERR, u32::MAX);
+		self.write_register(PORT_IS, u32::MAX);
+		self.start();
+	}
+
+	// Fills slot 0 with the command for `count` sectors of the DMA buffer and issues it
+	fn issue(&self, command: u8, lba: u64, count: usize, write: bool) {
+		let memory = unsafe { &mut *addr_of_mut!(PORT_MEMORY[self.port]) };
+		let fis = &mut memory.table.fis;
+		fis.fill(0);
+		fis[0] = FIS_TYPE_H2D;
+		fis[1] = FIS_COMMAND;
+		fis[2] = command;
+		fis[4] = lba as u8;										// LBA 0-7
+		fis[5] = (lba >> 8) as u8;								// LBA 8-15
+		fis[6] = (lba >> 16) as u8;								// LBA 16-23
+		fis[7] = FIS_LBA;
+		fis[8] = (lba >> 24) as u8;								// LBA 24-31
+		fis[9] = (lba >> 32) as u8;								// LBA 32-39
+		fis[10] = (lba >> 40) as u8;							// LBA 40-47
+		fis[12] = count as u8;									// Sectors to transfer
+		fis[13] = (count >> 8) as u8;
+
+		memory.table.prdt[0].count = (count * 512 - 1) as u32;
+		let header = &mut memory.list[0];
+		header.flags = 5 | if write { 1 << 6 } else { 0 };		// 5 dwords of FIS
+		header.prdt_length = 1;
+		header.transferred = 0;
+
+		PORTS[self.port].irq_received.store(false, Ordering::SeqCst);
+		fence(Ordering::SeqCst);
+		self.write_register(PORT_CI, 1);
+	}
+
+	// Moves the sectors between the disk and the DMA buffer of the port
+	async fn transfer(&self, command: u8, lba: u64, count: usize, write: bool) -> Result<(), Errno> {
+		self.issue(command, lba, count, write);
+		let status = if INTERRUPTS.load(Ordering::SeqCst) {
+			loop {
+				let status = Irq(self.port).await;
+				if status & IS_ERRORS != 0 || self.read_register(PORT_CI) & 1 == 0 {
+					break status;
+				}
+			}
+		} else {
+			self.poll()
+		};
+		fence(Ordering::SeqCst);
+		self.check(status, lba)
+	}
+
+	// Interrupt status once slot 0 is done or failed
+	fn poll(&self) -> u32 {
+		loop {
+			let status = self.read_register(PORT_IS);
+			if status & IS_ERRORS != 0 || self.read_register(PORT_CI) & 1 == 0 {
+				self.write_register(PORT_IS, status);
+				return status;
+			}
+			core::hint::spin_loop();
+		}
+	}
+
+	fn check(&self, status: u32, lba: u64) -> Result<(), Errno> {
+		let task_file = self.read_register(PORT_TFD);
+		if status & IS_ERRORS != 0 || task_file & (TFD_ERR | TFD_DF) != 0 {
+			println!("{}: AHCI error at sector {}: IS={:#x}, TFD={:#x}", self.name(), lba, status, task_file);
+			self.recover();
+			return Err(Errno::IoError);
+		}
+		Ok(())
+	}
+
+	// The 256 words IDENTIFY returns. Polls, the controller does not raise interrupts yet.
+	fn identify(&self) -> Option<[u16; 256]> {
+		self.issue(ATA_IDENTIFY, 0, 1, false);
+		let status = self.poll();
+		fence(Ordering::SeqCst);
+		self.check(status, 0).ok()?;
+
+		let buffer = dma_buffer(self.port);
+		let mut identify = [0u16; 256];
+		for (index, word) in identify.iter_mut().enumerate() {
+			*word = u16::from_le_bytes([buffer[index * 2], buffer[index * 2 + 1]]);
+		}
+		Some(identify)
+	}
+}
+
+impl BlockDevice for AhciDevice {
+	fn read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) {
+		if let Err(e) = self.try_read_sector(lba, buf) {
+			panic!("{}: read of sector {}: {}", self.name(), lba, e);
+		}
+	}
+
+	fn try_read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), Errno> {
+		multitasking::block_on(self.read(lba, &mut buf[..]))
+	}
+
+	fn write_sector(&mut self, lba: u32, buf: &[u8; 512]) {
+		if let Err(e) = multitasking::block_on(self.write(lba, &buf[..])) {
+			panic!("{}: write of sector {}: {}", self.name(), lba, e);
+		}
+	}
+}
+
+impl AsyncBlockDevice for AhciDevice {
+	fn read_sectors<'a>(&'a self, lba: u32, buf: &'a mut [u8]) -> BlockFuture<'a> {
+		Box::pin(self.read(lba, buf))
+	}
+
+	fn write_sectors<'a>(&'a self, lba: u32, buf: &'a [u8]) -> BlockFuture<'a> {
+		Box::pin(self.write(lba, buf))
+	}
+}
+
+fn hba_read(register: usize) -> u32 {
+	unsafe { ((ABAR.load(Ordering::SeqCst) + register) as *const u32).read_volatile() }
+}
+
+fn hba_write(register: usize, value: u32) {
+	unsafe { ((ABAR.load(Ordering::SeqCst) + register) as *mut u32).write_volatile(value) }
+}
+
+fn port_read(port: usize, register: usize) -> u32 {
+	hba_read(0x100 + port * 0x80 + register)
+}
+
+fn port_write(port: usize, register: usize, value: u32) {
+	hba_write(0x100 + port * 0x80 + register, value)
+}
+
+fn handle_irq() {
+	let pending = hba_read(HBA_IS);
+	for (port, state) in PORTS.iter().enumerate() {
+		if pending & (1 << port) == 0 {
+			continue;
+		}
+		let status = port_read(port, PORT_IS);
+		port_write(port, PORT_IS, status);
+		state.irq_status.store(status, Ordering::SeqCst);
+		state.irq_received.store(true, Ordering::SeqCst);
+		state.waker.wake();
+	}
+	hba_write(HBA_IS, pending);
+}
+
+fn dma_buffer(port: usize) -> &'static mut [u8; MAX_SECTORS * 512] {
+	unsafe { &mut (*addr_of_mut!(DMA_BUFFERS[port])).0 }
+}
+
+// Exclusive use of a port, released when dropped
+struct PortLock(usize);
+
+impl PortLock {
+	async fn acquire(port: usize) -> PortLock {
+		while PORTS[port].busy.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+			multitasking::cooperate().await;
+		}
+		PortLock(port)
+	}
+}
+
+impl Drop for PortLock {
+	fn drop(&mut self) {
+		PORTS[self.0].busy.store(false, Ordering::SeqCst);
+	}
+}
+
+// Resolves to the interrupt status the handler read once the port interrupted
+struct Irq(usize);
+
+impl Future for Irq {
+	type Output = u32;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u32> {
+		let state = &PORTS[self.0];
+		if state.irq_received.swap(false, Ordering::SeqCst) {
+			return Poll::Ready(state.irq_status.load(Ordering::SeqCst));
+		}
+
+		state.waker.register(&cx.waker());
+		if state.irq_received.swap(false, Ordering::SeqCst) {
+			state.waker.take();
+			Poll::Ready(state.irq_status.load(Ordering::SeqCst))
+		} else {
+			Poll::Pending
+		}
+	}
+}
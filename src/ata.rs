@@ -0,0 +1,572 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::{ addr_of, addr_of_mut };
+use core::sync::atomic::{ AtomicBool, AtomicU8, AtomicU16, AtomicU32, Ordering };
+use core::task::{ Context, Poll };
+use futures_util::task::AtomicWaker;
+use x86::io::{ inb, inw, outb, outl, outw };
+
+use abi::Errno;
+use crate::fat32::{ AsyncBlockDevice, BlockDevice, BlockFuture };
+use crate::interrupts::set_irq_handler;
+use crate::memory;
+use crate::multitasking;
+use crate::pci;
+use crate::println;
+
+// Driver of the drives on the two legacy IDE channels. Transfers are written once,
+// as futures: with interrupts they wait for the IRQ of the channel and are woken by
+// its handler, so executor tasks keep running meanwhile. The blocking `BlockDevice`
+// interface runs the same futures with `multitasking::block_on`.
+
+// ATA ports, from the base of the channel (0x1F0 primary, 0x170 secondary)
+// +0: Data Register (read/write)
+// +1: Error Register
+// +2: Sector Count
+// +3: LBA Low
+// +4: LBA Mid
+// +5: LBA High
+// +6: Device/Head
+// +7: Status/Command
+// The control port (0x3F6 primary, 0x376 secondary) is Alternate Status/Device Control.
+
+// DF - Device fault
+// DRQ - Data request
+
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_HEAD: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_BSY: u8 = 0x80;
+const STATUS_DF: u8 = 0x20;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_ERR: u8 = 0x01;
+
+const CONTROL_NIEN: u8 = 0x02;		// no interrupts from the drives
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_READ_DMA: u8 = 0xC8;
+const CMD_WRITE_DMA: u8 = 0xCA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// Bus Master IDE registers of a channel, at BAR4 of the IDE controller (+8 for the
+// secondary channel)
+// 0: Command (bit 0 start, bit 3 direction: 1 = into memory)
+// 2: Status (bit 0 active, bit 1 error, bit 2 interrupt, the last two clear on write)
+// 4: Physical address of the PRD table
+const BM_COMMAND: u16 = 0;
+const BM_STATUS: u16 = 2;
+const BM_PRDT: u16 = 4;
+
+const BM_START: u8 = 0x01;
+const BM_INTO_MEMORY: u8 = 0x08;
+const BM_STATUS_ERROR: u8 = 0x02;
+const BM_STATUS_IRQ: u8 = 0x04;
+
+// Sectors moved by one command, also the size of the DMA buffers
+const MAX_SECTORS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferMode {
+	// PIO, busy-waiting on the status port
+	Polling,
+	// PIO, waiting for the IRQ after every sector
+	Interrupt,
+	// Bus Master IDE, one interrupt per command
+	Dma,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+	Primary,
+	Secondary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+	Master,
+	Slave,
+}
+
+impl Channel {
+	fn index(self) -> usize {
+		self as usize
+	}
+
+	fn base(self) -> u16 {
+		match self {
+			Channel::Primary => 0x1F0,
+			Channel::Secondary => 0x170,
+		}
+	}
+
+	fn control(self) -> u16 {
+		match self {
+			Channel::Primary => 0x3F6,
+			Channel::Secondary => 0x376,
+		}
+	}
+
+	fn irq(self) -> u8 {
+		match self {
+			Channel::Primary => 14,
+			Channel::Secondary => 15,
+		}
+	}
+
+	fn state(self) -> &'static ChannelState {
+		&CHANNELS[self.index()]
+	}
+}
+
+// What the IRQ handler and the transfers of a channel share
+struct ChannelState {
+	irq_received: AtomicBool,
+	irq_status: AtomicU8,
+	irq_bm_status: AtomicU8,
+	waker: AtomicWaker,
+	// Held by the transfer using the channel, its registers and its DMA buffer
+	busy: AtomicBool,
+	// I/O base of the bus master registers, 0 without one
+	bus_master: AtomicU16,
+	prdt_address: AtomicU32,
+	buffer_address: AtomicU32,
+}
+
+impl ChannelState {
+	const fn new() -> Self {
+		ChannelState {
+			irq_received: AtomicBool::new(false),
+			irq_status: AtomicU8::new(0),
+			irq_bm_status: AtomicU8::new(0),
+			waker: AtomicWaker::new(),
+			busy: AtomicBool::new(false),
+			bus_master: AtomicU16::new(0),
+			prdt_address: AtomicU32::new(0),
+			buffer_address: AtomicU32::new(0),
+		}
+	}
+}
+
+static CHANNELS: [ChannelState; 2] = [ChannelState::new(), ChannelState::new()];
+
+// A single PRD entry: physical address, byte count and the end of table flag.
+// 8 byte aligned, so it never crosses a 64 KiB boundary.
+#[repr(C, align(8))]
+struct PrdTable(u64);
+
+// Aligned to its size, so it never crosses a 64 KiB boundary either
+#[repr(C, align(32768))]
+struct DmaBuffer([u8; MAX_SECTORS * 512]);
+
+static mut PRD_TABLES: [PrdTable; 2] = [PrdTable(0), PrdTable(0)];
+static mut DMA_BUFFERS: [DmaBuffer; 2] = [DmaBuffer([0u8; MAX_SECTORS * 512]), DmaBuffer([0u8; MAX_SECTORS * 512])];
+
+// A drive found by IDENTIFY
+pub struct Drive {
+	pub device: AtaDevice,
+	pub model: String,
+	// Addressable with 28 bit LBA, which is what the driver uses
+	pub sectors: u64,
+}
+
+#[derive(Copy, Clone)]
+pub struct AtaDevice {
+	channel: Channel,
+	position: Position,
+	mode: TransferMode,
+}
+
+// Looks for ATA drives on both channels and sets the channels up for the mode. DMA
+// falls back to interrupts without a bus master IDE controller or for drives that
+// can not do it. ATAPI drives (the CD-ROM) are skipped.
+pub fn detect(mode: TransferMode) -> Vec<Drive> {
+	let bus_master = mode == TransferMode::Dma && init_bus_master();
+	if mode == TransferMode::Dma && !bus_master {
+		println!("No bus master IDE, using interrupts for the disks");
+	}
+
+	let mut drives = Vec::new();
+	for channel in [Channel::Primary, Channel::Secondary] {
+		// no interrupts while identifying, the handler is not there yet
+		unsafe { outb(channel.control(), CONTROL_NIEN) };
+		let found = drives.len();
+		for position in [Position::Master, Position::Slave] {
+			let identify = match identify(channel, position) {
+				Some(identify) => identify,
+				None => continue,
+			};
+			let dma = identify[49] & (1 << 8) != 0;
+			let drive_mode = match mode {
+				TransferMode::Dma if !bus_master || !dma => TransferMode::Interrupt,
+				mode => mode,
+			};
+			drives.push(Drive {
+				device: AtaDevice { channel, position, mode: drive_mode },
+				model: model_string(&identify),
+				sectors: identify[60] as u64 | (identify[61] as u64) << 16,
+			});
+		}
+
+		if drives.len() > found && mode != TransferMode::Polling {
+			set_irq_handler(channel.irq(), match channel {
+				Channel::Primary => handle_primary_irq,
+				Channel::Secondary => handle_secondary_irq,
+			});
+			unsafe { outb(channel.control(), 0) };
+		}
+	}
+	drives
+}
+
+impl AtaDevice {
+	// Name in the style of Linux: hda, hdb (primary master, slave), hdc, hdd
+	pub fn name(&self) -> String {
+		let letter = b'a' + (self.channel.index() * 2 + self.position as usize) as u8;
+		format!("hd{}", letter as char)
+	}
+
+	pub async fn read(&self, lba: u32, buf: &mut [u8]) -> Result<(), Errno> {
+		if buf.len() % 512 != 0 {
+			return Err(Errno::InvalidArgument);
+		}
+		let _lock = ChannelLock::acquire(self.channel).await;
+		for (index, chunk) in buf.chunks_mut(MAX_SECTORS * 512).enumerate() {
+			let lba = lba + (index * MAX_SECTORS) as u32;
+			if self.mode == TransferMode::Dma {
+				self.dma(lba, chunk.len() / 512, false).await?;
+				chunk.copy_from_slice(&dma_buffer(self.channel)[..chunk.len()]);
+			} else {
+				self.pio_read(lba, chunk).await?;
+			}
+		}
+		Ok(())
+	}
+
+	pub async fn write(&self, lba: u32, buf: &[u8]) -> Result<(), Errno> {
+		if buf.len() % 512 != 0 {
+			return Err(Errno::InvalidArgument);
+		}
+		let _lock = ChannelLock::acquire(self.channel).await;
+		for (index, chunk) in buf.chunks(MAX_SECTORS * 512).enumerate() {
+			let lba = lba + (index * MAX_SECTORS) as u32;
+			if self.mode == TransferMode::Dma {
+				dma_buffer(self.channel)[..chunk.len()].copy_from_slice(chunk);
+				self.dma(lba, chunk.len() / 512, true).await?;
+			} else {
+				self.pio_write(lba, chunk).await?;
+			}
+		}
+		Ok(())
+	}
+
+	fn port(&self, register: u16) -> u16 {
+		self.channel.base() + register
+	}
+
+	fn issue(&self, lba: u32, count: usize, command: u8) {
+		let drive = match self.position {
+			Position::Master => 0xE0,
+			Position::Slave => 0xF0,
+		};
+		unsafe {
+			outb(self.port(DRIVE_HEAD), drive | ((lba >> 24) & 0x0F) as u8);	// LBA, drive
+			outb(self.port(SECTOR_COUNT), count as u8); 					// Sectors to transfer
+			outb(self.port(LBA_LOW), (lba & 0xFF) as u8); 					// LBA 0–7
+			outb(self.port(LBA_MID), ((lba >> 8) & 0xFF) as u8); 			// LBA 8–15
+			outb(self.port(LBA_HIGH), ((lba >> 16) & 0xFF) as u8); 			// LBA 16–23
+			outb(self.port(COMMAND), command);
+		}
+	}
+
+	// Status once the drive is done with the command or with the current sector
+	async fn completion(&self) -> u8 {
+		match self.mode {
+			TransferMode::Polling => wait_not_busy(self.channel),
+			_ => Irq(self.channel).await,
+		}
+	}
+
+	async fn pio_read(&self, lba: u32, buf: &mut [u8]) -> Result<(), Errno> {
+		self.channel.state().irq_received.store(false, Ordering::SeqCst);
+		self.issue(lba, buf.len() / 512, CMD_READ_SECTORS);
+		for sector in buf.chunks_mut(512) {
+			let status = self.completion().await;
+			check(self.channel, status)?;
+			if status & STATUS_DRQ == 0 {
+				return Err(Errno::IoError);
+			}
+			read_words(self.channel, sector);
+		}
+		Ok(())
+	}
+
+	// There is no interrupt before the first sector, only after each one was written
+	async fn pio_write(&self, lba: u32, buf: &[u8]) -> Result<(), Errno> {
+		self.channel.state().irq_received.store(false, Ordering::SeqCst);
+		self.issue(lba, buf.len() / 512, CMD_WRITE_SECTORS);
+		let mut status = wait_not_busy(self.channel);
+		for sector in buf.chunks(512) {
+			check(self.channel, status)?;
+			if status & STATUS_DRQ == 0 {
+				return Err(Errno::IoError);
+			}
+			for i in 0..256 {								// write 512 bytes (256 words)
+				let word = sector[i * 2] as u16 | ((sector[i * 2 + 1] as u16) << 8);
+				unsafe { outw(self.port(DATA), word) };
+			}
+			status = self.completion().await;
+		}
+		check(self.channel, status)
+	}
+
+	// Moves the sectors between the disk and the DMA buffer of the channel
+	async fn dma(&self, lba: u32, count: usize, write: bool) -> Result<(), Errno> {
+		let state = self.channel.state();
+		let bus_master = state.bus_master.load(Ordering::SeqCst);
+		let direction = if write { 0 } else { BM_INTO_MEMORY };
+		let entry = state.buffer_address.load(Ordering::SeqCst) as u64 | ((count * 512) as u64) << 32 | 1 << 63;
+		unsafe {
+			addr_of_mut!(PRD_TABLES[self.channel.index()].0).write_volatile(entry);
+			outb(bus_master + BM_COMMAND, 0);
+			outl(bus_master + BM_PRDT, state.prdt_address.load(Ordering::SeqCst));
+			outb(bus_master + BM_STATUS, inb(bus_master + BM_STATUS) | BM_STATUS_ERROR | BM_STATUS_IRQ);
+			outb(bus_master + BM_COMMAND, direction);
+		}
+
+		state.irq_received.store(false, Ordering::SeqCst);
+		self.issue(lba, count, if write { CMD_WRITE_DMA } else { CMD_READ_DMA });
+		unsafe { outb(bus_master + BM_COMMAND, direction | BM_START) };
+
+		let status = Irq(self.channel).await;
+		unsafe { outb(bus_master + BM_COMMAND, 0) };
+		if state.irq_bm_status.load(Ordering::SeqCst) & BM_STATUS_ERROR != 0 {
+			println!("{}: DMA error at sector {}", self.name(), lba);
+			return Err(Errno::IoError);
+		}
+		check(self.channel, status)
+	}
+}
+
+impl BlockDevice for AtaDevice {
+	fn read_sector(&mut self, lba: u32, buf: &mut[u8; 512]) {
+		if let Err(e) = self.try_read_sector(lba, buf) {
+			panic!("{}: read of sector {}: {}", self.name(), lba, e);
+		}
+	}
+
+	fn try_read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), Errno> {
+		multitasking::block_on(self.read(lba, &mut buf[..]))
+	}
+
+	fn write_sector(&mut self, lba: u32, buf: &[u8; 512]) {
+		if let Err(e) = multitasking::block_on(self.write(lba, &buf[..])) {
+			panic!("{}: write of sector {}: {}", self.name(), lba, e);
+		}
+	}
+}
+
+impl AsyncBlockDevice for AtaDevice {
+	fn read_sectors<'a>(&'a self, lba: u32, buf: &'a mut [u8]) -> BlockFuture<'a> {
+		Box::pin(self.read(lba, buf))
+	}
+
+	fn write_sectors<'a>(&'a self, lba: u32, buf: &'a [u8]) -> BlockFuture<'a> {
+		Box::pin(self.write(lba, buf))
+	}
+}
+
+// The 256 words IDENTIFY returns, None if there is no ATA drive at the position.
+// Polls, the channel does not raise interrupts yet.
+fn identify(channel: Channel, position: Position) -> Option<[u16; 256]> {
+	let port = |register: u16| channel.base() + register;
+	unsafe {
+		outb(port(DRIVE_HEAD), if position == Position::Master { 0xA0 } else { 0xB0 });
+		for _ in 0..4 {
+			inb(channel.control());
+		}
+		outb(port(SECTOR_COUNT), 0);
+		outb(port(LBA_LOW), 0);
+		outb(port(LBA_MID), 0);
+		outb(port(LBA_HIGH), 0);
+		outb(port(COMMAND), CMD_IDENTIFY);
+
+		let status = inb(port(STATUS));
+		if status == 0 || status == 0xFF {					// no drive, or no channel
+			return None;
+		}
+		let mut timeout = 1_000_000;
+		while inb(port(STATUS)) & STATUS_BSY != 0 {
+			timeout -= 1;
+			if timeout == 0 {
+				return None;
+			}
+		}
+		// ATAPI and SATA drives set these instead of answering
+		if inb(port(LBA_MID)) != 0 || inb(port(LBA_HIGH)) != 0 {
+			return None;
+		}
+		loop {
+			let status = inb(port(STATUS));
+			if status & STATUS_ERR != 0 {
+				return None;
+			}
+			if status & STATUS_DRQ != 0 {
+				break;
+			}
+		}
+
+		let mut identify = [0u16; 256];
+		for word in identify.iter_mut() {
+			*word = inw(port(DATA));
+		}
+		Some(identify)
+	}
+}
+
+// Words 27-46, two characters each with the first one in the high byte
+pub fn model_string(identify: &[u16; 256]) -> String {
+	let bytes: Vec<u8> = identify[27..47].iter().flat_map(|word| word.to_be_bytes()).collect();
+	String::from_utf8_lossy(&bytes).trim().into()
+}
+
+// Finds the bus master registers and the physical addresses of the DMA structures
+fn init_bus_master() -> bool {
+	let controller = match pci::find_class(0x01, 0x01) {	// IDE controller
+		Some(controller) => controller,
+		None => return false,
+	};
+	let bar4 = controller.bar(4);
+	if bar4 & 1 == 0 {										// not in I/O space
+		return false;
+	}
+
+	let active_table = &memory::controller().active_table;
+	let mut addresses = [(0u32, 0u32); 2];
+	for index in 0..2 {
+		let prdt = active_table.translate(unsafe { addr_of!(PRD_TABLES[index]) } as usize);
+		let buffer = active_table.translate(unsafe { addr_of!(DMA_BUFFERS[index]) } as usize);
+		addresses[index] = match (prdt, buffer) {
+			(Some(prdt), Some(buffer)) if prdt <= u32::MAX as usize && buffer <= u32::MAX as usize => (prdt as u32, buffer as u32),
+			_ => return false,
+		};
+	}
+
+	let command = controller.read(0x04);
+	controller.write(0x04, command | 1 << 0 | 1 << 2);		// I/O space, bus master
+	for (index, state) in CHANNELS.iter().enumerate() {
+		state.prdt_address.store(addresses[index].0, Ordering::SeqCst);
+		state.buffer_address.store(addresses[index].1, Ordering::SeqCst);
+		state.bus_master.store((bar4 & 0xFFFC) as u16 + index as u16 * 8, Ordering::SeqCst);
+	}
+	true
+}
+
+fn handle_primary_irq() {
+	handle_irq(Channel::Primary);
+}
+
+fn handle_secondary_irq() {
+	handle_irq(Channel::Secondary);
+}
+
+fn handle_irq(channel: Channel) {
+	let state = channel.state();
+	let bus_master = state.bus_master.load(Ordering::SeqCst);
+	unsafe {
+		if bus_master != 0 {
+			let status = inb(bus_master + BM_STATUS);
+			state.irq_bm_status.store(status, Ordering::SeqCst);
+			outb(bus_master + BM_STATUS, status);			// clears the error and interrupt bits
+		}
+		state.irq_status.store(inb(channel.base() + STATUS), Ordering::SeqCst);	// also acknowledges it
+	}
+	state.irq_received.store(true, Ordering::SeqCst);
+	state.waker.wake();
+}
+
+fn dma_buffer(channel: Channel) -> &'static mut [u8; MAX_SECTORS * 512] {
+	unsafe { &mut (*addr_of_mut!(DMA_BUFFERS[channel.index()])).0 }
+}
+
+fn read_words(channel: Channel, sector: &mut [u8]) {
+	for i in 0..256 {										// Read 512 bytes (256 words)
+		let w = unsafe { inw(channel.base() + DATA) };
+		sector[i * 2] = (w & 0xFF) as u8;
+		sector[i * 2 + 1] = (w >> 8) as u8;
+	}
+}
+
+fn wait_not_busy(channel: Channel) -> u8 {
+	unsafe {
+		for _ in 0..4 {										// 400ns for the status to be valid
+			inb(channel.control());
+		}
+		loop {
+			let status = inb(channel.base() + STATUS);
+			if status & STATUS_BSY == 0 {
+				return status;
+			}
+		}
+	}
+}
+
+fn check(channel: Channel, status: u8) -> Result<(), Errno> {
+	if status & (STATUS_ERR | STATUS_DF) != 0 {
+		let error = unsafe { inb(channel.base() + ERROR) };
+		println!("ATA Error: Status={:#x}, Error={:#x}", status, error);
+		return Err(Errno::IoError);
+	}
+	Ok(())
+}
+
+// Exclusive use of a channel, released when dropped
+struct ChannelLock(Channel);
+
+impl ChannelLock {
+	async fn acquire(channel: Channel) -> ChannelLock {
+		while channel.state().busy.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+			multitasking::cooperate().await;
+		}
+		ChannelLock(channel)
+	}
+}
+
+impl Drop for ChannelLock {
+	fn drop(&mut self) {
+		self.0.state().busy.store(false, Ordering::SeqCst);
+	}
+}
+
+// Resolves to the status the handler read once the IRQ of the channel arrived
+struct Irq(Channel);
+
+impl Future for Irq {
+	type Output = u8;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u8> {
+		let state = self.0.state();
+		if state.irq_received.swap(false, Ordering::SeqCst) {
+			return Poll::Ready(state.irq_status.load(Ordering::SeqCst));
+		}
+
+		state.waker.register(&cx.waker());
+		if state.irq_received.swap(false, Ordering::SeqCst) {
+			state.waker.take();
+			Poll::Ready(state.irq_status.load(Ordering::SeqCst))
+		} else {
+			Poll::Pending
+		}
+	}
+}
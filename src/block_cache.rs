@@ -0,0 +1,131 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use abi::Errno;
+use crate::fat32::BlockDevice;
+
+// Write-back cache of sectors in front of another block device. Writes stay in
+// memory until the sector is evicted or `sync` is called, so anything that must
+// survive a power off has to be synced first.
+
+// Sectors kept by default, 512 KiB
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+struct CachedBlock {
+	lba: u32,
+	data: [u8; 512],
+	dirty: bool,
+	last_used: u64,
+}
+
+pub struct BlockCache {
+	device: Box<dyn BlockDevice>,
+	capacity: usize,
+	blocks: Vec<CachedBlock>,
+	// lba -> index in `blocks`
+	index: BTreeMap<u32, usize>,
+	// last_used -> index in `blocks`, the least recently used sector first
+	recency: BTreeMap<u64, usize>,
+	clock: u64,
+}
+
+impl BlockCache {
+	pub fn new(device: Box<dyn BlockDevice>, capacity: usize) -> Self {
+		BlockCache {
+			device,
+			capacity: capacity.max(1),
+			blocks: Vec::new(),
+			index: BTreeMap::new(),
+			recency: BTreeMap::new(),
+			clock: 0,
+		}
+	}
+
+	// Writes every dirty sector to the device, in LBA order
+	pub fn sync(&mut self) {
+		for &slot in self.index.values() {
+			let block = &mut self.blocks[slot];
+			if block.dirty {
+				self.device.write_sector(block.lba, &block.data);
+				block.dirty = false;
+			}
+		}
+	}
+
+	// Index of the sector if it is cached
+	fn lookup(&mut self, lba: u32) -> Option<usize> {
+		self.clock += 1;
+		let slot = *self.index.get(&lba)?;
+		self.recency.remove(&self.blocks[slot].last_used);
+		self.recency.insert(self.clock, slot);
+		self.blocks[slot].last_used = self.clock;
+		Some(slot)
+	}
+
+	// Caches the sector with the data, in place of another one if the cache is full
+	fn insert(&mut self, lba: u32, data: &[u8; 512], dirty: bool) {
+		let slot = if self.blocks.len() < self.capacity {
+			self.blocks.push(CachedBlock { lba, data: [0u8; 512], dirty: false, last_used: 0 });
+			self.blocks.len() - 1
+		} else {
+			self.evict()
+		};
+
+		let block = &mut self.blocks[slot];
+		block.lba = lba;
+		block.data.copy_from_slice(data);
+		block.dirty = dirty;
+		block.last_used = self.clock;
+		self.index.insert(lba, slot);
+		self.recency.insert(self.clock, slot);
+	}
+
+	// Frees the least recently used sector, writing it back first if needed
+	fn evict(&mut self) -> usize {
+		let (_, slot) = self.recency.pop_first().unwrap();
+		let block = &mut self.blocks[slot];
+		if block.dirty {
+			self.device.write_sector(block.lba, &block.data);
+		}
+		self.index.remove(&block.lba);
+		slot
+	}
+}
+
+impl BlockDevice for BlockCache {
+	fn read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) {
+		if let Err(e) = self.try_read_sector(lba, buf) {
+			panic!("Read of sector {}: {}", lba, e);
+		}
+	}
+
+	// A sector that cannot be read is not cached
+	fn try_read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), Errno> {
+		match self.lookup(lba) {
+			Some(slot) => buf.copy_from_slice(&self.blocks[slot].data),
+			None => {
+				self.device.try_read_sector(lba, buf)?;
+				self.insert(lba, buf, false);
+			}
+		}
+		Ok(())
+	}
+
+	// The whole sector is replaced, so a miss does not read it first
+	fn write_sector(&mut self, lba: u32, buf: &[u8; 512]) {
+		match self.lookup(lba) {
+			Some(slot) => {
+				let block = &mut self.blocks[slot];
+				block.data.copy_from_slice(buf);
+				block.dirty = true;
+			}
+			None => self.insert(lba, buf, true),
+		}
+	}
+
+	fn flush(&mut self) {
+		self.sync();
+		self.device.flush();
+	}
+}
@@ -1,10 +1,11 @@
 use core::arch::asm;
-//use crate::gdt;
+use crate::gdt;
 
-// const IA32_EFER: u32 = 0xC0000080;
-// const IA32_STAR: u32 = 0xC0000081;
-// const IA32_LSTAR: u32 = 0xC0000082;
-// const IA32_FMASK: u32 = 0xC0000084;
+const IA32_EFER: u32 = 0xC0000080;
+const IA32_STAR: u32 = 0xC0000081;
+const IA32_LSTAR: u32 = 0xC0000082;
+const IA32_FMASK: u32 = 0xC0000084;
+const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
 
 pub unsafe fn wrmsr(msr: u32, value: u64) {
 	let low = value as u32;
@@ -79,6 +80,16 @@ pub fn disable_interrupts() {
 	}
 }
 
+// Asks the ACPI PM controller of QEMU (and Bochs) to turn the machine off. Writes
+// SLP_EN with the S5 sleep type to PM1a_CNT, which sits at 0x604 on current QEMU and
+// at 0xB004 on older versions and Bochs.
+pub fn power_off() {
+	unsafe {
+		x86::io::outw(0x604, 0x2000);
+		x86::io::outw(0xB004, 0x2000);
+	}
+}
+
 pub fn check_cpl() -> u16 {
     unsafe {
         let cs: u16;
@@ -88,7 +99,6 @@ pub fn check_cpl() -> u16 {
 }
 
 pub fn enable_nxe_bit() {
-	const IA32_EFER: u32 = 3221225600;
 	let nxe_bit = 1 << 11;
 	unsafe {
 		let efer = rdmsr(IA32_EFER);
@@ -101,22 +111,26 @@ pub fn enable_write_protect_bit() {
 	unsafe { cr0_write(cr0() | CR0_WRITE_PROTECT)};
 }
 
-// pub unsafe fn init_syscall(syscall_entry: u64) {
-//     // Turn on SCE bit (System Call Extensions) in EFER
-//     let mut efer = rdmsr(IA32_EFER);
-//     efer |= 1; // EFER.SCE = 1
-//     wrmsr(IA32_EFER, efer);
-// 
-//     let user_cs: u64 = gdt::GDT.1.user_code_selector.0 as u64;
-//     let kernel_cs: u64 = gdt::GDT.1.code_selector.0 as u64;
-//     let star = (user_cs << 48) | (kernel_cs << 32);
-//     wrmsr(IA32_STAR, star);
-// 
-//     // Set RFLAGS mask
-//     let rflags_mask: u64 = 1 << 9; // IF
-//     wrmsr(IA32_FMASK, rflags_mask);
-// 
-//     // Set syscall handler address
-//     wrmsr(IA32_LSTAR, syscall_entry);
-// }
+pub unsafe fn init_syscall(syscall_entry: u64) {
+	unsafe {
+		// Turn on SCE bit (System Call Extensions) in EFER
+		let efer = rdmsr(IA32_EFER);
+		wrmsr(IA32_EFER, efer | 1);
 
+		// SYSCALL loads CS from STAR[47:32] and SS from the next entry. SYSRET loads
+		// SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16, hence the user data
+		// segment right before the user code one in the GDT.
+		let kernel_cs = gdt::GDT.1.code_selector.0 as u64;
+		let sysret_base = (gdt::GDT.1.user_data_selector.0 as u64 & !3) - 8;
+		wrmsr(IA32_STAR, (sysret_base << 48) | (kernel_cs << 32));
+
+		// Syscalls run with interrupts disabled, like the int 0x80 gate. TF, DF and AC are cleared too.
+		let rflags_mask: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+		wrmsr(IA32_FMASK, rflags_mask);
+
+		wrmsr(IA32_LSTAR, syscall_entry);
+
+		// swapgs in the entry code makes this the GS base
+		wrmsr(IA32_KERNEL_GS_BASE, gdt::per_cpu_address().as_u64());
+	}
+}
@@ -0,0 +1,177 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use abi::Errno;
+use crate::block_cache::{ self, BlockCache };
+use crate::fat32::{ self, BlockDevice, Fat32FileSystem };
+use crate::fsck;
+use crate::partition::{ self, Partition };
+use crate::thread;
+use crate::vfs::{ self, FileSystem };
+
+// Block devices found at boot, by name. Any of them can be mounted, each at most once
+// so that no two caches hold the same sectors. The partitions of a disk are registered
+// after it as devices of their own, named after the disk and the partition number.
+
+pub struct Disk {
+	pub name: String,
+	pub model: String,
+	pub sectors: u64,
+	// First sector on the parent disk, 0 for a whole disk
+	pub start: u32,
+	// Whether the disk or partition starts with a FAT32 boot sector
+	pub fat32: bool,
+	// The disk a partition belongs to
+	pub parent: Option<String>,
+	device: Arc<Mutex<Box<dyn BlockDevice>>>,
+	// The volume of the disk, once it is mounted
+	fs: Option<Arc<Fat32FileSystem>>,
+}
+
+static mut DISKS: Vec<Disk> = Vec::new();
+
+#[allow(static_mut_refs)]
+fn disks_mut() -> &'static mut Vec<Disk> {
+	unsafe { &mut DISKS }
+}
+
+pub fn disks() -> &'static [Disk] {
+	disks_mut()
+}
+
+// Registers the disk and, unless it holds a volume without a partition table, each of
+// its partitions
+pub fn register(name: String, model: String, sectors: u64, device: Box<dyn BlockDevice>) {
+	let device = Arc::new(Mutex::new(device));
+	let fat32 = has_fat32(&mut SharedDevice(device.clone()));
+	let partitions = if fat32 { Vec::new() } else { partition::read_table(&mut SharedDevice(device.clone())) };
+	disks_mut().push(Disk { name: name.clone(), model, sectors, start: 0, fat32, parent: None, device: device.clone(), fs: None });
+
+	for info in partitions {
+		if info.start == 0 || info.sectors == 0 || info.start as u64 + info.sectors as u64 > sectors {
+			continue;
+		}
+		let mut partition = Partition::new(SharedDevice(device.clone()), info.start, info.sectors);
+		let fat32 = has_fat32(&mut partition);
+		disks_mut().push(Disk {
+			name: format!("{}{}", name, info.number),
+			model: info.description,
+			sectors: info.sectors as u64,
+			start: info.start,
+			fat32,
+			parent: Some(name.clone()),
+			device: Arc::new(Mutex::new(Box::new(partition))),
+			fs: None,
+		});
+	}
+}
+
+fn has_fat32(device: &mut dyn BlockDevice) -> bool {
+	let mut vbr = [0u8; 512];
+	device.read_sector(0, &mut vbr);
+	fat32::is_fat32(&vbr)
+}
+
+// The disk itself if it holds a FAT32 volume, otherwise its first FAT32 partition
+pub fn find_fat32(name: &str) -> Option<&'static Disk> {
+	disks().iter().find(|disk| disk.fat32 && (disk.name == name || disk.parent.as_deref() == Some(name)))
+}
+
+// Mounts the FAT32 volume of the disk at the path, behind a block cache. A disk can not
+// be mounted together with its partitions.
+pub fn mount(name: &str, path: &str) -> Result<Arc<Fat32FileSystem>, Errno> {
+	let index = disks().iter().position(|disk| disk.name == name).ok_or(Errno::NotFound)?;
+	if overlaps_mounted(index) {
+		return Err(Errno::Busy);
+	}
+	// mounting marks the volume as in use on the disk, so the path is checked first
+	let path = vfs::check_mount_point(path)?;
+
+	let disk = &mut disks_mut()[index];
+	let volume = fat32::mount_fat32(disk.cached_device(), disk.sectors).map_err(|_| Errno::InvalidArgument)?;
+	let fs = Arc::new(Fat32FileSystem::new(volume));
+	if let Err(e) = vfs::mount(&path, fs.clone()) {
+		// something was mounted there meanwhile, the volume is left clean again
+		let _ = fs.unmount();
+		return Err(e);
+	}
+	disk.fs = Some(fs.clone());
+	Ok(fs)
+}
+
+// Checks the FAT32 volume of the disk and repairs it if asked to. A mounted volume is
+// not used by anything else until the check is done.
+pub fn check(name: &str, repair: bool) -> Result<fsck::Report, Errno> {
+	let index = disks().iter().position(|disk| disk.name == name).ok_or(Errno::NotFound)?;
+	let disk = &disks()[index];
+	if let Some(fs) = disk.fs.as_ref() {
+		return Ok(fs.check(repair));
+	}
+	if overlaps_mounted(index) {
+		return Err(Errno::Busy);
+	}
+
+	let mut volume = fat32::open_fat32(disk.cached_device(), disk.sectors).map_err(|_| Errno::InvalidArgument)?;
+	let report = fsck::check(&mut volume, repair);
+	// a volume that is not mounted is left clean by a repair, or by a check with -r that
+	// found nothing to repair. A plain check writes nothing.
+	if repair {
+		if report.problems.is_empty() || report.repaired {
+			volume.set_clean(true);
+		}
+		volume.sync();
+	}
+	Ok(report)
+}
+
+// Whether the disk or one of its partitions or the disk it is a partition of is mounted
+fn overlaps_mounted(index: usize) -> bool {
+	let name = disks()[index].name.as_str();
+	let parent = disks()[index].parent.as_deref();
+	disks().iter().any(|other| other.fs.is_some()
+		&& (other.name == name || other.parent.as_deref() == Some(name) || parent == Some(other.name.as_str())))
+}
+
+impl Disk {
+	fn cached_device(&self) -> Box<dyn BlockDevice> {
+		let shared = SharedDevice(self.device.clone());
+		Box::new(BlockCache::new(Box::new(shared), block_cache::DEFAULT_CAPACITY))
+	}
+}
+
+// A registered device as used by a mounted volume
+struct SharedDevice(Arc<Mutex<Box<dyn BlockDevice>>>);
+
+impl SharedDevice {
+	// Gives the time to other threads while waiting, the holder may be waiting for the disk
+	fn lock(&self) -> spin::MutexGuard<'_, Box<dyn BlockDevice>> {
+		loop {
+			if let Some(guard) = self.0.try_lock() {
+				return guard;
+			}
+			thread::yield_now();
+		}
+	}
+}
+
+impl BlockDevice for SharedDevice {
+	fn read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) {
+		self.lock().read_sector(lba, buf);
+	}
+
+	fn try_read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), Errno> {
+		self.lock().try_read_sector(lba, buf)
+	}
+
+	fn write_sector(&mut self, lba: u32, buf: &[u8; 512]) {
+		self.lock().write_sector(lba, buf);
+	}
+
+	fn flush(&mut self) {
+		self.lock().flush();
+	}
+}
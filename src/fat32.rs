@@ -1,25 +1,34 @@
-use core::ptr::copy_nonoverlapping;
-use x86::io::{inb, inw, outb, outw};
 use xmas_elf::ElfFile;
 use xmas_elf::program::Type;
 
 use crate::alloc::string::ToString;
+use alloc::collections::BTreeMap;
 use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
-
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use spin::{ Mutex, MutexGuard };
+
+use abi::{ DateTime, Errno };
+use crate::fsck;
+use crate::memory;
 use crate::multitasking;
 use crate::println;
+use crate::thread;
+use crate::time;
+use crate::vfs::{ self, DirEntry, FileType, Inode, Metadata };
 
-pub static mut FS_PTR: *mut FAT32Volume = core::ptr::null_mut();
-pub static mut RETURN_ADDR: usize = 0;
 
-pub fn load_elf_and_jump(elf_data: &[u8]) {
-    let elf = ElfFile::new(elf_data).expect("Invalid ELF");
+// Builds a fresh address space for the program and maps every PT_LOAD segment into it
+pub fn load_elf(elf_data: &[u8]) -> Result<(memory::AddressSpace, usize), &'static str> {
+    let elf = ElfFile::new(elf_data)?;
+    let mut address_space = memory::AddressSpace::new();
 
     for ph in elf.program_iter() {
-        if ph.get_type().unwrap() != Type::Load {
+        if ph.get_type() != Ok(Type::Load) {
             continue;
         }
 
@@ -28,142 +37,54 @@ pub fn load_elf_and_jump(elf_data: &[u8]) {
         let p_filesz = ph.file_size() as usize;
         let p_memsz = ph.mem_size() as usize;
 
-        unsafe {
-            let src = elf_data.as_ptr().add(p_offset);
-            let dst = p_vaddr as *mut u8;
-            copy_nonoverlapping(src, dst, p_filesz);
+        if p_offset + p_filesz > elf_data.len() {
+            return Err("Segment is out of file bounds");
+        }
 
-            core::ptr::write_bytes(dst.add(p_filesz), 0, p_memsz - p_filesz);
+        let mut flags = memory::USER_ACCESSIBLE;
+        if ph.flags().is_write() {
+            flags = flags | memory::WRITABLE;
+        }
+        if !ph.flags().is_execute() {
+            flags = flags | memory::NO_EXECUTE;
         }
-    }
 
-    let entry = elf.header.pt2.entry_point() as usize;
-    unsafe {
-        RETURN_ADDR = return_to_kernel as usize;
-        core::arch::asm!(
-            "mov rax, {0}", // entry point
-            "jmp rax",
-            in(reg) entry,
-            options(noreturn)
-        );
+        address_space.map_region(p_vaddr, &elf_data[p_offset..p_offset + p_filesz], p_memsz, flags)?;
     }
-}
 
-extern "C" fn return_to_kernel() {
-    let executor = unsafe { &mut *multitasking::EXECUTOR_PTR };
-    executor.run();
+    Ok((address_space, elf.header.pt2.entry_point() as usize))
 }
 
 pub trait BlockDevice {
     fn read_sector(&mut self, lba: u32, buf: &mut [u8; 512]);
     fn write_sector(&mut self, lba: u32, buf: &[u8; 512]);
-}
-
-pub unsafe fn identify_drive() {
-    unsafe {
-        outb(0x1F6, 0xA0);
-        outb(0x1F7, 0xEC); // IDENTIFY
-
-        if inb(0x1F7) == 0 {
-            println!("No drive found");
-            return;
-        }
-        while (inb(0x1F7) & 0x80) != 0 {}
-        while (inb(0x1F7) & 0x08) == 0 {}
-        for _ in 0..256 {
-            let _ = inw(0x1F0);
-        }
-
-        println!("Drive identified!");
-    }
-}
 
-// ATA ports
-// 0x1F0: Data Register (read/write)
-// 0x1F1: Error Register
-// 0x1F2: Sector Count
-// 0x1F3: LBA Low
-// 0x1F4: LBA Mid
-// 0x1F5: LBA High
-// 0x1F6: Device/Head
-// 0x1F7: Status/Command
-
-// DF - Device fault
-// DRQ - Data request
-
-#[derive(Copy, Clone)]
-pub struct AtaDevice;
-
-impl AtaDevice {
-    pub fn new() -> Self {
-        AtaDevice
+    // Like `read_sector`, but a sector that cannot be read is an error instead of a panic
+    fn try_read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), Errno> {
+        self.read_sector(lba, buf);
+        Ok(())
     }
 
-    unsafe fn wait_ready() {
-        unsafe {
-            while (inb(0x1F7) & 0x80) != 0 {} // BSY
+    // Makes the sectors written so far reach the disk
+    fn flush(&mut self) {}
+}
 
-            let status = inb(0x1F7);
-            if (status & 0x01) != 0 || (status & 0x20) != 0 { // ERR / DF
-                let error = inb(0x1F1);
-                panic!("ATA Error: Status={:#x}, Error={:#x}", status, error);
-            }
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Errno>> + 'a>>;
 
-            let mut timeout = 100_000;
-            while (inb(0x1F7) & 0x08) == 0 { // DRQ
-                timeout -= 1;
-                if timeout == 0 {
-                    println!("Timeout on Secondary IDE");
-                    let status = inb(0x1F7);
-                    println!("Status(0x1F7): {:#x}", status);
-                    let error = inb(0x1F1);
-                    println!("Error(0x1F1): {:#x}", error);
-                    let statusf6 = inb(0x1F6);
-                    println!("status(0x1F6): {:#x}", statusf6);
-                }
-            }
-        }
-    }
+// A block device whose transfers complete in the background, the futures are woken
+// when the device is done. Buffers are whole sectors.
+pub trait AsyncBlockDevice {
+    fn read_sectors<'a>(&'a self, lba: u32, buf: &'a mut [u8]) -> BlockFuture<'a>;
+    fn write_sectors<'a>(&'a self, lba: u32, buf: &'a [u8]) -> BlockFuture<'a>;
 }
 
-impl BlockDevice for AtaDevice {
-    fn read_sector(&mut self, lba: u32, buf: &mut[u8; 512]) {
-        unsafe {
-            outb(0x1F6, 0xF0 | ((lba >> 24) & 0x0F) as u8); // Primary slave
-            outb(0x1F2, 1); 								// Sectors to read
-            outb(0x1F3, (lba & 0xFF) as u8); 				// LBA 0–7
-            outb(0x1F4, ((lba >> 8) & 0xFF) as u8); 		// LBA 8–15
-            outb(0x1F5, ((lba >> 16) & 0xFF) as u8); 		// LBA 16–23
-            outb(0x1F7, 0x20); 								// READ SECTORS
-            Self::wait_ready();
-            for i in 0..256 {								// Read 512 bytes (256 words) 
-                let w = inw(0x1F0);
-                buf[i * 2] = (w & 0xFF) as u8;
-                buf[i * 2 + 1] = (w >> 8) as u8;
-            }
-        }
+impl<D: AsyncBlockDevice + ?Sized> AsyncBlockDevice for Box<D> {
+    fn read_sectors<'a>(&'a self, lba: u32, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        (**self).read_sectors(lba, buf)
     }
 
-    fn write_sector(&mut self, lba: u32, buf: &[u8; 512]) {
-        unsafe {
-            outb(0x1F6, 0xF0 | ((lba >> 24) & 0x0F) as u8); // Primary slave
-            outb(0x1F2, 1); 								// 1 sector
-            outb(0x1F3, (lba & 0xFF) as u8);
-            outb(0x1F4, ((lba >> 8) & 0xFF) as u8);
-            outb(0x1F5, ((lba >> 16) & 0xFF) as u8);
-            outb(0x1F7, 0x30); 								// WRITE SECTOR command
-
-            Self::wait_ready(); 							// DRQ
-
-            for i in 0..256 {								// write 512 bytes (256 words)
-                let lo = buf[i * 2] as u16;
-                let hi = buf[i * 2 + 1] as u16;
-                let word = lo | (hi << 8);
-                outw(0x1F0, word);
-            }
-
-            while (inb(0x1F7) & 0x80) != 0 {}				// BSY
-        }
+    fn write_sectors<'a>(&'a self, lba: u32, buf: &'a [u8]) -> BlockFuture<'a> {
+        (**self).write_sectors(lba, buf)
     }
 }
 
@@ -178,13 +99,52 @@ impl BlockDevice for AtaDevice {
 #[derive(Debug, Clone)]
 pub struct DirectoryEntry {
     pub name: [u8; 11],
+    pub long_name: Option<String>,
     pub attr: u8,
+    // NTRes, where some systems keep the case of an 8.3 name that has no long name
+    pub case_flags: u8,
     pub cluster_high: u16,
     pub cluster_low: u16,
     pub file_size: u32,
+    pub created: FatTime,
+    pub accessed_date: u16,
+    pub modified: FatTime,
 }
 
 impl DirectoryEntry {
+    // A new entry, created and last touched now
+    fn new(name: [u8; 11], long_name: Option<String>, attr: u8, cluster: u32, file_size: u32) -> DirectoryEntry {
+        let now = FatTime::now();
+        DirectoryEntry {
+            name,
+            long_name,
+            attr,
+            case_flags: 0,
+            cluster_high: (cluster >> 16) as u16,
+            cluster_low: cluster as u16,
+            file_size,
+            created: now,
+            accessed_date: now.date,
+            modified: now,
+        }
+    }
+
+    fn parse(name: [u8; 11], long_name: Option<String>, entry: &[u8]) -> DirectoryEntry {
+        let read_u16 = |offset: usize| u16::from_le_bytes([entry[offset], entry[offset + 1]]);
+        DirectoryEntry {
+            name,
+            long_name,
+            attr: entry[11],
+            case_flags: entry[12],
+            cluster_high: read_u16(20),
+            cluster_low: read_u16(26),
+            file_size: u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]),
+            created: FatTime { date: read_u16(16), time: read_u16(14), tenths: entry[13] },
+            accessed_date: read_u16(18),
+            modified: FatTime { date: read_u16(24), time: read_u16(22), tenths: 0 },
+        }
+    }
+
     pub fn is_directory(&self) -> bool {
         self.attr & 0x10 != 0
     }
@@ -193,7 +153,15 @@ impl DirectoryEntry {
         ((self.cluster_high as u32) << 16) | self.cluster_low as u32
     }
 
+    // The long name if the entry has one, the 8.3 one otherwise
     pub fn filename(&self) -> String {
+        match &self.long_name {
+            Some(long_name) => long_name.clone(),
+            None => self.short_filename(),
+        }
+    }
+
+    pub fn short_filename(&self) -> String {
         let raw = &self.name;
         let name = String::from_utf8_lossy(&raw[..8]).trim().to_string();
         let ext = String::from_utf8_lossy(&raw[8..]).trim().to_string();
@@ -204,21 +172,170 @@ impl DirectoryEntry {
             format!("{}.{}", name, ext)
         }
     }
+
+    // Names are found by their long name as well as by their 8.3 alias, ignoring case
+    pub fn matches(&self, name: &str) -> bool {
+        self.long_name.as_ref().is_some_and(|long_name| long_name.eq_ignore_ascii_case(name))
+            || self.short_filename().eq_ignore_ascii_case(name)
+    }
+
+    fn to_bytes(&self) -> [u8; 32] {
+        let mut data = [0u8; 32];
+        data[0..11].copy_from_slice(&self.name);								// name
+        data[11] = self.attr;													// attr
+        data[12] = self.case_flags;											// NTRes
+        data[13] = self.created.tenths;										// creation time, 10 ms units
+        data[14..16].copy_from_slice(&self.created.time.to_le_bytes());		// creation time
+        data[16..18].copy_from_slice(&self.created.date.to_le_bytes());		// creation date
+        data[18..20].copy_from_slice(&self.accessed_date.to_le_bytes());		// access date
+        data[20..22].copy_from_slice(&self.cluster_high.to_le_bytes());		// high
+        data[22..24].copy_from_slice(&self.modified.time.to_le_bytes());		// write time
+        data[24..26].copy_from_slice(&self.modified.date.to_le_bytes());		// write date
+        data[26..28].copy_from_slice(&self.cluster_low.to_le_bytes());		// low
+        data[28..32].copy_from_slice(&self.file_size.to_le_bytes());			// size
+        data
+    }
+}
+
+// Date and time as directory entries store them, in local time
+// date: bits 9-15 year since 1980, 5-8 month, 0-4 day; zero if not set
+// time: bits 11-15 hours, 5-10 minutes, 0-4 seconds / 2
+// tenths: 0-199, the odd second and hundredths of it, only kept for the creation time
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FatTime {
+    pub date: u16,
+    pub time: u16,
+    pub tenths: u8,
+}
+
+impl FatTime {
+    // The kernel clock. Years before 1980 cannot be stored and become 1980.
+    pub fn now() -> FatTime {
+        let now = time::now();
+        let year = now.year.clamp(1980, 1980 + 127) - 1980;
+        FatTime {
+            date: (year << 9) | ((now.month as u16) << 5) | now.day as u16,
+            time: ((now.hour as u16) << 11) | ((now.minute as u16) << 5) | (now.second as u16 / 2),
+            tenths: (now.second % 2) * 100,
+        }
+    }
+
+    pub fn to_datetime(&self) -> DateTime {
+        if self.date == 0 {
+            return DateTime::default();
+        }
+        DateTime {
+            year: 1980 + (self.date >> 9),
+            month: ((self.date >> 5) & 0x0F) as u8,
+            day: (self.date & 0x1F) as u8,
+            hour: (self.time >> 11) as u8,
+            minute: ((self.time >> 5) & 0x3F) as u8,
+            second: ((self.time & 0x1F) * 2) as u8 + self.tenths / 100,
+        }
+    }
+}
+
+// Long file name (VFAT) entries have attribute 0x0F and come right before the short
+// entry they belong to, last part first. The first of them has 0x40 set in its sequence
+// number. Each holds 13 UCS-2 characters at LFN_OFFSETS and the checksum of the short name.
+const LFN_ATTR: u8 = 0x0F;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LEN: usize = 255;
+
+// An entry with the 32 byte slots it takes in the directory: its long name slots,
+// then the short entry. Slots are given as cluster and offset in it.
+pub(crate) struct DirectoryRecord {
+    pub entry: DirectoryEntry,
+    pub slots: Vec<(u32, usize)>,
+}
+
+impl DirectoryRecord {
+    fn short_slot(&self) -> (u32, usize) {
+        *self.slots.last().unwrap()
+    }
+}
+
+// Long name parts read so far, dropped when the short entry does not follow
+#[derive(Default)]
+struct LongNameParts {
+    units: Vec<u16>,
+    slots: Vec<(u32, usize)>,
+    next_sequence: u8,
+    checksum: u8,
+}
+
+impl LongNameParts {
+    fn add(&mut self, slot: &[u8], location: (u32, usize)) {
+        let sequence = slot[0] & 0x1F;
+        if sequence == 0 {
+            *self = LongNameParts::default();
+            return;
+        }
+        if slot[0] & LFN_LAST != 0 {
+            *self = LongNameParts { checksum: slot[13], ..LongNameParts::default() };
+        } else if sequence != self.next_sequence || slot[13] != self.checksum {
+            *self = LongNameParts::default();
+            return;
+        }
+
+        // parts come last first, so each one goes in front of the previous ones
+        let units: Vec<u16> = LFN_OFFSETS.iter().map(|&offset| u16::from_le_bytes([slot[offset], slot[offset + 1]])).collect();
+        self.units.splice(0..0, units);
+        self.slots.push(location);
+        self.next_sequence = sequence - 1;
+    }
+
+    fn take(&mut self, short_name: &[u8; 11]) -> (Option<String>, Vec<(u32, usize)>) {
+        let parts = core::mem::take(self);
+        if parts.slots.is_empty() || parts.next_sequence != 0 || parts.checksum != short_name_checksum(short_name) {
+            return (None, Vec::new());
+        }
+        let end = parts.units.iter().position(|&unit| unit == 0x0000 || unit == 0xFFFF).unwrap_or(parts.units.len());
+        let name = char::decode_utf16(parts.units[..end].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        (Some(name), parts.slots)
+    }
 }
 
 
+// The FAT is stored `num_fats` times back to back. Every copy is written, reads go to
+// the first one and only fall back to the others when a sector of it cannot be read.
 pub struct FAT {
     pub fat_start_lba: u32,
     pub fat_size_sectors: u32,
     pub bytes_per_sector: u16,
+    pub num_fats: u8,
 }
 
 impl FAT {
-    pub fn new(fat_start_lba: u32, fat_size_sectors: u32, bytes_per_sector: u16) -> Self {
+    pub fn new(fat_start_lba: u32, fat_size_sectors: u32, bytes_per_sector: u16, num_fats: u8) -> Self {
         FAT {
             fat_start_lba,
             fat_size_sectors,
             bytes_per_sector,
+            num_fats,
+        }
+    }
+
+    // Sector `sector` of the FAT, from the first copy it can be read from
+    pub(crate) fn read_sector(&self, device: &mut dyn BlockDevice, sector: u32) -> [u8; 512] {
+        let mut buf = [0u8; 512];
+        for copy in 0..self.num_fats.max(1) as u32 {
+            match device.try_read_sector(self.fat_start_lba + copy * self.fat_size_sectors + sector, &mut buf) {
+                Ok(()) => return buf,
+                Err(e) => println!("FAT {}: sector {} cannot be read: {}", copy + 1, sector, e),
+            }
+        }
+        panic!("No copy of FAT sector {} can be read", sector);
+    }
+
+    // Writes the sector to every copy of the FAT
+    fn write_sector(&self, device: &mut dyn BlockDevice, sector: u32, buf: &[u8; 512]) {
+        for copy in 0..self.num_fats.max(1) as u32 {
+            device.write_sector(self.fat_start_lba + copy * self.fat_size_sectors + sector, buf);
         }
     }
 
@@ -227,8 +344,7 @@ impl FAT {
         let sector = fat_offset / self.bytes_per_sector as u32;
         let offset = fat_offset % self.bytes_per_sector as u32;
 
-        let mut buf = [0u8; 512];
-        device.read_sector(self.fat_start_lba + sector, &mut buf);
+        let buf = self.read_sector(device, sector);
         u32::from_le_bytes([				// read 4 bytes as u32
             buf[offset as usize],
             buf[offset as usize + 1],
@@ -242,20 +358,33 @@ impl FAT {
         let sector = fat_offset / self.bytes_per_sector as u32;
         let offset = fat_offset % self.bytes_per_sector as u32;
 
-        let mut buf = [0u8; 512];
-        device.read_sector(self.fat_start_lba + sector, &mut buf);
+        let mut buf = self.read_sector(device, sector);
 
-		// replace 4 bytes of read data
+		// replace 4 bytes of read data, keeping the 4 reserved high bits
+        let old = u32::from_le_bytes([buf[offset as usize], buf[offset as usize + 1], buf[offset as usize + 2], buf[offset as usize + 3]]);
+        let value = (old & 0xF0000000) | (value & 0x0FFFFFFF);
         buf[offset as usize..offset as usize + 4].copy_from_slice(&value.to_le_bytes());
-        device.write_sector(self.fat_start_lba + sector, &buf);
+        self.write_sector(device, sector, &buf);
     }
 }
 
+// FSInfo sector (FAT32 only)
+// 0-3: lead signature
+// 484-487: struct signature
+// 488-491: free cluster count, 0xFFFFFFFF if unknown
+// 492-495: cluster to look for free ones from, 0xFFFFFFFF if unknown
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+
 // 0x00000000 - free
 // 0x00000001 - reserved
 // 0x0FFFFFF7 - bad cluster
 // 0x0FFFFFF8 - 0x0FFFFFFF - chain end
 
+// FAT[1] is not a cluster. In FAT32 its bit 27 is set while the volume is unmounted
+// cleanly, a volume without it is mounted or was when the machine went down.
+const FAT_CLEAN_SHUTDOWN: u32 = 0x08000000;
+
 
 pub struct FAT32Volume {
     pub fat: FAT,
@@ -267,6 +396,14 @@ pub struct FAT32Volume {
     pub reserved_sector_count: u16,
     pub fat_size_sectors: u32,
     pub num_fats: u8,
+    // Clusters 2..cluster_count + 2 are on the volume
+    pub cluster_count: u32,
+    // Sector of the FSInfo structure, if the volume has a valid one
+    pub fs_info_sector: Option<u32>,
+    // Free clusters as counted by the FSInfo sector, None if unknown
+    pub free_count: Option<u32>,
+    // Cluster to start looking for a free one from
+    pub next_free: u32,
 }
 
 // dir structure (32 bytes)
@@ -297,36 +434,118 @@ impl FAT32Volume {
         }
     }
 
+    // Looks for a free cluster from the `next_free` hint on, wrapping around once,
+    // and reads the FAT a sector at a time instead of an entry at a time
     pub fn allocate_cluster(&mut self) -> Option<u32> {
-        let entries_per_sector = self.bytes_per_sector as u32 / 4;
-        let total_entries = self.fat_size_sectors * entries_per_sector;
+        let end = self.cluster_count + 2;
+        let start = if (2..end).contains(&self.next_free) { self.next_free } else { 2 };
+        let cluster = self.find_free_cluster(start, end).or_else(|| self.find_free_cluster(2, start))?;
+
+        self.fat.write_entry(&mut *self.device, cluster, 0x0FFFFFFF);
+        self.next_free = cluster + 1;
+        self.free_count = self.free_count.map(|count| count.saturating_sub(1));
+        Some(cluster)
+    }
 
+    fn find_free_cluster(&mut self, start: u32, end: u32) -> Option<u32> {
+        let entries_per_sector = self.bytes_per_sector as u32 / 4;
         let mut buf = [0u8; 512];
-        for i in 2..total_entries {
-            let val = self.fat.read_entry(&mut *self.device, i);
-            if val == 0 {		// if free
-                self.fat.write_entry(&mut *self.device, i, 0x0FFFFFFF);
-                return Some(i);  // cluster num
+        let mut loaded_sector = None;
+        for cluster in start..end {
+            let sector = cluster / entries_per_sector;
+            if loaded_sector != Some(sector) {
+                buf = self.fat.read_sector(&mut *self.device, sector);
+                loaded_sector = Some(sector);
+            }
+            let offset = (cluster % entries_per_sector) as usize * 4;
+            let val = u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]);
+            if val & 0x0FFFFFFF == 0 {		// if free
+                return Some(cluster);
             }
         }
         None
     }
 
+    pub fn is_dirty(&mut self) -> bool {
+        self.fat.read_entry(&mut *self.device, 1) & FAT_CLEAN_SHUTDOWN == 0
+    }
+
+    pub fn set_clean(&mut self, clean: bool) {
+        let flags = self.fat.read_entry(&mut *self.device, 1);
+        let flags = if clean { flags | FAT_CLEAN_SHUTDOWN } else { flags & !FAT_CLEAN_SHUTDOWN };
+        self.fat.write_entry(&mut *self.device, 1, flags);
+    }
+
+    // Counts the FAT the first time when there is no FSInfo count to go by
+    fn free_clusters(&mut self) -> u32 {
+        match self.free_count {
+            Some(count) => count,
+            None => {
+                let count = self.count_free_clusters();
+                self.free_count = Some(count);
+                count
+            }
+        }
+    }
+
+    fn count_free_clusters(&mut self) -> u32 {
+        let entries_per_sector = self.bytes_per_sector as u32 / 4;
+        let end = self.cluster_count + 2;
+        let mut free = 0;
+        for sector in 0..end.div_ceil(entries_per_sector) {
+            let buf = self.fat.read_sector(&mut *self.device, sector);
+            for (index, entry) in buf.chunks(4).enumerate() {
+                let cluster = sector * entries_per_sector + index as u32;
+                let val = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                if (2..end).contains(&cluster) && val & 0x0FFFFFFF == 0 {
+                    free += 1;
+                }
+            }
+        }
+        free
+    }
+
     pub fn set_next_cluster(&mut self, current: u32, next: u32) {
         self.fat.write_entry(&mut *self.device, current, next);
     }
 
+    // Frees the chain starting at `cluster`. A link out of the volume or to a free cluster
+    // ends the walk, and it never takes more than cluster_count steps.
     pub fn free_cluster_chain(&mut self, mut cluster: u32) {
-        while cluster < 0x0FFFFFF8 {
-            let next = self.fat.read_entry(&mut *self.device, cluster);
-            self.fat.write_entry(&mut *self.device, cluster, 0); // set as free
-            if next >= 0x0FFFFFF8 {
+        for _ in 0..self.cluster_count {
+            if !(2..self.cluster_count + 2).contains(&cluster) {
                 break;
             }
+            let next = self.fat.read_entry(&mut *self.device, cluster);
+            if next == 0 {
+                break; // already freed, the chain looped back
+            }
+            self.fat.write_entry(&mut *self.device, cluster, 0); // set as free
+            self.free_count = self.free_count.map(|count| count + 1);
+            self.next_free = self.next_free.min(cluster);
             cluster = next;
         }
     }
 
+    // Syncs the volume and marks it as cleanly unmounted, the volume is not to be used
+    // afterwards
+    pub fn unmount(&mut self) {
+        self.set_clean(true);
+        self.sync();
+    }
+
+    // Stores the free cluster hints in FSInfo and flushes the device
+    pub fn sync(&mut self) {
+        if let Some(sector) = self.fs_info_sector {
+            let mut fs_info = [0u8; 512];
+            self.device.read_sector(sector, &mut fs_info);
+            fs_info[488..492].copy_from_slice(&self.free_count.unwrap_or(0xFFFFFFFF).to_le_bytes());
+            fs_info[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+            self.device.write_sector(sector, &fs_info);
+        }
+        self.device.flush();
+    }
+
     pub fn read_cluster(&mut self, cluster: u32) -> Vec<u8> {
         let start_lba = self.first_sector_of_cluster(cluster);
         let mut buf = vec![0u8; self.cluster_size];
@@ -355,25 +574,40 @@ impl FAT32Volume {
         data_start + (cluster - 2) * self.sectors_per_cluster as u32
     }
 
-    pub fn set_entry_cluster(&mut self, path: &str, cluster: u32) -> Result<(), &'static str> {
+    pub fn set_entry_cluster(&mut self, path: &str, cluster: u32) -> Result<(), Errno> {
+        self.update_entry(path, |entry| {
+            entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());		// high
+            entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());			// low
+        })
+    }
+
+    // Sets the write time and access date of the entry to now, and its size if given
+    fn touch(&mut self, path: &str, new_size: Option<u32>) -> Result<(), Errno> {
+        let now = FatTime::now();
+        self.update_entry(path, |entry| {
+            entry[18..20].copy_from_slice(&now.date.to_le_bytes());		// access date
+            entry[22..24].copy_from_slice(&now.time.to_le_bytes());		// write time
+            entry[24..26].copy_from_slice(&now.date.to_le_bytes());		// write date
+            if let Some(size) = new_size {
+                entry[28..32].copy_from_slice(&size.to_le_bytes());
+            }
+        })
+    }
+
+    // Rewrites the short entry of the file or directory at `path` after `update` changed it
+    fn update_entry(&mut self, path: &str, update: impl FnOnce(&mut [u8])) -> Result<(), Errno> {
+        let record = self.path_record(path)?;
+        self.update_short_entry(&record, update);
+        Ok(())
+    }
+
+    fn path_record(&mut self, path: &str) -> Result<DirectoryRecord, Errno> {
         let (dir_path, filename) = split_path(path)?;
         let cluster_dir = self.find_directory_cluster(dir_path)?;
-        let mut entries = self.read_directory(cluster_dir);
-    
-        for entry in entries.iter_mut() {
-            if entry.filename().eq_ignore_ascii_case(filename) {
-                entry.cluster_low = (cluster & 0xFFFF) as u16;
-                entry.cluster_high = ((cluster >> 16) & 0xFFFF) as u16;
-    
-                self.write_directory(cluster_dir, &entries)?;
-                return Ok(());
-            }
-        }
-    
-        Err("File not found in the directory")
+        self.find_record(cluster_dir, filename).ok_or(Errno::NotFound)
     }
 
-    pub fn find_directory_cluster(&mut self, path: &str) -> Result<u32, &'static str> {
+    pub fn find_directory_cluster(&mut self, path: &str) -> Result<u32, Errno> {
         if path == "/" {
             return Ok(self.root_dir_cluster);
         }
@@ -386,7 +620,7 @@ impl FAT32Volume {
             let mut found = false;
     
             for entry in entries {
-                if entry.filename().eq_ignore_ascii_case(component) && entry.is_directory() {
+                if entry.matches(component) && entry.is_directory() {
                     cluster = entry.starting_cluster();
                     found = true;
                     break;
@@ -394,61 +628,168 @@ impl FAT32Volume {
             }
     
             if !found {
-                return Err("Directory not found");
+                return Err(Errno::NotFound);
             }
         }
     
         Ok(cluster)
     }
     
-    pub fn write_directory(&mut self, cluster: u32, entries: &[DirectoryEntry]) -> Result<(), &'static str> {
-        let mut data = vec![0u8; self.cluster_size];
-        let mut offset = 0;
-    
-        for entry in entries {
-            if offset + 32 > data.len() {
-                return Err("Too many entries in directory");
+    pub fn read_directory(&mut self, cluster: u32) -> Vec<DirectoryEntry> {
+        self.read_records(cluster).into_iter().map(|record| record.entry).collect()
+    }
+
+    fn read_records(&mut self, cluster: u32) -> Vec<DirectoryRecord> {
+        let mut records = Vec::new();
+        let mut long_name = LongNameParts::default();
+        let mut current_cluster = cluster;
+        while current_cluster < 0x0FFFFFF8 {
+            if !self.parse_records(current_cluster, &mut long_name, &mut records) {
+                break;
+            }
+            match self.next_cluster(current_cluster) {
+                Some(next) => current_cluster = next,
+                None => break,
             }
-    
-            data[offset..offset + 11].copy_from_slice(&entry.name); 								// name 
-            data[offset + 11] = entry.attr; 														// attib	
-            data[offset + 20..offset + 22].copy_from_slice(&entry.cluster_high.to_le_bytes());		// high
-            data[offset + 26..offset + 28].copy_from_slice(&entry.cluster_low.to_le_bytes());		// low
-            data[offset + 28..offset + 32].copy_from_slice(&entry.file_size.to_le_bytes());			// size
-    
-            offset += 32;
         }
-    
+        records
+    }
+
+    // Records of a directory stored in the given clusters, for when its chain cannot
+    // be trusted
+    pub(crate) fn records_in(&mut self, clusters: &[u32]) -> Vec<DirectoryRecord> {
+        let mut records = Vec::new();
+        let mut long_name = LongNameParts::default();
+        for &cluster in clusters {
+            if !self.parse_records(cluster, &mut long_name, &mut records) {
+                break;
+            }
+        }
+        records
+    }
+
+    // Adds the records of one cluster of a directory, false once its end marker was found
+    fn parse_records(&mut self, cluster: u32, long_name: &mut LongNameParts, records: &mut Vec<DirectoryRecord>) -> bool {
+        let data = self.read_cluster(cluster);
+        for i in 0..(self.cluster_size / 32) {		// 32 bytes per entry
+            let offset = i * 32;
+            let entry = &data[offset..offset + 32];
+            if entry[0] == 0x00 { return false; }
+            if entry[0] == 0xE5 {
+                *long_name = LongNameParts::default();
+                continue;
+            }
+            if entry[11] == LFN_ATTR {
+                long_name.add(entry, (cluster, offset));
+                continue;
+            }
+
+            let name: [u8; 11] = entry[0..11].try_into().unwrap();
+            let (long_name, mut slots) = long_name.take(&name);
+            if entry[11] & 0x08 != 0 {		// volume label
+                continue;
+            }
+            slots.push((cluster, offset));
+            records.push(DirectoryRecord { entry: DirectoryEntry::parse(name, long_name, entry), slots });
+        }
+        true
+    }
+
+    fn find_record(&mut self, cluster: u32, name: &str) -> Option<DirectoryRecord> {
+        self.read_records(cluster).into_iter().find(|record| record.entry.matches(name))
+    }
+
+    // Rewrites the short entry of the record after `update` changed it
+    pub(crate) fn update_short_entry(&mut self, record: &DirectoryRecord, update: impl FnOnce(&mut [u8])) {
+        let (cluster, offset) = record.short_slot();
+        let mut data = self.read_cluster(cluster);
+        update(&mut data[offset..offset + 32]);
         self.write_cluster(cluster, &data);
-        Ok(())
     }
-    
 
-    pub fn read_directory(&mut self, cluster: u32) -> Vec<DirectoryEntry> {
-        let mut entries = Vec::new();
+    fn write_slots(&mut self, slots: &[(u32, usize)], contents: &[[u8; 32]]) {
+        let mut current: Option<(u32, Vec<u8>)> = None;
+        for (&(cluster, offset), content) in slots.iter().zip(contents) {
+            if current.as_ref().is_some_and(|(current_cluster, _)| *current_cluster != cluster) {
+                let (done_cluster, data) = current.take().unwrap();
+                self.write_cluster(done_cluster, &data);
+            }
+            let (_, data) = current.get_or_insert_with(|| (cluster, self.read_cluster(cluster)));
+            data[offset..offset + 32].copy_from_slice(content);
+        }
+        if let Some((cluster, data)) = current {
+            self.write_cluster(cluster, &data);
+        }
+    }
+
+    // `count` consecutive free slots of the directory, which may run over a cluster boundary.
+    // The directory gets more clusters if it has no such run yet.
+    fn find_free_slots(&mut self, cluster: u32, count: usize) -> Result<Vec<(u32, usize)>, Errno> {
+        let mut run = Vec::new();
         let mut current_cluster = cluster;
-        while current_cluster < 0x0FFFFFF8 {
+        loop {
             let data = self.read_cluster(current_cluster);
-            for i in 0..(self.cluster_size / 32) {		// 32 bytes per entry
+            for i in 0..(self.cluster_size / 32) {
                 let offset = i * 32;
-                let entry = &data[offset..offset + 32];
-                if entry[0] == 0x00 { return entries; }
-                if entry[0] == 0xE5 || entry[11] == 0x0F { continue; }
-                let dir_entry = DirectoryEntry {
-                    name: entry[0..11].try_into().unwrap(),
-                    attr: entry[11],
-                    cluster_high: u16::from_le_bytes([entry[20], entry[21]]),
-                    cluster_low: u16::from_le_bytes([entry[26], entry[27]]),
-                    file_size: u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]),
-                };
-                entries.push(dir_entry);
+                if data[offset] == 0x00 || data[offset] == 0xE5 {
+                    run.push((current_cluster, offset));
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
             }
-            match self.next_cluster(current_cluster) {
-                Some(next) => current_cluster = next,
-                None => break,
+            current_cluster = match self.next_cluster(current_cluster) {
+                Some(next) => next,
+                None => self.extend_chain(current_cluster)?,
+            };
+        }
+    }
+
+    // Links a zeroed cluster to the end of a chain. For a directory all its slots are free then.
+    fn extend_chain(&mut self, last_cluster: u32) -> Result<u32, Errno> {
+        let new_cluster = self.allocate_cluster().ok_or(Errno::NoSpace)?;
+        self.write_cluster(new_cluster, &vec![0u8; self.cluster_size]);
+        self.set_next_cluster(last_cluster, new_cluster);
+        Ok(new_cluster)
+    }
+
+    // Short name and, if the name does not fit 8.3 as it is, long name of a new entry in the directory.
+    // The entry whose short entry is in slot `renamed` does not count, it is the one getting the name.
+    fn new_entry_names(&mut self, cluster: u32, name: &str, renamed: Option<(u32, usize)>) -> Result<([u8; 11], Option<String>), Errno> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(|c| "\"*/:<>?\\|".contains(c) || c < ' ') {
+            return Err(Errno::InvalidArgument);
+        }
+        if name.encode_utf16().count() > MAX_NAME_LEN {
+            return Err(Errno::NameTooLong);
+        }
+
+        let (basis, exact, truncated) = short_name_basis(name);
+        if exact {
+            return Ok((basis, None));
+        }
+
+        let taken: Vec<[u8; 11]> = self.read_records(cluster).into_iter()
+            .filter(|record| Some(record.short_slot()) != renamed)
+            .map(|record| record.entry.name)
+            .collect();
+        if !truncated && !taken.contains(&basis) {
+            return Ok((basis, Some(name.to_string())));
+        }
+
+        let base_len = basis[..8].iter().position(|&b| b == b' ').unwrap_or(8);
+        for n in 1..1_000_000 {
+            let tail = format!("~{}", n);
+            let keep = base_len.min(8 - tail.len());
+            let mut alias = basis;
+            alias[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+            alias[keep + tail.len()..8].fill(b' ');
+            if !taken.contains(&alias) {
+                return Ok((alias, Some(name.to_string())));
             }
         }
-        entries
+        Err(Errno::NoSpace)
     }
 
     pub fn find_path(&mut self, path: &str) -> Option<DirectoryEntry> {
@@ -458,7 +799,7 @@ impl FAT32Volume {
             let entries = self.read_directory(cluster);
             let mut found = false;
             for entry in entries {
-                if entry.filename().eq_ignore_ascii_case(component) {
+                if entry.matches(component) {
                     if components.peek().is_some() {
                         if entry.is_directory() {
                             cluster = entry.starting_cluster();
@@ -477,37 +818,24 @@ impl FAT32Volume {
         None
     }
 
-    pub fn write_directory_entry(&mut self, cluster: u32, entry: &DirectoryEntry) -> Result<(), &'static str> {
-        let mut current_cluster = cluster;
-
-        while current_cluster < 0x0FFFFFF8 {
-            let mut data = self.read_cluster(current_cluster);
-
-            for i in 0..(self.cluster_size / 32) {
-                let offset = i * 32;
-                if data[offset] == 0x00 || data[offset] == 0xE5 {
-                    data[offset..offset + 11].copy_from_slice(&entry.name);									// name
-                    data[offset + 11] = entry.attr;															// attr 
-                    data[offset + 20..offset + 22].copy_from_slice(&entry.cluster_high.to_le_bytes());		// high
-                    data[offset + 26..offset + 28].copy_from_slice(&entry.cluster_low.to_le_bytes());		// low
-                    data[offset + 28..offset + 32].copy_from_slice(&entry.file_size.to_le_bytes());			// size
-
-                    self.write_cluster(current_cluster, &data);
-                    return Ok(());
-                }
-            }
-
-            match self.next_cluster(current_cluster) {
-                Some(next) => current_cluster = next,
-                None => break,
-            }
-        }
+    // Writes the entry with its long name slots, if it has a long name
+    pub fn write_directory_entry(&mut self, cluster: u32, entry: &DirectoryEntry) -> Result<(), Errno> {
+        let mut contents = match &entry.long_name {
+            Some(long_name) => long_name_slots(long_name, &entry.name),
+            None => Vec::new(),
+        };
+        contents.push(entry.to_bytes());
 
-        Err("Insufficient space for creating DirectoryEntry")
+        let slots = self.find_free_slots(cluster, contents.len())?;
+        self.write_slots(&slots, &contents);
+        Ok(())
     }
 
-	pub fn create_directory(&mut self, path: &str) -> Result<(), &'static str> {
+	pub fn create_directory(&mut self, path: &str) -> Result<(), Errno> {
         let path = path.trim_matches('/');
+        if self.find_path(path).is_some() {
+            return Err(Errno::AlreadyExists);
+        }
     
         let (parent_path, name) = match path.rfind('/') {
             Some(pos) => (&path[..pos], &path[pos + 1..]),
@@ -518,32 +846,22 @@ impl FAT32Volume {
             self.root_dir_cluster
         } else {
             self.find_path(parent_path)
-                .ok_or("Parent dir not found")?
+                .ok_or(Errno::NotFound)?
                 .starting_cluster()
         };
     
-        let new_cluster = self.allocate_cluster().ok_or("No clusters available")?;
-    
-        let dir_data: &mut [u8] = &mut [0u8; 4096];
-    
-        if self.cluster_size > 4096 {
-            return Err("Cluster is too large: increase size of the dir_data buffer");
-        }
-    
-        // "." and ".."
-        write_dot_entry(&mut dir_data[0..32], ".", 0x10, new_cluster);
-        write_dot_entry(&mut dir_data[32..64], "..", 0x10, parent_cluster);
-        self.write_cluster(new_cluster, &dir_data[..self.cluster_size]);
+        let (name_raw, long_name) = self.new_entry_names(parent_cluster, name, None)?;
 
-        let name_raw = to_short_name(name);
+        let mut dir_data = vec![0u8; self.cluster_size];
+
+        let new_cluster = self.allocate_cluster().ok_or(Errno::NoSpace)?;
     
-        let entry = DirectoryEntry {
-            name: name_raw,
-            attr: 0x10, // directory
-            cluster_high: (new_cluster >> 16) as u16,
-            cluster_low: new_cluster as u16,
-            file_size: 0,
-        };
+        let entry = DirectoryEntry::new(name_raw, long_name, 0x10, new_cluster, 0); // directory
+
+        // "." and ".." carry the times of the directory
+        write_dot_entry(&mut dir_data[0..32], ".", &entry, new_cluster);
+        write_dot_entry(&mut dir_data[32..64], "..", &entry, self.dot_dot_cluster(parent_cluster));
+        self.write_cluster(new_cluster, &dir_data);
     
         self.write_directory_entry(parent_cluster, &entry)?;
         Ok(())
@@ -551,8 +869,11 @@ impl FAT32Volume {
     
 
 
-	pub fn create_file(&mut self, path: &str, size: u32) -> Result<(), &'static str> {
+	pub fn create_file(&mut self, path: &str, size: u32) -> Result<(), Errno> {
         let path = path.trim_matches('/');
+        if self.find_path(path).is_some() {
+            return Err(Errno::AlreadyExists);
+        }
 
         let (parent_path, name) = match path.rfind('/') {
             Some(pos) => (&path[..pos], &path[pos + 1..]),
@@ -563,15 +884,17 @@ impl FAT32Volume {
             self.root_dir_cluster
         } else {
             self.find_path(parent_path)
-                .ok_or("Parent dir not founf")?
+                .ok_or(Errno::NotFound)?
                 .starting_cluster()
         };
 
+        let (name_raw, long_name) = self.new_entry_names(parent_cluster, name, None)?;
+
         let clusters_needed = ((size + self.cluster_size as u32 - 1) / self.cluster_size as u32) as usize;
 
         let mut cluster_chain = Vec::new();
         for _ in 0..clusters_needed {
-            let cluster = self.allocate_cluster().ok_or("No clusters available")?;
+            let cluster = self.allocate_cluster().ok_or(Errno::NoSpace)?;
             if let Some(&prev) = cluster_chain.last() {
                 self.set_next_cluster(prev, cluster);
             }
@@ -582,18 +905,9 @@ impl FAT32Volume {
             self.set_next_cluster(last, 0x0FFFFFFF); // EOF
         }
 
-        // DirectoryEntry
-        let name_raw = to_short_name(name);
-
         let start_cluster = *cluster_chain.first().unwrap_or(&0);
 
-        let entry = DirectoryEntry {
-            name: name_raw,
-            attr: 0x20,
-            cluster_high: (start_cluster >> 16) as u16,
-            cluster_low: start_cluster as u16,
-            file_size: size,
-        };
+        let entry = DirectoryEntry::new(name_raw, long_name, 0x20, start_cluster, size);
 
         self.write_directory_entry(parent_cluster, &entry)?;
 
@@ -601,108 +915,189 @@ impl FAT32Volume {
     }
 
 
-    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, &'static str> {
-        let entry = self.find_path(path).ok_or("File not found")?;
+    // Cluster `index` of the chain that starts at `first`. With `extend` the chain
+    // gets zeroed clusters if it is shorter, otherwise that is an error.
+    fn chain_cluster(&mut self, first: u32, index: usize, extend: bool) -> Result<u32, Errno> {
+        let mut cluster = first;
+        for _ in 0..index {
+            cluster = match self.next_cluster(cluster) {
+                Some(next) => next,
+                None if extend => self.extend_chain(cluster)?,
+                None => return Err(Errno::IoError),
+            };
+        }
+        Ok(cluster)
+    }
 
+    // Runs of consecutive sectors (first sector, count) holding the file, in order,
+    // and the size of the file
+    pub fn file_extents(&mut self, path: &str) -> Result<(Vec<(u32, u32)>, usize), Errno> {
+        let entry = self.find_path(path).ok_or(Errno::NotFound)?;
         if entry.is_directory() {
-            return Err("Directory, not a file");
+            return Err(Errno::IsADirectory);
         }
 
-        let mut remaining = entry.file_size;
+        let size = entry.file_size as usize;
+        let sectors = self.sectors_per_cluster as u32;
+        let mut extents: Vec<(u32, u32)> = Vec::new();
         let mut cluster = entry.starting_cluster();
-        let mut result = Vec::with_capacity(remaining as usize);
+        for index in 0..size.div_ceil(self.cluster_size) {
+            if index > 0 {
+                cluster = self.next_cluster(cluster).ok_or(Errno::IoError)?;
+            }
+            let lba = self.first_sector_of_cluster(cluster);
+            match extents.last_mut() {
+                Some((start, count)) if *start + *count == lba => *count += sectors,
+                _ => extents.push((lba, sectors)),
+            }
+        }
+        Ok((extents, size))
+    }
 
-        while cluster < 0x0FFFFFF8 && remaining > 0 {
-            let data = self.read_cluster(cluster);
-            let to_copy = remaining.min(self.cluster_size as u32);
-            result.extend_from_slice(&data[..to_copy as usize]);
-            remaining -= to_copy;
+    // Reads from `offset` of the file into the buffer, returns the number of bytes read
+    pub fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let entry = self.find_path(path).ok_or(Errno::NotFound)?;
+        if entry.is_directory() {
+            return Err(Errno::IsADirectory);
+        }
 
-            match self.next_cluster(cluster) {
-                Some(next) => cluster = next,
-                None => break,
-            }
+        // the access date only has days, so most reads leave the entry alone
+        let today = FatTime::now().date;
+        if entry.accessed_date != today {
+            self.update_entry(path, |entry| entry[18..20].copy_from_slice(&today.to_le_bytes()))?;
+        }
+
+        let size = entry.file_size as u64;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
         }
+        let len = (size - offset).min(buf.len() as u64) as usize;
 
-        Ok(result)
+        let cluster_size = self.cluster_size;
+        let mut cluster = self.chain_cluster(entry.starting_cluster(), offset as usize / cluster_size, false)?;
+        let mut in_cluster = offset as usize % cluster_size;
+        let mut done = 0;
+        loop {
+            let data = self.read_cluster(cluster);
+            let to_copy = (cluster_size - in_cluster).min(len - done);
+            buf[done..done + to_copy].copy_from_slice(&data[in_cluster..in_cluster + to_copy]);
+            done += to_copy;
+            if done == len {
+                return Ok(len);
+            }
+            in_cluster = 0;
+            cluster = self.next_cluster(cluster).ok_or(Errno::IoError)?;
+        }
     }
 
-	pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), &'static str> {
-        let entry = self.find_path(path).ok_or("File not found")?;
+    // Writes the data at `offset` of the file, growing it if needed. A gap between
+    // the end of the file and the offset is filled with zeros.
+    pub fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+        let entry = self.find_path(path).ok_or(Errno::NotFound)?;
         if entry.is_directory() {
-            return Err("Cant write in directory");
+            return Err(Errno::IsADirectory);
         }
-    
-        let mut current_cluster = entry.starting_cluster();
-        
-        if current_cluster < 2 {
-            current_cluster = self.allocate_cluster().ok_or("No clusters available")?;
-            self.set_next_cluster(current_cluster, 0x0FFFFFFF);
-        
-            self.set_entry_cluster(path, current_cluster)?;
-        }
-        
-        let mut offset = 0;
-        let total_size = data.len();
-    
-        let mut cluster_chain_buf = [0u32; 128];
-        let mut chain_len = 0;
-    
-        cluster_chain_buf[chain_len] = current_cluster;
-        chain_len += 1;
-    
-        while let Some(next) = self.next_cluster(current_cluster) {
-            if next >= 0x0FFFFFF8 {
-                break;
+
+        let end = offset.checked_add(data.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(Errno::FileTooLarge)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        self.write_range(path, &entry, offset, data, end)?;
+        Ok(data.len())
+    }
+
+    // Writes zeros from the end of the file up to `offset`, then the data, and sets the
+    // size to `end` if that is more. Whether the volume has the clusters for all of it
+    // is checked first, and the zeros are written a cluster at a time.
+    fn write_range(&mut self, path: &str, entry: &DirectoryEntry, offset: u64, data: &[u8], end: u64) -> Result<(), Errno> {
+        let size = entry.file_size as u64;
+        let start = offset.min(size);
+        let cluster_size = self.cluster_size as u64;
+        let needed = end.div_ceil(cluster_size).saturating_sub(size.div_ceil(cluster_size));
+        if needed > 0 && needed > self.free_clusters() as u64 {
+            return Err(Errno::NoSpace);
+        }
+
+        let mut first = entry.starting_cluster();
+        if first < 2 {
+            first = self.allocate_cluster().ok_or(Errno::NoSpace)?;
+            self.set_entry_cluster(path, first)?;
+        }
+
+        let mut cluster = self.chain_cluster(first, (start / cluster_size) as usize, true)?;
+        let mut position = start;
+        let mut block = vec![0u8; cluster_size as usize];
+        loop {
+            let in_cluster = (position % cluster_size) as usize;
+            let len = (cluster_size - in_cluster as u64).min(end - position);
+            // whole clusters are not read first
+            if len < cluster_size {
+                block = self.read_cluster(cluster);
             }
-            current_cluster = next;
-            if chain_len >= cluster_chain_buf.len() {
-                return Err("Clusters chain is too long");
+
+            // zeros before `offset`, then the data
+            let gap = (offset.clamp(position, position + len) - position) as usize;
+            block[in_cluster..in_cluster + gap].fill(0);
+            if gap < len as usize {
+                let from = (position + gap as u64 - offset) as usize;
+                let to = (position + len - offset) as usize;
+                block[in_cluster + gap..in_cluster + len as usize].copy_from_slice(&data[from..to]);
             }
-            cluster_chain_buf[chain_len] = current_cluster;
-            chain_len += 1;
+            self.write_cluster(cluster, &block);
+
+            position += len;
+            if position == end {
+                break;
+            }
+            cluster = match self.next_cluster(cluster) {
+                Some(next) => next,
+                None => self.extend_chain(cluster)?,
+            };
         }
-    
-        let clusters_needed = (total_size + self.cluster_size - 1) / self.cluster_size;
-    
-        while chain_len < clusters_needed {
-            let new_cluster = self.allocate_cluster().ok_or("No clusters available")?;
-            self.set_next_cluster(cluster_chain_buf[chain_len - 1], new_cluster);
-            cluster_chain_buf[chain_len] = new_cluster;
-            self.set_next_cluster(new_cluster, 0x0FFFFFFF);
-            chain_len += 1;
+
+        self.touch(path, (end > size).then_some(end as u32))
+    }
+
+    // Cuts the file to `new_size` bytes and frees the clusters it no longer needs,
+    // or fills it with zeros up to that size
+    pub fn truncate(&mut self, path: &str, new_size: u64) -> Result<(), Errno> {
+        let entry = self.find_path(path).ok_or(Errno::NotFound)?;
+        if entry.is_directory() {
+            return Err(Errno::IsADirectory);
         }
-    
-        let mut block = [0u8; 4096];
-        if self.cluster_size > 4096 {
-            return Err("Cluster is too large: increase size of the block buffer");
+
+        let size = entry.file_size as u64;
+        if new_size > u32::MAX as u64 {
+            return Err(Errno::FileTooLarge);
         }
-    
-        offset = 0;
-        for i in 0..clusters_needed {
-            let cluster = cluster_chain_buf[i];
-            let to_copy = core::cmp::min(total_size - offset, self.cluster_size);
-            for j in 0..to_copy {
-                block[j] = data[offset + j];
+        if new_size > size {
+            return self.write_range(path, &entry, new_size, &[], new_size);
+        }
+        if new_size == size {
+            return Ok(());
+        }
+
+        let first = entry.starting_cluster();
+        let keep = (new_size as usize + self.cluster_size - 1) / self.cluster_size;
+        if keep == 0 {
+            if first >= 2 {
+                self.free_cluster_chain(first);
+                self.set_entry_cluster(path, 0)?;
+            }
+        } else {
+            let last = self.chain_cluster(first, keep - 1, false)?;
+            if let Some(next) = self.next_cluster(last) {
+                self.set_next_cluster(last, 0x0FFFFFFF);
+                self.free_cluster_chain(next);
             }
-            self.write_cluster(cluster, &block[..self.cluster_size]);
-            offset += to_copy;
         }
-    
-		if chain_len > clusters_needed {
-		    let to_free = &cluster_chain_buf[clusters_needed..chain_len];
-		    self.set_next_cluster(cluster_chain_buf[clusters_needed - 1], 0x0FFFFFFF);
-		    for &c in to_free {
-		        self.free_cluster_chain(c);
-		    }
-		}
-    
-        self.update_file_size(path, total_size as u32)?;
-        Ok(())
+        self.touch(path, Some(new_size as u32))
     }
     
 
-	pub fn delete_file(&mut self, path: &str) -> Result<(), &'static str> {
+	pub fn delete_file(&mut self, path: &str) -> Result<(), Errno> {
         let path = path.trim_matches('/');
     
         let (parent_path, name) = match path.rfind('/') {
@@ -714,7 +1109,7 @@ impl FAT32Volume {
             self.root_dir_cluster
         } else {
             self.find_path(parent_path)
-                .ok_or("Parent dir not found")?
+                .ok_or(Errno::NotFound)?
                 .starting_cluster()
         };
     
@@ -722,10 +1117,10 @@ impl FAT32Volume {
     }
     
 
-	pub fn delete_directory(&mut self, path: &str) -> Result<(), &'static str> {
-	    let entry = self.find_path(path).ok_or("Directory not found")?;
+	pub fn delete_directory(&mut self, path: &str) -> Result<(), Errno> {
+	    let entry = self.find_path(path).ok_or(Errno::NotFound)?;
 	    if !entry.is_directory() {
-	        return Err("Not a directory");
+	        return Err(Errno::NotADirectory);
 	    }
 
 	    let cluster = entry.starting_cluster();
@@ -737,7 +1132,7 @@ impl FAT32Volume {
 	        .collect();
 
 	    if !user_entries.is_empty() {
-	        return Err("Directory is not empty");
+	        return Err(Errno::DirectoryNotEmpty);
 	    }
 
 	    let (parent_path, name) = match path.rfind('/') {
@@ -749,130 +1144,103 @@ impl FAT32Volume {
 	        self.root_dir_cluster
 	    } else {
 	        self.find_path(parent_path)
-	            .ok_or("Parent dir not found")?
+	            .ok_or(Errno::NotFound)?
 	            .starting_cluster()
 	    };
 
 	    self.delete_entry_from_dir(parent_cluster, name)
 	}
 
-	pub fn delete_entry_from_dir(&mut self, parent_cluster: u32, name: &str,) -> Result<(), &'static str> {
-	    let raw_name = to_short_name(name);
-	    let mut current_cluster = parent_cluster;
-
-	    while current_cluster < 0x0FFFFFF8 {
-	        let mut data = self.read_cluster(current_cluster);
-
-	        for i in 0..(self.cluster_size / 32) {
-	            let offset = i * 32;
-	            let entry = &mut data[offset..offset + 32];
-
-	            if entry[0] == 0x00 || entry[0] == 0xE5 || entry[11] == 0x0F {
-	                continue;
-	            }
-
-	            if &entry[0..11] == &raw_name {
-	                let cluster_low = u16::from_le_bytes([entry[26], entry[27]]);
-	                let cluster_high = u16::from_le_bytes([entry[20], entry[21]]);
-	                let first_cluster = ((cluster_high as u32) << 16) | (cluster_low as u32);
-	                self.free_cluster_chain(first_cluster);
-
-	                // delete
-	                entry[0] = 0xE5;
-	                self.write_cluster(current_cluster, &data);
-	                return Ok(());
-	            }
-	        }
+	pub fn delete_entry_from_dir(&mut self, parent_cluster: u32, name: &str,) -> Result<(), Errno> {
+	    let record = self.find_record(parent_cluster, name).ok_or(Errno::NotFound)?;
+	    self.free_cluster_chain(record.entry.starting_cluster());
+	    self.remove_record(&record);
+	    Ok(())
+	}
 
-	        match self.next_cluster(current_cluster) {
-	            Some(next) => current_cluster = next,
-	            None => break,
+	// Marks the short entry of the record free together with its long name slots
+	pub(crate) fn remove_record(&mut self, record: &DirectoryRecord) {
+	    let mut contents = Vec::new();
+	    let mut current: Option<(u32, Vec<u8>)> = None;
+	    for &(cluster, offset) in &record.slots {
+	        if current.as_ref().is_none_or(|(current_cluster, _)| *current_cluster != cluster) {
+	            current = Some((cluster, self.read_cluster(cluster)));
 	        }
+	        let (_, data) = current.as_ref().unwrap();
+	        let mut slot: [u8; 32] = data[offset..offset + 32].try_into().unwrap();
+	        slot[0] = 0xE5;
+	        contents.push(slot);
 	    }
-
-	    Err("Entry not found")
+	    self.write_slots(&record.slots, &contents);
 	}
 
+    // Moves the file or directory at `old` to `new`, which may be in another directory
+    // but must not exist yet. The entry keeps its clusters and times. The new entry is
+    // written before the old one is removed, so an interrupted rename leaves both
+    // rather than none.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), Errno> {
+        let old = old.trim_matches('/');
+        let new = new.trim_matches('/');
+        if old.is_empty() || new.is_empty() {
+            return Err(Errno::Busy);
+        }
+        // a directory cannot go into itself
+        if new.len() > old.len() && new.as_bytes()[old.len()] == b'/' && new[..old.len()].eq_ignore_ascii_case(old) {
+            return Err(Errno::InvalidArgument);
+        }
 
-	pub fn update_file_size(&mut self, path: &str, new_size: u32) -> Result<(), &'static str> {
-        let path = path.trim_matches('/');
-    
-        let (parent_path, name) = match path.rfind('/') {
-            Some(pos) => (&path[..pos], &path[pos + 1..]),
-            None => ("", path),
-        };
-        
-        let parent_cluster = if parent_path.is_empty() {
-            self.root_dir_cluster
-        } else {
-            self.find_path(parent_path)
-                .ok_or("Parent dir not found")?
-                .starting_cluster()
-        };
-    
-        let mut current_cluster = parent_cluster;
-    
-        while current_cluster < 0x0FFFFFF8 {
-            let mut data = self.read_cluster(current_cluster);
-    
-            for i in 0..(self.cluster_size / 32) {
-                let offset = i * 32;
-                let entry = &mut data[offset..offset + 32];
-    
-                if entry[0] == 0x00 || entry[0] == 0xE5 || entry[11] == 0x0F {
-                    continue;
-                }
-    
-                let raw_name = to_short_name(name);
-    
-                if &entry[0..11] == &raw_name {
-                    entry[28..32].copy_from_slice(&new_size.to_le_bytes());
-                    self.write_cluster(current_cluster, &data);
-                    return Ok(());
-                }
-            }
-    
-            match self.next_cluster(current_cluster) {
-                Some(next) => current_cluster = next,
-                None => break,
+        let (old_parent, old_name) = split_path(old)?;
+        let (new_parent, new_name) = split_path(new)?;
+        let old_parent_cluster = self.find_directory_cluster(old_parent)?;
+        let new_parent_cluster = self.find_directory_cluster(new_parent)?;
+        let record = self.find_record(old_parent_cluster, old_name).ok_or(Errno::NotFound)?;
+        // only a change of case may find the entry itself
+        if let Some(existing) = self.find_record(new_parent_cluster, new_name) {
+            if existing.short_slot() != record.short_slot() {
+                return Err(Errno::AlreadyExists);
             }
         }
-    
-        Err("DirectoryEntry not found")
+
+        let (name, long_name) = self.new_entry_names(new_parent_cluster, new_name, Some(record.short_slot()))?;
+        let mut entry = record.entry.clone();
+        entry.name = name;
+        entry.long_name = long_name;
+        // the case of the old name does not apply to the new one
+        entry.case_flags = 0;
+        self.write_directory_entry(new_parent_cluster, &entry)?;
+        self.remove_record(&record);
+
+        if entry.is_directory() && old_parent_cluster != new_parent_cluster {
+            self.set_dot_dot(entry.starting_cluster(), new_parent_cluster);
+        }
+        Ok(())
     }
-    
-    
-    pub fn list_dir(&mut self, path: &str) -> Result<Vec<String>, &'static str> {
-        let cluster = if path == "/" {
-            self.root_dir_cluster
-        } else {
-            let entry = self.find_path(path).ok_or("Directory not found")?;
-            if !entry.is_directory() {
-                return Err("Not a directory");
-            }
-            entry.starting_cluster()
-        };
 
-        let entries = self.read_directory(cluster);
-        Ok(entries
-            .into_iter()
-            .filter(|e| e.filename() != "." && e.filename() != "..")
-            .map(|e| {
-                if e.is_directory() {
-                    format!("{}/", e.filename())
-                } else {
-                    e.filename()
-                }
-            })
-            .collect())
+    // Points `..` of the directory starting at `cluster` at its new parent
+    fn set_dot_dot(&mut self, cluster: u32, parent_cluster: u32) {
+        let parent = self.dot_dot_cluster(parent_cluster);
+        let mut data = self.read_cluster(cluster);
+        let entry = &mut data[32..64];
+        if &entry[0..11] != b"..         " {
+            return;
+        }
+        entry[20..22].copy_from_slice(&((parent >> 16) as u16).to_le_bytes());		// high
+        entry[26..28].copy_from_slice(&(parent as u16).to_le_bytes());			// low
+        self.write_cluster(cluster, &data);
+    }
+
+    // Cluster `..` stores for a parent, 0 when it is the root directory
+    fn dot_dot_cluster(&self, parent_cluster: u32) -> u32 {
+        if parent_cluster == self.root_dir_cluster { 0 } else { parent_cluster }
     }
 
+
     pub fn file_exists(&mut self, path: &str) -> bool {
         self.find_path(path).is_some()
     }
 }
 
-fn split_path(path: &str) -> Result<(&str, &str), &'static str> {
+fn split_path(path: &str) -> Result<(&str, &str), Errno> {
     let trimmed = path.trim_matches('/');
     if let Some(pos) = trimmed.rfind('/') {
         let (dir, file) = trimmed.split_at(pos);
@@ -883,44 +1251,133 @@ fn split_path(path: &str) -> Result<(&str, &str), &'static str> {
 }
 
 
-fn write_dot_entry(buf: &mut [u8], name: &str, attr: u8, cluster: u32) {
+// `.` or `..` entry pointing at `cluster`, with the attributes and times of `directory`
+fn write_dot_entry(buf: &mut [u8], name: &str, directory: &DirectoryEntry, cluster: u32) {
     let mut raw_name = [b' '; 11];
     let name_bytes = name.as_bytes();
     for (i, b) in name_bytes.iter().take(11).enumerate() {
         raw_name[i] = *b;
     }
 
-    buf[0..11].copy_from_slice(&raw_name);
-    buf[11] = attr;
-    buf[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
-    buf[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
-    buf[28..32].copy_from_slice(&0u32.to_le_bytes());
+    let mut entry = directory.clone();
+    entry.name = raw_name;
+    entry.long_name = None;
+    entry.cluster_high = (cluster >> 16) as u16;
+    entry.cluster_low = cluster as u16;
+    buf.copy_from_slice(&entry.to_bytes());
 }
 
-fn to_short_name(name: &str) -> [u8; 11] {
+// Characters allowed in 8.3 names besides letters and digits
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+// 8.3 form of a name: upper case, characters that are not allowed replaced by '_',
+// base and extension cut to 8 and 3. Also returns whether the name is exactly the
+// 8.3 one, and whether something other than the case was lost.
+fn short_name_basis(name: &str) -> ([u8; 11], bool, bool) {
     let mut short_name = [b' '; 11];
-    let parts: Vec<&str> = name.split('.').collect();
+    let mut exact = true;
+    let mut truncated = false;
 
-    if parts.len() == 2 {
-        for (i, b) in parts[0].bytes().take(8).enumerate() {
-            short_name[i] = b.to_ascii_uppercase();
-        }
-        for (i, b) in parts[1].bytes().take(3).enumerate() {
-            short_name[8 + i] = b.to_ascii_uppercase();
-        }
-    } else {
-        for (i, b) in name.bytes().take(11).enumerate() {
-            short_name[i] = b.to_ascii_uppercase();
+    let stripped = name.trim_start_matches('.');
+    if stripped.len() != name.len() {
+        truncated = true;
+    }
+    let (base, ext) = match stripped.rfind('.') {
+        Some(pos) => (&stripped[..pos], &stripped[pos + 1..]),
+        None => (stripped, ""),
+    };
+
+    for (part, start, len) in [(base, 0, 8), (ext, 8, 3)] {
+        let mut written = 0;
+        for c in part.chars() {
+            let b = match c {
+                ' ' | '.' => { truncated = true; continue; }
+                c if c.is_ascii_alphanumeric() || (c.is_ascii() && SHORT_NAME_SPECIAL.contains(&(c as u8))) => c.to_ascii_uppercase() as u8,
+                _ => { truncated = true; b'_' }
+            };
+            if b != c as u8 {
+                exact = false;
+            }
+            if written == len {
+                truncated = true;
+                break;
+            }
+            short_name[start + written] = b;
+            written += 1;
         }
     }
 
-    short_name
+    if short_name[0] == b' ' {
+        short_name[0] = b'_';
+        truncated = true;
+    }
+    (short_name, exact && !truncated, truncated)
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
 }
 
+// Long name slots of a name in the order they are written, last part first
+fn long_name_slots(name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + LFN_CHARS - 1) / LFN_CHARS;
+    let checksum = short_name_checksum(short_name);
+
+    (1..=count).rev().map(|sequence| {
+        let mut slot = [0u8; 32];
+        slot[0] = sequence as u8 | if sequence == count { LFN_LAST } else { 0 };
+        slot[11] = LFN_ATTR;
+        slot[13] = checksum;
+        for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+            // the name ends with a 0x0000, the rest of the slot is padded with 0xFFFF
+            let index = (sequence - 1) * LFN_CHARS + i;
+            let unit = match index.cmp(&units.len()) {
+                core::cmp::Ordering::Less => units[index],
+                core::cmp::Ordering::Equal => 0x0000,
+                core::cmp::Ordering::Greater => 0xFFFF,
+            };
+            slot[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        slot
+    }).collect()
+}
+
+
+// Whether the sector is the boot sector of a FAT32 volume with 512 byte sectors.
+// FAT16 and FAT12 keep the size of the FAT at 22 instead.
+pub fn is_fat32(vbr: &[u8; 512]) -> bool {
+    let bytes_per_sector = u16::from_le_bytes([vbr[11], vbr[12]]);
+    let fat_size_16 = u16::from_le_bytes([vbr[22], vbr[23]]);
+    let fat_size_sectors = u32::from_le_bytes([vbr[36], vbr[37], vbr[38], vbr[39]]);
+    vbr[510..512] == [0x55, 0xAA] && (vbr[0] == 0xEB || vbr[0] == 0xE9)
+        && bytes_per_sector == 512 && vbr[13] != 0 && vbr[16] != 0
+        && fat_size_16 == 0 && fat_size_sectors != 0
+}
 
-pub fn mount_fat32(mut device: Box<dyn BlockDevice>) -> Result<FAT32Volume, &'static str> {
+// Opens the volume, after checking and repairing it if it was not unmounted cleanly.
+// It counts as in use from now on, until `unmount` marks it clean again.
+pub fn mount_fat32(device: Box<dyn BlockDevice>, sectors: u64) -> Result<FAT32Volume, &'static str> {
     println!("Mounting File System...");
 
+    let mut volume = open_fat32(device, sectors)?;
+    if volume.is_dirty() {
+        println!("The volume was not unmounted cleanly, checking it");
+        let report = fsck::check(&mut volume, true);
+        for problem in report.problems.iter() {
+            println!("fsck: {}", problem);
+        }
+        println!("fsck: {}", report.summary());
+    }
+    volume.set_clean(false);
+    volume.sync();
+
+    println!("File System    [OK]");
+    Ok(volume)
+}
+
+// Reads the boot sector and FSInfo of the volume
+pub fn open_fat32(mut device: Box<dyn BlockDevice>, sectors: u64) -> Result<FAT32Volume, &'static str> {
     let mut vbr = [0u8; 512];
     device.read_sector(0, &mut vbr);
 
@@ -931,13 +1388,56 @@ pub fn mount_fat32(mut device: Box<dyn BlockDevice>) -> Result<FAT32Volume, &'st
     let fat_size_sectors = u32::from_le_bytes([vbr[36], vbr[37], vbr[38], vbr[39]]);
     let root_dir_cluster = u32::from_le_bytes([vbr[44], vbr[45], vbr[46], vbr[47]]);
 
-    let fat_start_lba = reserved_sector_count as u32;
+    if !is_fat32(&vbr) {
+        return Err("Not a FAT32 volume");
+    }
 
-    let fat = FAT::new(fat_start_lba, fat_size_sectors, bytes_per_sector);
+    let total_sectors = u32::from_le_bytes([vbr[32], vbr[33], vbr[34], vbr[35]]);
+    let fs_info_sector = u16::from_le_bytes([vbr[48], vbr[49]]) as u32;
 
-    println!("File System    [OK]");
+    // A boot sector that does not fit the device of `sectors` sectors would have the
+    // volume read and write past its end
+    if total_sectors == 0 || total_sectors as u64 > sectors {
+        return Err("The volume is larger than the disk");
+    }
+    if reserved_sector_count == 0
+        || reserved_sector_count as u64 + num_fats as u64 * fat_size_sectors as u64 >= total_sectors as u64 {
+        return Err("The FATs do not fit in the volume");
+    }
 
-    Ok(FAT32Volume {
+    let fat_start_lba = reserved_sector_count as u32;
+    let data_start_lba = fat_start_lba + num_fats as u32 * fat_size_sectors;
+    let fat_entries = fat_size_sectors.saturating_mul(bytes_per_sector as u32 / 4);
+    let cluster_count = ((total_sectors - data_start_lba) / sectors_per_cluster as u32).min(fat_entries - 2);
+    if !(2..cluster_count + 2).contains(&root_dir_cluster) {
+        return Err("The root directory is not on the volume");
+    }
+
+    let fat = FAT::new(fat_start_lba, fat_size_sectors, bytes_per_sector, num_fats);
+
+    // The free count and next free cluster of FSInfo are only hints, they are
+    // checked against the cluster count and ignored when out of range. FSInfo is
+    // in the reserved sectors, when there is one at all.
+    let mut fs_info = [0u8; 512];
+    let fs_info_valid = fs_info_sector != 0 && fs_info_sector != 0xFFFF
+        && fs_info_sector < reserved_sector_count as u32
+        && device.try_read_sector(fs_info_sector, &mut fs_info).is_ok()
+        && fs_info[0..4] == FS_INFO_LEAD_SIGNATURE.to_le_bytes()
+        && fs_info[484..488] == FS_INFO_STRUCT_SIGNATURE.to_le_bytes();
+    let mut free_count = None;
+    let mut next_free = 2;
+    if fs_info_valid {
+        let count = u32::from_le_bytes([fs_info[488], fs_info[489], fs_info[490], fs_info[491]]);
+        let next = u32::from_le_bytes([fs_info[492], fs_info[493], fs_info[494], fs_info[495]]);
+        if count <= cluster_count {
+            free_count = Some(count);
+        }
+        if (2..cluster_count + 2).contains(&next) {
+            next_free = next;
+        }
+    }
+
+    let mut volume = FAT32Volume {
         fat,
         cluster_size: bytes_per_sector as usize * sectors_per_cluster as usize,
         root_dir_cluster,
@@ -947,5 +1447,245 @@ pub fn mount_fat32(mut device: Box<dyn BlockDevice>) -> Result<FAT32Volume, &'st
         reserved_sector_count,
         fat_size_sectors,
         num_fats,
-    })
+        cluster_count,
+        fs_info_sector: fs_info_valid.then_some(fs_info_sector),
+        free_count,
+        next_free,
+    };
+    // an unknown free count is counted once, FSInfo has it from the next sync on
+    if volume.free_count.is_none() && fs_info_valid {
+        volume.free_count = Some(volume.count_free_clusters());
+    }
+    Ok(volume)
+}
+
+
+// The volume as seen by the VFS. Inodes are the paths of the entries on the volume,
+// so an entry can not be renamed or removed while it or an entry below it has an inode.
+pub struct Fat32FileSystem {
+    volume: Arc<Mutex<FAT32Volume>>,
+    inodes: Arc<Mutex<LiveInodes>>,
+}
+
+// The entries that have inodes, by inode: the slot of the short entry and the first
+// clusters of the directories above it. Neither changes when an entry is renamed.
+#[derive(Default)]
+struct LiveInodes {
+    next_id: u64,
+    entries: BTreeMap<u64, ((u32, usize), Vec<u32>)>,
+}
+
+impl LiveInodes {
+    // Whether the entry with its short entry in `slot` has an inode, or, for a
+    // directory starting at `cluster`, something in it has one
+    fn in_use(&self, slot: (u32, usize), cluster: Option<u32>) -> bool {
+        self.entries.values().any(|(entry_slot, directories)| {
+            *entry_slot == slot || cluster.is_some_and(|cluster| directories.contains(&cluster))
+        })
+    }
+}
+
+impl Fat32FileSystem {
+    pub fn new(volume: FAT32Volume) -> Self {
+        Fat32FileSystem {
+            volume: Arc::new(Mutex::new(volume)),
+            inodes: Arc::new(Mutex::new(LiveInodes::default())),
+        }
+    }
+
+    // Checks the volume, which is locked meanwhile
+    pub fn check(&self, repair: bool) -> fsck::Report {
+        let mut volume = lock(&self.volume);
+        let report = fsck::check(&mut volume, repair);
+        volume.sync();
+        report
+    }
+
+    // Reads the file for a kernel task. Only finding its clusters goes through the
+    // volume, the data is read from `device` (the disk of the volume) while the
+    // executor runs the other tasks. Sectors still dirty in a cache are written back
+    // first, so the disk has the current content.
+    pub async fn read_file_async(&self, device: &dyn AsyncBlockDevice, path: &str) -> Result<Vec<u8>, Errno> {
+        let (extents, size) = {
+            let mut volume = loop {
+                match self.volume.try_lock() {
+                    Some(volume) => break volume,
+                    None => multitasking::cooperate().await,
+                }
+            };
+            volume.device.flush();
+            volume.file_extents(path)?
+        };
+
+        let mut data = vec![0u8; extents.iter().map(|&(_, count)| count as usize * 512).sum()];
+        let mut offset = 0;
+        for (lba, count) in extents {
+            let len = count as usize * 512;
+            device.read_sectors(lba, &mut data[offset..offset + len]).await?;
+            offset += len;
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+}
+
+// Waits for the volume by giving the time to other threads, as its holder may be a
+// thread waiting for the disk
+fn lock(volume: &Mutex<FAT32Volume>) -> MutexGuard<'_, FAT32Volume> {
+    loop {
+        if let Some(guard) = volume.try_lock() {
+            return guard;
+        }
+        thread::yield_now();
+    }
+}
+
+impl vfs::FileSystem for Fat32FileSystem {
+    fn root(&self) -> Arc<dyn Inode> {
+        let cluster = lock(&self.volume).root_dir_cluster;
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            path: "/".to_string(),
+            inodes: self.inodes.clone(),
+            id: None,
+            directories: Vec::new(),
+            cluster: Some(cluster),
+        })
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        lock(&self.volume).sync();
+        Ok(())
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<(), Errno> {
+        let mut volume = lock(&self.volume);
+        let record = volume.path_record(old.trim_matches('/'))?;
+        let cluster = record.entry.is_directory().then(|| record.entry.starting_cluster());
+        if self.inodes.lock().in_use(record.short_slot(), cluster) {
+            return Err(Errno::Busy);
+        }
+        volume.rename(old, new)
+    }
+
+    fn unmount(&self) -> Result<(), Errno> {
+        lock(&self.volume).unmount();
+        Ok(())
+    }
+}
+
+struct FatInode {
+    volume: Arc<Mutex<FAT32Volume>>,
+    path: String,
+    inodes: Arc<Mutex<LiveInodes>>,
+    // Key in `inodes`, None for the root directory, which has no entry
+    id: Option<u64>,
+    // First clusters of the directories above the entry
+    directories: Vec<u32>,
+    // First cluster of the directory, None for a file
+    cluster: Option<u32>,
+}
+
+impl FatInode {
+    fn child_path(&self, name: &str) -> String {
+        format!("{}/{}", self.path.trim_end_matches('/'), name)
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.inodes.lock().entries.remove(&id);
+        }
+    }
+}
+
+fn file_type(entry: &DirectoryEntry) -> FileType {
+    if entry.is_directory() { FileType::Directory } else { FileType::File }
+}
+
+impl Inode for FatInode {
+    // Read from the entry every time, as writes change the size
+    fn metadata(&self) -> Metadata {
+        // the root directory has no entry and so no times
+        let unknown = DateTime::default();
+        if self.path == "/" {
+            return Metadata { file_type: FileType::Directory, size: 0, created: unknown, modified: unknown, accessed: unknown };
+        }
+        match lock(&self.volume).find_path(&self.path) {
+            Some(entry) => Metadata {
+                file_type: file_type(&entry),
+                size: entry.file_size as u64,
+                created: entry.created.to_datetime(),
+                modified: entry.modified.to_datetime(),
+                accessed: FatTime { date: entry.accessed_date, time: 0, tenths: 0 }.to_datetime(),
+            },
+            None => Metadata { file_type: FileType::File, size: 0, created: unknown, modified: unknown, accessed: unknown },
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let cluster = self.cluster.ok_or(Errno::NotADirectory)?;
+        let record = lock(&self.volume).find_record(cluster, name).ok_or(Errno::NotFound)?;
+        let mut directories = self.directories.clone();
+        directories.push(cluster);
+
+        let mut inodes = self.inodes.lock();
+        let id = inodes.next_id;
+        inodes.next_id += 1;
+        inodes.entries.insert(id, (record.short_slot(), directories.clone()));
+        Ok(Arc::new(FatInode {
+            volume: self.volume.clone(),
+            path: self.child_path(name),
+            inodes: self.inodes.clone(),
+            id: Some(id),
+            directories,
+            cluster: record.entry.is_directory().then(|| record.entry.starting_cluster()),
+        }))
+    }
+
+    fn list(&self) -> Result<Vec<DirEntry>, Errno> {
+        let mut volume = lock(&self.volume);
+        let cluster = volume.find_directory_cluster(&self.path)?;
+        Ok(volume.read_directory(cluster)
+            .into_iter()
+            .filter(|e| e.filename() != "." && e.filename() != "..")
+            .map(|e| DirEntry { name: e.filename(), file_type: file_type(&e) })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<(), Errno> {
+        let path = self.child_path(name);
+        match file_type {
+            FileType::Directory => lock(&self.volume).create_directory(&path),
+            FileType::File => lock(&self.volume).create_file(&path, 0),
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Errno> {
+        let path = self.child_path(name);
+        let mut volume = lock(&self.volume);
+        let record = volume.find_record(self.cluster.ok_or(Errno::NotADirectory)?, name).ok_or(Errno::NotFound)?;
+        let cluster = record.entry.is_directory().then(|| record.entry.starting_cluster());
+        if self.inodes.lock().in_use(record.short_slot(), cluster) {
+            return Err(Errno::Busy);
+        }
+        if record.entry.is_directory() {
+            volume.delete_directory(path.trim_matches('/'))
+        } else {
+            volume.delete_file(&path)
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        lock(&self.volume).read_at(&self.path, offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+        lock(&self.volume).write_at(&self.path, offset, data)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        lock(&self.volume).truncate(&self.path, size)
+    }
 }
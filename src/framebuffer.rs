@@ -457,16 +457,10 @@ macro_rules! print {
 
         interrupts::without_interrupts(|| {
 			if $crate::SYSTEM_INITIALIZED.load(core::sync::atomic::Ordering::SeqCst) {
-	            let executor = unsafe { &mut *$crate::multitasking::EXECUTOR_PTR };
-
-	            if let Some(task_id) = executor.current_task {
-	                let task = executor.tasks.get(&task_id).unwrap();
-
-	                if let Some(term_id) = task.terminal_id {
-	                    let mut writer = $crate::framebuffer::TerminalWriter { terminal_id: term_id };
-	                    writer.write_fmt(format_args!($($arg)*)).unwrap();
-	                    return;
-	                }
+	            if let Some(term_id) = $crate::thread::current_terminal() {
+	                let mut writer = $crate::framebuffer::TerminalWriter { terminal_id: term_id };
+	                writer.write_fmt(format_args!($($arg)*)).unwrap();
+	                return;
 	            }
 	        }
 
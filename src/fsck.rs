@@ -0,0 +1,274 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fat32::{ DirectoryRecord, FAT32Volume };
+
+// Consistency check of a FAT32 volume, which nothing else may use meanwhile. Every
+// directory is walked from the root and the chain of each entry followed through the
+// first FAT. A chain ends early at a link that is free, bad or out of range and at a
+// cluster another chain already has (a cross link), and the chain of a file after the
+// clusters its size needs. Clusters in use that no entry reaches are lost.
+//
+// Repairing cuts the chains where they went wrong, fits the sizes of files to what is
+// left of their chains, drops directories without clusters, frees lost clusters and
+// copies the first FAT over the others.
+
+const FAT_BAD: u32 = 0x0FFFFFF7;
+const FAT_END: u32 = 0x0FFFFFFF;
+
+pub struct Report {
+	pub directories: usize,
+	pub files: usize,
+	// One line per problem found
+	pub problems: Vec<String>,
+	// Whether the problems were repaired
+	pub repaired: bool,
+}
+
+impl Report {
+	pub fn summary(&self) -> String {
+		let state = match (self.problems.len(), self.repaired) {
+			(0, _) => "clean",
+			(_, true) => "repaired",
+			(_, false) => "not repaired",
+		};
+		format!("{} directories, {} files, {} problems, {}", self.directories, self.files, self.problems.len(), state)
+	}
+}
+
+pub fn check(volume: &mut FAT32Volume, repair: bool) -> Report {
+	let fat = read_fat(volume);
+	let free = fat[2..].iter().filter(|&&entry| entry == 0).count() as u32;
+	let reached = vec![false; fat.len()];
+	let mut checker = Checker {
+		volume,
+		fat,
+		reached,
+		repair,
+		report: Report { directories: 0, files: 0, problems: Vec::new(), repaired: false },
+	};
+
+	if checker.walk() {
+		checker.find_lost();
+		checker.check_free_count(free);
+		checker.compare_fats();
+		checker.report.repaired = repair;
+	}
+	checker.report
+}
+
+// Entries of the FAT, for clusters 0 to the last one of the volume
+fn read_fat(volume: &mut FAT32Volume) -> Vec<u32> {
+	let entries = volume.cluster_count as usize + 2;
+	let mut fat = Vec::with_capacity(entries);
+	for index in 0..entries.div_ceil(128) as u32 {
+		let sector = volume.fat.read_sector(&mut *volume.device, index);
+		for entry in sector.chunks(4) {
+			if fat.len() < entries {
+				fat.push(u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) & 0x0FFFFFFF);
+			}
+		}
+	}
+	fat
+}
+
+struct Checker<'a> {
+	volume: &'a mut FAT32Volume,
+	// The first FAT, with the cuts made so far
+	fat: Vec<u32>,
+	// Clusters that belong to an entry
+	reached: Vec<bool>,
+	repair: bool,
+	report: Report,
+}
+
+impl Checker<'_> {
+	fn problem(&mut self, problem: String) {
+		self.report.problems.push(problem);
+	}
+
+	fn in_range(&self, cluster: u32) -> bool {
+		(2..self.fat.len() as u32).contains(&cluster)
+	}
+
+	fn set_fat(&mut self, cluster: u32, value: u32) {
+		self.fat[cluster as usize] = value;
+		if self.repair {
+			self.volume.set_next_cluster(cluster, value);
+		}
+	}
+
+	// Walks the tree of directories, false when the root directory itself is broken
+	fn walk(&mut self) -> bool {
+		let root = self.volume.root_dir_cluster;
+		if !self.in_range(root) {
+			self.problem(format!("/: the root directory starts at cluster {}, which is not on the volume", root));
+			return false;
+		}
+
+		let mut pending = vec![(String::new(), self.follow("/", root, None))];
+		while let Some((path, clusters)) = pending.pop() {
+			let records = self.volume.records_in(&clusters);
+			for record in records {
+				if record.entry.name[0] == b'.' {		// "." and ".."
+					continue;
+				}
+				let child = format!("{}/{}", path, record.entry.filename());
+				if record.entry.is_directory() {
+					self.report.directories += 1;
+					if let Some(clusters) = self.check_directory(&child, &record) {
+						pending.push((child, clusters));
+					}
+				} else {
+					self.report.files += 1;
+					self.check_file(&child, &record);
+				}
+			}
+		}
+		true
+	}
+
+	// Clusters of the directory to look for entries in, None if it has none left
+	fn check_directory(&mut self, path: &str, record: &DirectoryRecord) -> Option<Vec<u32>> {
+		let first = record.entry.starting_cluster();
+		let clusters = if self.in_range(first) {
+			self.follow(path, first, None)
+		} else {
+			self.problem(format!("{}: the directory starts at cluster {}, which is not on the volume", path, first));
+			Vec::new()
+		};
+
+		if clusters.is_empty() {
+			if self.repair {
+				self.volume.remove_record(record);
+			}
+			return None;
+		}
+		Some(clusters)
+	}
+
+	fn check_file(&mut self, path: &str, record: &DirectoryRecord) {
+		let first = record.entry.starting_cluster();
+		let size = record.entry.file_size;
+		let needed = (size as usize).div_ceil(self.volume.cluster_size);
+		let clusters = if first == 0 {
+			Vec::new()
+		} else if self.in_range(first) {
+			self.follow(path, first, Some(needed))
+		} else {
+			self.problem(format!("{}: the file starts at cluster {}, which is not on the volume", path, first));
+			Vec::new()
+		};
+
+		let new_first = if clusters.is_empty() { 0 } else { first };
+		let mut new_size = size;
+		if clusters.len() < needed {
+			new_size = (clusters.len() * self.volume.cluster_size) as u32;
+			self.problem(format!("{}: the size is {} bytes, but the clusters only hold {}", path, size, new_size));
+		}
+		if self.repair && (new_first != first || new_size != size) {
+			self.volume.update_short_entry(record, |entry| {
+				entry[20..22].copy_from_slice(&((new_first >> 16) as u16).to_le_bytes());		// high
+				entry[26..28].copy_from_slice(&(new_first as u16).to_le_bytes());			// low
+				entry[28..32].copy_from_slice(&new_size.to_le_bytes());
+			});
+		}
+	}
+
+	// Clusters of the chain from `first` that belong to the entry at `path`, at most
+	// `needed` of them. The chain is cut after them if it went on.
+	fn follow(&mut self, path: &str, first: u32, needed: Option<usize>) -> Vec<u32> {
+		let mut clusters: Vec<u32> = Vec::new();
+		let mut cluster = first;
+		loop {
+			if self.reached[cluster as usize] {
+				if clusters.contains(&cluster) {
+					self.problem(format!("{}: the chain loops back to cluster {}", path, cluster));
+				} else {
+					self.problem(format!("{}: cross-linked with another entry at cluster {}", path, cluster));
+				}
+				break;
+			}
+			if needed == Some(clusters.len()) {
+				self.problem(format!("{}: the chain is longer than the {} clusters the size needs", path, clusters.len()));
+				break;
+			}
+
+			self.reached[cluster as usize] = true;
+			clusters.push(cluster);
+			let next = self.fat[cluster as usize];
+			if next >= 0x0FFFFFF8 {
+				return clusters;
+			}
+			if !self.in_range(next) || next == FAT_BAD {
+				self.problem(format!("{}: cluster {} links to {:#x}", path, cluster, next));
+				break;
+			}
+			cluster = next;
+		}
+
+		if let Some(&last) = clusters.last() {
+			self.set_fat(last, FAT_END);
+		}
+		clusters
+	}
+
+	// Clusters marked as used that no entry reached, freed when repairing
+	fn find_lost(&mut self) {
+		let lost: Vec<u32> = (2..self.fat.len() as u32)
+			.filter(|&cluster| !self.reached[cluster as usize] && self.fat[cluster as usize] != 0 && self.fat[cluster as usize] != FAT_BAD)
+			.collect();
+		if lost.is_empty() {
+			return;
+		}
+
+		// a chain starts at a lost cluster no other one links to
+		let mut linked = vec![false; self.fat.len()];
+		for &cluster in lost.iter() {
+			if let Some(next) = linked.get_mut(self.fat[cluster as usize] as usize) {
+				*next = true;
+			}
+		}
+		let chains = lost.iter().filter(|&&cluster| !linked[cluster as usize]).count();
+		self.problem(format!("{} lost clusters in {} chains", lost.len(), chains));
+		for cluster in lost {
+			self.set_fat(cluster, 0);
+		}
+	}
+
+	// FSInfo only holds a hint, but a wrong one has new clusters searched for in vain.
+	// `free` is the count before the repairs.
+	fn check_free_count(&mut self, free: u32) {
+		if let Some(count) = self.volume.free_count.filter(|&count| count != free) {
+			self.problem(format!("FSInfo counts {} free clusters instead of {}", count, free));
+		}
+		if self.repair {
+			self.volume.free_count = Some(self.fat[2..].iter().filter(|&&entry| entry == 0).count() as u32);
+		}
+	}
+
+	// The other FATs should be copies of the first one, which is the one used. A sector
+	// of a copy that cannot be read counts as differing.
+	fn compare_fats(&mut self) {
+		let start = self.volume.fat.fat_start_lba;
+		let size = self.volume.fat_size_sectors;
+		for copy in 1..self.volume.num_fats as u32 {
+			let mut differing = 0;
+			for sector in 0..size {
+				let first = self.volume.fat.read_sector(&mut *self.volume.device, sector);
+				let mut other = [0u8; 512];
+				let readable = self.volume.device.try_read_sector(start + copy * size + sector, &mut other).is_ok();
+				if !readable || first != other {
+					differing += 1;
+					if self.repair {
+						self.volume.device.write_sector(start + copy * size + sector, &first);
+					}
+				}
+			}
+			if differing > 0 {
+				self.problem(format!("FAT {} differs from the first one in {} sectors", copy + 1, differing));
+			}
+		}
+	}
+}
@@ -6,15 +6,38 @@ use lazy_static::lazy_static;
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 2;
 
+// privilege_stack_table[0] is rewritten whenever another program starts running,
+// so the TSS can not live in an immutable lazy_static
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+// Data the SYSCALL entry reaches through the GS base after swapgs, as it starts
+// on the user stack and has no other way to find the kernel one. The entry code
+// uses the field offsets, so the layout must not change.
+#[repr(C)]
+pub struct PerCpu {
+	pub kernel_stack: u64,			// 0: same as the rsp0 of the TSS
+	pub user_stack: u64,			// 8: scratch slot for the user rsp
+	pub user_code_selector: u64,	// 16
+	pub user_data_selector: u64,	// 24
+}
+
+static mut PER_CPU: PerCpu = PerCpu {
+	kernel_stack: 0,
+	user_stack: 0,
+	user_code_selector: 0,
+	user_data_selector: 0,
+};
+
 lazy_static! {
+	// The order of the segments is the one SYSCALL/SYSRET expects:
+	// kernel code, kernel data, user data, user code
 	pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
 		let mut gdt = GlobalDescriptorTable::new();
 		let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-		let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
 		let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-
-		let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
 		let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+		let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+		let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*(&raw const TSS) }));
 		
 		(gdt, Selectors { code_selector, tss_selector, data_selector, user_code_selector,  user_data_selector})
 	};
@@ -30,49 +53,67 @@ pub struct Selectors {
 
 pub fn init() {
 	use x86_64::instructions::tables::load_tss;
-	use x86_64::instructions::segmentation::{CS, DS, Segment};
+	use x86_64::instructions::segmentation::{CS, DS, SS, Segment};
 
+	init_tss();
 	GDT.0.load();
+	init_per_cpu();
 	unsafe {
 		CS::set_reg(GDT.1.code_selector);
 		DS::set_reg(GDT.1.data_selector);
+		SS::set_reg(GDT.1.data_selector);
 		load_tss(GDT.1.tss_selector);
 	}
 }
 
+// Stack the CPU switches to when an interrupt or a syscall arrives from ring 3
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+	unsafe {
+		(*(&raw mut TSS)).privilege_stack_table[0] = stack_top;
+		(*(&raw mut PER_CPU)).kernel_stack = stack_top.as_u64();
+	}
+}
 
-lazy_static! {
-	static ref TSS: TaskStateSegment = {
-		let mut tss = TaskStateSegment::new();
-		tss.privilege_stack_table[0] = {
-			const STACK_SIZE: usize = 4096 * 5;
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-			let stack_start = VirtAddr::from_ptr(&raw const STACK);
-			let stack_end = stack_start + STACK_SIZE;
-			stack_end	
-		};
-		
-		tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-			const STACK_SIZE: usize = 4096 * 5;
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+pub fn per_cpu_address() -> VirtAddr {
+	VirtAddr::from_ptr(&raw const PER_CPU)
+}
 
-			let stack_start = VirtAddr::from_ptr(&raw const STACK);
-			let stack_end = stack_start + STACK_SIZE;
-			stack_end
-		};
+fn init_per_cpu() {
+	let per_cpu = unsafe { &mut *(&raw mut PER_CPU) };
+	per_cpu.kernel_stack = unsafe { (*(&raw const TSS)).privilege_stack_table[0].as_u64() };
+	per_cpu.user_code_selector = GDT.1.user_code_selector.0 as u64;
+	per_cpu.user_data_selector = GDT.1.user_data_selector.0 as u64;
+}
 
-		tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = {
-			const STACK_SIZE: usize = 4096 * 5;
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+fn init_tss() {
+	let tss = unsafe { &mut *(&raw mut TSS) };
 
-			let stack_start = VirtAddr::from_ptr(&raw const STACK);
-			let stack_end = stack_start + STACK_SIZE;
-			stack_end
-		};
+	tss.privilege_stack_table[0] = {
+		const STACK_SIZE: usize = 4096 * 5;
+		static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-		tss.iomap_base = 0xFFFFu16;
-		
-		tss
+		let stack_start = VirtAddr::from_ptr(&raw const STACK);
+		let stack_end = stack_start + STACK_SIZE;
+		stack_end	
+	};
+	
+	tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+		const STACK_SIZE: usize = 4096 * 5;
+		static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+		let stack_start = VirtAddr::from_ptr(&raw const STACK);
+		let stack_end = stack_start + STACK_SIZE;
+		stack_end
 	};
+
+	tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = {
+		const STACK_SIZE: usize = 4096 * 5;
+		static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+		let stack_start = VirtAddr::from_ptr(&raw const STACK);
+		let stack_end = stack_start + STACK_SIZE;
+		stack_end
+	};
+
+	tss.iomap_base = 0xFFFFu16;
 }
@@ -2,21 +2,18 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, Pag
 use core::sync::atomic::{ Ordering };
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use alloc::vec::Vec;
 use spin::Mutex;
 
-use alloc::boxed::Box;
-
 use crate::println;
-use crate::print;
 use crate::gdt;
+use crate::cpu;
 use crate::keyboard;
-use crate::fat32;
-use crate::multitasking;
-use crate::memory;
-use crate::framebuffer;
 use crate::mouse;
 use crate::time;
+use crate::process;
+use crate::thread;
+use crate::syscall;
+use abi::SyscallArgs;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -26,6 +23,13 @@ pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1
 #[repr(align(8), C)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Registers {
+    // Saved callee-saved registers
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rbp: usize,
+    pub rbx: usize,
     // Saved scratch registers
     pub r11: usize,
     pub r10: usize,
@@ -61,6 +65,8 @@ pub fn init() {
 	println!("Interrupt descriptor table is set.");
 	gdt::init();
 	println!("Global descriptor table is initialized");
+	unsafe { cpu::init_syscall(syscall_entry as u64); }
+	println!("SYSCALL entry is set.");
 	unsafe { PICS.lock().initialize(); }
 	println!("Programmable interrupt controller is initialized.");
 	x86_64::instructions::interrupts::enable();
@@ -68,13 +74,15 @@ pub fn init() {
 }
 
 lazy_static! {
-	static ref IRQ_HANDLERS: Mutex<[fn(); 16]> = {
-		Mutex::new([default_handler; 16])
+	static ref IRQ_HANDLERS: Mutex<[[Option<fn()>; MAX_SHARED_HANDLERS]; 16]> = {
+		Mutex::new([[None; MAX_SHARED_HANDLERS]; 16])
 	};
 	
 	static ref IDT: InterruptDescriptorTable = {
 		let mut idt = InterruptDescriptorTable::new();
 		idt.breakpoint.set_handler_fn(breakpoint_handler);
+		idt.divide_error.set_handler_fn(divide_error_handler);
+		idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
 		idt.page_fault.set_handler_fn(page_fault_handler);
 		
 		idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
@@ -83,23 +91,59 @@ lazy_static! {
 			idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
 			idt.general_protection_fault.set_handler_fn(general_protection_fault_handler).set_stack_index(gdt::GENERAL_PROTECTION_FAULT_IST_INDEX);
 		}
-		idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
 		idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
 
 		idt[(PIC_1_OFFSET + 12) as usize].set_handler_fn(mouse_handler);
+		// the lines PCI devices are usually routed to
+		idt[(PIC_1_OFFSET + 9) as usize].set_handler_fn(irq9_handler);
+		idt[(PIC_1_OFFSET + 10) as usize].set_handler_fn(irq10_handler);
+		idt[(PIC_1_OFFSET + 11) as usize].set_handler_fn(irq11_handler);
 		idt[(PIC_1_OFFSET + 14) as usize].set_handler_fn(irq14_handler);
+		idt[(PIC_1_OFFSET + 15) as usize].set_handler_fn(irq15_handler);
 		
 		//idt[0x80].set_handler_fn(syscall_interrupt_handler);
 		unsafe {
+			// programs use SYSCALL, int 0x80 is kept for compatibility
 			let f = wrapped_syscall_handler as *mut fn();
 			idt[0x80].set_handler_fn(core::mem::transmute(f)).set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+
+			// these two may return into another thread
+			let f = wrapped_timer_interrupt_handler as *mut fn();
+			idt[InterruptIndex::Timer.as_usize()].set_handler_fn(core::mem::transmute(f));
+			let f = wrapped_reschedule_handler as *mut fn();
+			idt[thread::RESCHEDULE_VECTOR as usize].set_handler_fn(core::mem::transmute(f));
 		}
 		
 		idt
 	};		
 }
 
-fn default_handler() {}
+// Handlers one line can have, PCI devices share lines
+const MAX_SHARED_HANDLERS: usize = 4;
+
+// Runs the handler on every interrupt of the line, which is unmasked on the PICs. Only
+// lines with a stub in the IDT (`irq_handler!`) reach it. A line can have several
+// handlers, each of them checks whether its device interrupted.
+pub fn set_irq_handler(irq: u8, handler: fn()) {
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut handlers = IRQ_HANDLERS.lock();
+		match handlers[irq as usize].iter_mut().find(|slot| slot.is_none()) {
+			Some(slot) => *slot = Some(handler),
+			None => panic!("IRQ {} has too many handlers", irq),
+		}
+		let mut pics = PICS.lock();
+		unsafe {
+			let [mut mask1, mut mask2] = pics.read_masks();
+			if irq < 8 {
+				mask1 &= !(1 << irq);
+			} else {
+				mask1 &= !(1 << 2);		// cascade from the second PIC
+				mask2 &= !(1 << (irq - 8));
+			}
+			pics.write_masks(mask1, mask2);
+		}
+	});
+}
 
 static mut MOUSE_PACKET: [u8; 3] = [0; 3];
 static mut MOUSE_PHASE: usize = 0;
@@ -145,7 +189,7 @@ pub extern "x86-interrupt" fn mouse_handler(_stack_frame: InterruptStackFrame) {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "sysv64" fn timer_interrupt_handler(rsp: usize) -> usize {
     let ticks = time::TICKS.fetch_add(1, Ordering::Relaxed) + 1;
 
 	unsafe {
@@ -158,6 +202,12 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 		}
 		PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
 	}
+
+	thread::schedule(rsp)
+}
+
+extern "sysv64" fn reschedule_handler(rsp: usize) -> usize {
+	thread::schedule(rsp)
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -172,7 +222,33 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 	}
 }
 
+// Faults in ring 3 only end the program that caused them. Returns if the fault is the kernel's.
+fn kill_faulting_program(fault: &str, address: Option<u64>, stack_frame: &InterruptStackFrame) {
+	if stack_frame.code_segment & 3 != 3 {
+		return;
+	}
+
+	let name = process::current_name().unwrap_or_default();
+	let rip = stack_frame.instruction_pointer.as_u64();
+	match address {
+		Some(address) => println!("{}: {} at {:#x} (rip {:#x}), killed", name, fault, address, rip),
+		None => println!("{}: {} (rip {:#x}), killed", name, fault, rip),
+	}
+	process::exit(process::KILLED_STATUS);
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+	kill_faulting_program("divide error", None, &stack_frame);
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+	kill_faulting_program("invalid opcode", None, &stack_frame);
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
+	kill_faulting_program("general protection fault", None, &stack_frame);
     panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
 }
 
@@ -182,14 +258,17 @@ extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame,
 
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
     let addr = x86_64::registers::control::Cr2::read();
+    kill_faulting_program("page fault", Some(addr.as_u64()), &stack_frame);
     panic!("Page Fault at {:#x}, error: {:?}\n{:#?}", addr, error_code, stack_frame);
 }
 
 extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+	kill_faulting_program("stack segment fault", None, &stack_frame);
     panic!("EXCEPTION: STACK SEGMENT FAULT\nStack Frame: {:#?}\nError: {:?}", stack_frame, error_code);
 }
 
 extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+	kill_faulting_program("segment not present", None, &stack_frame);
     panic!("EXCEPTION: SEGMENT NOT PRESENT\nStack Frame: {:#?}\nError: {:?}", stack_frame, error_code);
 }
 
@@ -201,7 +280,9 @@ macro_rules! irq_handler {
     ($handler:ident, $irq:expr) => {
         pub extern "x86-interrupt" fn $handler(_: InterruptStackFrame) {
             let handlers = IRQ_HANDLERS.lock();
-            handlers[$irq]();
+            for handler in handlers[$irq].iter().flatten() {
+                handler();
+            }
             unsafe {
                 PICS.lock().notify_end_of_interrupt(
                     (PIC_1_OFFSET + $irq) as u8
@@ -225,10 +306,69 @@ macro_rules! wrap {
                 "push r9",
                 "push r10",
                 "push r11",
+                "push rbx",
+                "push rbp",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
                 "mov rsi, rsp", // Arg #2: register list
                 "mov rdi, rsp", // Arg #1: interupt frame
-                "add rdi, 9 * 8", // 9 registers * 8 bytes
+                "add rdi, 15 * 8", // 15 registers * 8 bytes
+                "call {}",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop rbp",
+                "pop rbx",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rax",
+                "iretq",
+                sym $fn
+            );
+        }
+    };
+}
+
+// Same register layout as `wrap!`, but the handler gets the stack pointer and
+// returns the one to continue with, which is how threads are switched
+macro_rules! wrap_switch {
+    ($fn: ident => $w:ident) => {
+        #[naked]
+        pub unsafe extern "sysv64" fn $w() {
+           core::arch:: naked_asm!(
+                "push rax",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push rbx",
+                "push rbp",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov rdi, rsp", // Arg #1: saved context
                 "call {}",
+                "mov rsp, rax", // context of the thread to resume
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop rbp",
+                "pop rbx",
                 "pop r11",
                 "pop r10",
                 "pop r9",
@@ -245,246 +385,82 @@ macro_rules! wrap {
     };
 }
 
+// Entry of the SYSCALL instruction. The CPU leaves the user rip in rcx and the
+// rflags in r11 and stays on the user stack, so the kernel stack is taken from
+// the per-CPU data first. The frame built on it looks like the one of `int 0x80`,
+// so both paths share `syscall_handler` and the threads see one layout.
+#[naked]
+pub unsafe extern "sysv64" fn syscall_entry() {
+    core::arch::naked_asm!(
+        "swapgs",
+        "mov gs:[8], rsp",          // user rsp
+        "mov rsp, gs:[0]",          // kernel stack of the thread
+        "push qword ptr gs:[24]",   // ss
+        "push qword ptr gs:[8]",    // rsp
+        "push r11",                 // rflags
+        "push qword ptr gs:[16]",   // cs
+        "push rcx",                 // rip
+        "swapgs",
+        "push rax",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rsi, rsp", // Arg #2: register list
+        "mov rdi, rsp", // Arg #1: interupt frame
+        "add rdi, 15 * 8", // 15 registers * 8 bytes
+        "call {}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rax",
+        // SYSRET faults in ring 0 on a non-canonical rip, leave through iretq then
+        "mov rcx, [rsp]",
+        "mov r11, rcx",
+        "shr r11, 47",
+        "jnz 2f",
+        "mov r11, [rsp + 16]",
+        "mov rsp, [rsp + 24]",
+        "sysretq",
+        "2:",
+        "iretq",
+        sym syscall_handler
+    );
+}
+
 wrap!(syscall_handler => wrapped_syscall_handler);
+wrap_switch!(timer_interrupt_handler => wrapped_timer_interrupt_handler);
+wrap_switch!(reschedule_handler => wrapped_reschedule_handler);
 
 extern "sysv64" fn syscall_handler(_stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
     let n = regs.rax as u64; 
     // The registers order follow the System V ABI convention
-    let arg1 = regs.rdi as u64;
-    let arg2 = regs.rsi as u64;
-    let arg3 = regs.rdx as u64;
-    let arg4 = regs.r8 as u64;
+    let args = SyscallArgs::new(regs.rdi as u64, regs.rsi as u64, regs.rdx as u64, regs.r8 as u64);
 
-	if n != 3 && n != 1 {
-    	//println!("SYSCALL n={} arg1={:#x} arg2={} arg3={:#x}", n, arg1, arg2, arg3);
-    }
-    
-    let res = _syscall_handler(n, arg1, arg2, arg3, arg4) as usize;
+    let res = syscall::dispatch(n, &args) as usize;
 
     regs.rax = res;
-
-    unsafe { PICS.lock().notify_end_of_interrupt(0x80) };
-}
-
-#[unsafe(no_mangle)]
-pub fn _syscall_handler(number: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> u64  {
-	let mut ret;
-	match number {
-		1 => { // SYS_WRITE
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				print!("{}", text);
-			}
-			ret = 0;
-		}
-		2 => { // SYS_EXIT
-		    println!("Program requested exit via syscall.");
-		    unsafe {
-		        let addr = fat32::RETURN_ADDR;
-		        core::arch::asm!("jmp {}", in(reg) addr, options(noreturn));
-		        ret = 0;
-		    }
-		}
-		3 => { // SYS_READ
-			let user_buffer = unsafe { &mut *(arg1 as *mut Vec<char>) };
-			let data: Vec<char> = keyboard::INPUT_BUFFER.lock().iter().cloned().collect();
-			keyboard::INPUT_BUFFER.lock().clear();
-			for i in data {
-				user_buffer.push(i);
-			}
-			ret = 0;		    
-		}
-		4 => { // SYS_RM_CHAR
-			if crate::SYSTEM_INITIALIZED.load(core::sync::atomic::Ordering::SeqCst) {
-				let executor = unsafe { &mut *multitasking::EXECUTOR_PTR };
-
-				if let Some(task_id) = executor.current_task {
-				    let task = executor.tasks.get(&task_id).unwrap();
-
-				    if let Some(term_id) = task.terminal_id {
-				        let mut writer = framebuffer::TerminalWriter { terminal_id: term_id };
-				        writer.rm_char();
-				    }
-				}	
-			}
-			else {
-				framebuffer::FB_WRITER.lock().rm_char();
-			}
-			ret = 0;
-		}
-		5 => { // SYS_SPAWN
-    		unsafe {
-		        let raw_ptr = arg1 as *mut multitasking::Task;
-		        let boxed = Box::from_raw(raw_ptr);
-		        multitasking::EXECUTOR_PTR.as_mut().unwrap().spawn(*boxed);
-		        //multitasking::EXECUTOR_PTR.as_mut().unwrap().run();
-		    }
-		    ret = 0;
-		}		
-		6 => { // SYS_ALLOC
-				use alloc::alloc::{ GlobalAlloc, Layout };
-				let layout = Layout::from_size_align(arg1 as usize, arg2 as usize).unwrap();
-			  	let ptr = unsafe {
-			        memory::ALLOCATOR.alloc(layout)
-			    };
-			    //println!("ALLOC returns: 0x{:x}", ptr as u64);
-			    ret = ptr as u64;
-		}
-		7 => { // SYS_DEALLOC
-				use alloc::alloc::{ GlobalAlloc, Layout };
-				let layout = Layout::from_size_align(arg2 as usize, arg3 as usize).unwrap();
-				unsafe {
-			    	memory::ALLOCATOR.dealloc(arg1 as *mut u8, layout);
-			    }
-			    ret = 0;
-		}
-		8 => { // SYS_CHECK_DIR_EXISTS
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			ret = 0;
-			if let Ok(text) = core::str::from_utf8(s) {
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				if fs.find_path(text).is_some() {
-					ret = 1;
-				}
-			}
-		}	
-		9 => { // SYS_GEN_TASK_ID
-			ret = multitasking::TaskId::new().0;
-		}
-		10 => { // SYS_LIST_DIR
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				let data = fs.list_dir(text).unwrap();
-				let user_buffer = unsafe { &mut *(arg3 as *mut Vec<alloc::string::String>) };
-				for i in data {
-					user_buffer.push(i);
-				}
-			}
-			ret = 0;
-		}	
-		11 => { // SYS_MKDIR
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				fs.create_directory(text);
-			}
-			ret = 0;
-		}
-		12 => { // SYS_MKFILE
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				fs.create_file(text, 0);
-			}
-			ret = 0;
-		}
-		13 => { // SYS_WRITE_FILE
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				let content_ptr = arg3 as *const u8;
-				let content_len = arg4 as usize;
-				let data = unsafe { core::slice::from_raw_parts(content_ptr, content_len) };
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				fs.write_file(text, data);
-			}
-			ret = 0;
-		},
-		14 => { // SYS_READ_FILE
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				let user_ptr = arg3 as *mut u8;
-				let user_len = arg4 as usize;
-				let bytes = fs.read_file(text).unwrap();
-				let copy_len = core::cmp::min(user_len, bytes.len());
-				unsafe {
-			    	core::ptr::copy_nonoverlapping(bytes.as_ptr(), user_ptr, copy_len);
-				}
-				ret = copy_len as u64;
-			}
-			else {
-				ret = 0;
-			}
-		},
-		15 => { // SYS_MKFILE
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				fs.delete_directory(text);
-			}
-			ret = 0;
-		}
-		16 => { // SYS_MKFILE
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				fs.delete_file(text);
-			}
-			ret = 0;
-		}	
-		17 => { // SYS_RUN
-			let ptr = arg1 as *const u8;
-			let len = arg2 as usize;
-			let s = unsafe { core::slice::from_raw_parts(ptr, len) };
-			if let Ok(text) = core::str::from_utf8(s) {
-				let fs = unsafe { &mut *fat32::FS_PTR };
-				let data = fs.read_file(text).unwrap();
-				unsafe { multitasking::EXECUTOR_PTR.as_mut().unwrap().spawn(multitasking::Task::new(run_programm(data), Some(5))) };
-			}
-			ret = 0;
-		}
-		18 => { // SYS_CLEAR
-			if crate::SYSTEM_INITIALIZED.load(core::sync::atomic::Ordering::SeqCst) {
-				let executor = unsafe { &mut *multitasking::EXECUTOR_PTR };
-
-				if let Some(task_id) = executor.current_task {
-				    let task = executor.tasks.get(&task_id).unwrap();
-
-				    if let Some(term_id) = task.terminal_id {
-				        let mut writer = framebuffer::TerminalWriter { terminal_id: term_id };
-				        writer.clear();
-				    }
-				}
-			}
-			else {
-				framebuffer::FB_WRITER.lock().clear();	
-			}
-			ret = 0;
-		}	
-		_ => {
-			println!("Unknown syscall: {}", number);
-			ret = -1i64 as u64;
-		}
-	}
-
-	unsafe {
-		core::arch::asm!("mov rax, {0}", in(reg) ret, options(nostack, preserves_flags));
-	}
-
-	ret
-}
-
-async fn run_programm(data: Vec<u8>) {
-	fat32::load_elf_and_jump(&data);
 }
 
 #[unsafe(no_mangle)]
@@ -492,4 +468,8 @@ pub extern "C" fn print_cs_ss(cs: u64, ss: u64) {
     println!(">>> CS: {:#x}, SS: {:#x}", cs, ss);
 }
 
+irq_handler!(irq9_handler, 9);
+irq_handler!(irq10_handler, 10);
+irq_handler!(irq11_handler, 11);
 irq_handler!(irq14_handler, 14);
+irq_handler!(irq15_handler, 15);
@@ -7,7 +7,9 @@
 
 use core::panic::PanicInfo;
 use alloc::boxed::Box; 
+use alloc::string::String;
 use alloc::vec::Vec;
+use alloc::sync::Arc;
 use core::sync::atomic::AtomicBool;
 
 mod vga_buffer;
@@ -20,7 +22,16 @@ mod time;
 mod std;
 mod multitasking;
 mod keyboard;
+mod ata;
+mod ahci;
+mod virtio;
+mod virtio_blk;
 mod fat32;
+mod fsck;
+mod tmpfs;
+mod block_cache;
+mod disk;
+mod partition;
 mod framebuffer;
 mod mouse;
 mod fonts;
@@ -28,6 +39,11 @@ mod gui;
 mod tdg;
 mod pci;
 mod network;
+mod process;
+mod thread;
+mod uaccess;
+mod syscall;
+mod vfs;
 
 #[macro_use]
 extern crate bitflags;
@@ -69,10 +85,34 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
 	let executor = Box::new(multitasking::Executor::new());
 	framebuffer.draw_frame();
 	
-	let ata = fat32::AtaDevice::new();
-	let boxed_ata = Box::new(ata);
+	// the first disk holds the system, either as the whole disk or as its first FAT32
+	// partition, the others can be mounted later. Virtio disks come first, then IDE
+	// and SATA ones.
+	let mut first_disk: Option<(String, Box<dyn fat32::AsyncBlockDevice>)> = None;
+	for drive in virtio_blk::detect() {
+		first_disk.get_or_insert_with(|| (drive.device.name(), Box::new(drive.device)));
+		disk::register(drive.device.name(), drive.model, drive.sectors, Box::new(drive.device));
+	}
+	for drive in ata::detect(ata::TransferMode::Dma) {
+		first_disk.get_or_insert_with(|| (drive.device.name(), Box::new(drive.device)));
+		disk::register(drive.device.name(), drive.model, drive.sectors, Box::new(drive.device));
+	}
+	for drive in ahci::detect() {
+		first_disk.get_or_insert_with(|| (drive.device.name(), Box::new(drive.device)));
+		disk::register(drive.device.name(), drive.model, drive.sectors, Box::new(drive.device));
+	}
+	for disk in disk::disks() {
+		println!("{}: {} ({} MiB){}", disk.name, disk.model, disk.sectors / 2048, if disk.fat32 { ", FAT32" } else { "" });
+	}
 	framebuffer.draw_frame();
-	let mut fs = fat32::mount_fat32(boxed_ata).unwrap();
+	let (first_name, first_device) = first_disk.expect("No disk found");
+	let system = disk::find_fat32(&first_name).expect("No FAT32 volume on the first disk");
+	let fs = disk::mount(&system.name, "/").unwrap();
+	if !vfs::exists("/tmp") {
+		vfs::create_dir("/tmp").unwrap();
+	}
+	vfs::mount("/tmp", Arc::new(tmpfs::TmpFs::new())).unwrap();
+	let system_device = partition::Partition::new(first_device, system.start, system.sectors.min(u32::MAX as u64) as u32);
 	framebuffer.draw_frame();
 
 	framebuffer::draw_background();
@@ -82,7 +122,7 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
 	let mut gui = gui::GuiSystem::new(framebuffer.width as isize, framebuffer.height as isize);
 	unsafe { gui::GUI_PTR = &mut gui as *mut gui::GuiSystem }
 
-	let ip_bytes = fs.read_file("ip.txt").unwrap();
+	let ip_bytes = vfs::read_file("/ip.txt").unwrap();
 	let ip_str = core::str::from_utf8(&ip_bytes[..ip_bytes.len() as usize]).unwrap_or("[invalid utf8]");
 	let mut net_driver;
 	if let Some(ip) = network::parse_ip(ip_str) {
@@ -94,7 +134,6 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
 	
 	unsafe {
 	    multitasking::EXECUTOR_PTR = Box::into_raw(executor);
-	    fat32::FS_PTR = &mut fs as *mut fat32::FAT32Volume;
 		(*multitasking::EXECUTOR_PTR).spawn(multitasking::Task::new(framebuffer::gui_loop(), None));
 
 		let gui = &mut *gui::GUI_PTR;
@@ -114,7 +153,7 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
 		gui.create_taskbar();
 
 		//(*multitasking::EXECUTOR_PTR).spawn(multitasking::Task::new(draw_window(), None));
-	    (*multitasking::EXECUTOR_PTR).spawn(multitasking::Task::new(start_shell(), Some(term)));
+	    (*multitasking::EXECUTOR_PTR).spawn(multitasking::Task::new(start_shell(fs, system_device, term), Some(term)));
 	    (*multitasking::EXECUTOR_PTR).spawn(multitasking::Task::new(network_task(&mut *network::NIC_PTR), Some(term)));
 	    if ip_str == "10.0.0.1\n" {
 	    	(*multitasking::EXECUTOR_PTR).spawn(multitasking::Task::new(ping_task(&mut *network::NIC_PTR), Some(term)));	
@@ -124,10 +163,10 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
 	}
 }
 
-async fn start_shell() {
-	let fs = unsafe { &mut *fat32::FS_PTR };
-	let data = fs.read_file("/SOMNIA").unwrap();	
-	fat32::load_elf_and_jump(&data);
+// Loaded from a task, so the GUI keeps running while the disk reads the shell
+async fn start_shell(fs: Arc<fat32::Fat32FileSystem>, device: partition::Partition<Box<dyn fat32::AsyncBlockDevice>>, term: gui::NodeId) {
+	let data = fs.read_file_async(&device, "/SOMNIA").await.unwrap();
+	process::spawn(&data, "/SOMNIA", b"/SOMNIA\0", b"", Some(term)).unwrap();
 }
 
 async fn network_task(nic: &mut network::E1000) {
@@ -155,6 +194,18 @@ async fn draw_window() {
 	gui.create_window("My window", 450, 50, 200, 150);
 }
 
+// Writes back everything the file systems still hold in memory and powers off,
+// halting instead where that is not supported
+pub fn shutdown() -> ! {
+	cpu::disable_interrupts();
+	if let Err(e) = vfs::unmount_all() {
+		println!("unmount: {}", e);
+	}
+	println!("Powering off");
+	cpu::power_off();
+	hlt_loop();
+}
+
 pub fn hlt_loop() -> ! {
 	loop {
 		cpu::hlt();
@@ -5,6 +5,8 @@ use core::ptr::{ Unique, self,  null_mut };
 use core::cell::UnsafeCell;
 use core::mem;
 use alloc::alloc::{ GlobalAlloc, Layout };
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use crate::cpu::{ cr3, write_raw_cr3 }; 
 use crate::println;
 
@@ -15,6 +17,13 @@ pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100000 * 1024;
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+// User programs live in their own P4 entry, every other entry is shared with the kernel
+pub const USER_SPACE_START: usize = 0x_4000_0000_0000;
+pub const USER_SPACE_END: usize = 0x_4080_0000_0000;
+const USER_P4_INDEX: usize = 128;
+
+pub static mut MEMORY_CONTROLLER: Option<MemoryController> = None;
+
 #[global_allocator]
 pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
@@ -87,8 +96,16 @@ pub fn init(multiboot_information_address: usize) {
 	let multiboot_start = multiboot_information_address;
 	let multiboot_end = multiboot_start + (boot_info.total_size());
 	
-	let mut frame_allocator = AreaFrameAllocator::new(kernel_start as usize, kernel_end as usize, multiboot_start, multiboot_end, memory_map_tag.memory_areas());
-	let mut active_table = remap_kernel(&mut frame_allocator, &boot_info);
+	// the multiboot structure stays identity mapped for the whole lifetime of the kernel
+	let memory_areas: &'static [MemoryArea] = unsafe { &*(memory_map_tag.memory_areas() as *const [MemoryArea]) };
+	let mut frame_allocator = AreaFrameAllocator::new(kernel_start as usize, kernel_end as usize, multiboot_start, multiboot_end, memory_areas);
+	let mut temporary_page = TemporaryPage::new(Page { number: 0x123456789 }, &mut frame_allocator);
+	let mut active_table = remap_kernel(&mut frame_allocator, &mut temporary_page, &boot_info);
+
+	// create the page tables of the temporary page in the kernel table before any
+	// address space copies its entries, so all of them share the same tables
+	temporary_page.map(Frame::containing_address(0), &mut active_table);
+	temporary_page.unmap(&mut active_table);
 	
 	let heap_start_page = Page::containing_address(HEAP_START);
 	let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
@@ -100,10 +117,44 @@ pub fn init(multiboot_information_address: usize) {
 	unsafe {
 		ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
 	}
+
+	unsafe {
+		MEMORY_CONTROLLER = Some(MemoryController {
+			kernel_p4: cr3() as PhysicalAddress,
+			active_table,
+			frame_allocator,
+			temporary_page,
+		});
+	}
 		
 	//println!("Memory initialization    [OK]");
 }
 
+pub struct MemoryController {
+	pub kernel_p4: PhysicalAddress,
+	pub active_table: ActivePageTable,
+	pub frame_allocator: AreaFrameAllocator<'static>,
+	pub temporary_page: TemporaryPage,
+}
+
+#[allow(static_mut_refs)]
+pub fn controller() -> &'static mut MemoryController {
+	unsafe { MEMORY_CONTROLLER.as_mut().expect("memory is not initialized") }
+}
+
+// Identity maps the registers of a device, uncached. Pages that are mapped already are
+// left as they are.
+pub fn map_mmio(address: PhysicalAddress, size: usize) {
+	let controller = controller();
+	let start_frame = Frame::containing_address(address);
+	let end_frame = Frame::containing_address(address + size - 1);
+	for frame in Frame::range_inclusive(start_frame, end_frame) {
+		if controller.active_table.translate(frame.start_address()).is_none() {
+			controller.active_table.identity_map(frame, WRITABLE | WRITE_THROUGH | NO_CACHE, &mut controller.frame_allocator);
+		}
+	}
+}
+
 //******* Frame Allocator *******\\
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -158,6 +209,7 @@ pub trait FrameAllocator {
 
 pub struct AreaFrameAllocator<'a> {
 	next_free_frame: Frame,
+	free_frames: Vec<Frame>,
 	current_area: Option<&'a MemoryArea>,
 	areas: &'a [MemoryArea],
 	kernel_start: Frame,
@@ -168,6 +220,10 @@ pub struct AreaFrameAllocator<'a> {
 
 impl<'a> FrameAllocator for AreaFrameAllocator<'a> {
 	fn allocate_frame(&mut self) -> Option<Frame> {
+		if let Some(frame) = self.free_frames.pop() {
+			return Some(frame);
+		}
+
 		if let Some(area) = self.current_area {
 			let frame = Frame { number: self.next_free_frame.number };
 			
@@ -199,7 +255,7 @@ impl<'a> FrameAllocator for AreaFrameAllocator<'a> {
 	}
 
 	fn deallocate_frame(&mut self, frame: Frame) {
-		unimplemented!()
+		self.free_frames.push(frame);
 	}
 }
 
@@ -207,6 +263,7 @@ impl<'a> AreaFrameAllocator<'a> {
 	pub fn new(kernel_start: usize, kernel_end: usize, multiboot_start: usize, multiboot_end: usize, memory_areas: &'a[MemoryArea]) -> AreaFrameAllocator<'a> {
 		let mut allocator = AreaFrameAllocator {
 			next_free_frame: Frame::containing_address(0),
+			free_frames: Vec::new(),
 			current_area: None,
 			areas: memory_areas,
 			kernel_start: Frame::containing_address(kernel_start),
@@ -370,12 +427,21 @@ impl<L> Table<L> where L: HeirarchicalLevel {
 	}
 
 	pub fn next_table_create<A>(&mut self, index: usize, allocator: &mut A) -> &mut Table<L::NextLevel> where A: FrameAllocator {
+		self.next_table_create_flags(index, EntryFlags::empty(), allocator)
+	}
+
+	// same as next_table_create, but makes sure the entry also has `flags` (e.g. USER_ACCESSIBLE)
+	pub fn next_table_create_flags<A>(&mut self, index: usize, flags: EntryFlags, allocator: &mut A) -> &mut Table<L::NextLevel> where A: FrameAllocator {
 		if self.next_table(index).is_none() {
 			assert!(!self.entries[index].flags().contains(HUGE_PAGE), "huge pages are not supported");
 			let frame = allocator.allocate_frame().expect("no frames available");
-			self.entries[index].set(frame, PRESENT | WRITABLE);
+			self.entries[index].set(frame, PRESENT | WRITABLE | flags);
 			self.next_table_mut(index).unwrap().zero();
-		}		
+		} else if !self.entries[index].flags().contains(flags) {
+			let frame = self.entries[index].pointed_frame().unwrap();
+			let new_flags = self.entries[index].flags() | flags;
+			self.entries[index].set(frame, new_flags);
+		}
 		self.next_table_mut(index).unwrap()
 	}
 }
@@ -445,6 +511,16 @@ impl Mapper {
 		p1[page.p1_index()].set(frame, flags | PRESENT);
 	}
 
+	// maps (or remaps) a page of a user address space, the intermediate tables get USER_ACCESSIBLE too
+	pub fn map_user_page<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
+		let p4 = self.p4_mut();
+		let p3 = p4.next_table_create_flags(page.p4_index(), USER_ACCESSIBLE, allocator);
+		let p2 = p3.next_table_create_flags(page.p3_index(), USER_ACCESSIBLE, allocator);
+		let p1 = p2.next_table_create_flags(page.p2_index(), USER_ACCESSIBLE, allocator);
+
+		p1[page.p1_index()].set(frame, flags | PRESENT | USER_ACCESSIBLE);
+	}
+
 	pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
 		let frame = allocator.allocate_frame().expect("out of memory");
 		self.map_to(page, frame, flags, allocator)
@@ -635,16 +711,15 @@ impl ActivePageTable {
 	}
 }
 
-pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable where A: FrameAllocator {
+pub fn remap_kernel<A>(allocator: &mut A, temporary_page: &mut TemporaryPage, boot_info: &BootInformation) -> ActivePageTable where A: FrameAllocator {
 	//println!("Remapping kernel.");
-	let mut temporary_page = TemporaryPage::new(Page { number: 0x123456789 }, allocator);
 	let mut active_table = unsafe { ActivePageTable::new() };
 	let mut new_table = {
 		let frame = allocator.allocate_frame().expect("no more frames");
-		InactivePageTable::new(frame, &mut active_table, &mut temporary_page)	
+		InactivePageTable::new(frame, &mut active_table, temporary_page)	
 	};
 
-	active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+	active_table.with(&mut new_table, temporary_page, |mapper| {
 		let elf_sections_tag = boot_info.elf_sections().expect("Elf-sections tag required");
 
 		for section in elf_sections_tag {
@@ -720,6 +795,158 @@ pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Active
 	active_table
 }
 
+//****** ADDRESS SPACES *******
+
+// Page table of a single user program. All P4 entries except the user one are copied
+// from the kernel table, so kernel code, heap and stacks stay reachable after switching.
+pub struct AddressSpace {
+	table: InactivePageTable,
+	pages: BTreeMap<Page, (usize, EntryFlags)>,	// page -> (frame number, flags)
+}
+
+impl AddressSpace {
+	pub fn new() -> AddressSpace {
+		let MemoryController { active_table, frame_allocator, temporary_page, kernel_p4 } = controller();
+
+		// entries are read through the recursive mapping, so the kernel table has to be the active one
+		assert!(cr3() as usize == *kernel_p4, "address spaces are created from the kernel table");
+		let mut kernel_entries = [0u64; ENTRY_COUNT];
+		for (i, raw) in kernel_entries.iter_mut().enumerate() {
+			*raw = active_table.p4()[i].0;
+		}
+
+		let frame = frame_allocator.allocate_frame().expect("no frames available");
+		let mut table = InactivePageTable::new(frame, active_table, temporary_page);
+
+		active_table.with(&mut table, temporary_page, |mapper| {
+			for i in 0..ENTRY_COUNT - 1 {
+				if i != USER_P4_INDEX {
+					mapper.p4_mut()[i].0 = kernel_entries[i];
+				}
+			}
+		});
+
+		AddressSpace { table, pages: BTreeMap::new() }
+	}
+
+	pub fn p4_address(&self) -> PhysicalAddress {
+		self.table.p4_frame.start_address()
+	}
+
+	pub fn activate(&self) {
+		unsafe { write_raw_cr3(self.p4_address() as u64) };
+	}
+
+	pub fn is_mapped(&self, address: VirtualAddress) -> bool {
+		(USER_SPACE_START..USER_SPACE_END).contains(&address) && self.pages.contains_key(&Page::containing_address(address))
+	}
+
+	pub fn is_writable(&self, address: VirtualAddress) -> bool {
+		self.is_mapped(address) && self.pages[&Page::containing_address(address)].1.contains(WRITABLE)
+	}
+
+	// Maps `size` bytes at `start`, fills them with `data` and zeroes the rest. Pages shared
+	// with an earlier region (e.g. unaligned ELF segments) keep their content and get the union of both flags.
+	pub fn map_region(&mut self, start: VirtualAddress, data: &[u8], size: usize, flags: EntryFlags) -> Result<(), &'static str> {
+		if size == 0 {
+			return Ok(());
+		}
+		if start < USER_SPACE_START || size > USER_SPACE_END - start || data.len() > size {
+			return Err("Region is outside of the user space");
+		}
+
+		let MemoryController { active_table, frame_allocator, temporary_page, .. } = controller();
+		let first_page = Page::containing_address(start);
+		let last_page = Page::containing_address(start + size - 1);
+
+		for page in Page::range_inclusive(first_page, last_page) {
+			let page_start = page.start_address();
+			let (frame_number, flags) = match self.pages.get(&page) {
+				Some(&(number, old_flags)) => (number, merge_flags(old_flags, flags)),
+				None => {
+					let frame = frame_allocator.allocate_frame().ok_or("Out of physical memory")?;
+					let address = temporary_page.map(frame.clone(), active_table);
+					unsafe { ptr::write_bytes(address as *mut u8, 0, PAGE_SIZE) };
+					temporary_page.unmap(active_table);
+					(frame.number, flags)
+				}
+			};
+
+			let copy_start = start.max(page_start);
+			let copy_end = (start + data.len()).min(page_start + PAGE_SIZE);
+			if copy_start < copy_end {
+				let address = temporary_page.map(Frame { number: frame_number }, active_table);
+				unsafe {
+					let src = data.as_ptr().add(copy_start - start);
+					let dst = (address + (copy_start - page_start)) as *mut u8;
+					ptr::copy_nonoverlapping(src, dst, copy_end - copy_start);
+				}
+				temporary_page.unmap(active_table);
+			}
+
+			self.pages.insert(page, (frame_number, flags));
+		}
+
+		let pages = &self.pages;
+		active_table.with(&mut self.table, temporary_page, |mapper| {
+			for page in Page::range_inclusive(first_page, last_page) {
+				let (number, flags) = pages[&page];
+				mapper.map_user_page(page, Frame { number }, flags, frame_allocator);
+			}
+		});
+
+		Ok(())
+	}
+}
+
+// Gives the frames of the program and of its user page tables back to the frame allocator
+impl Drop for AddressSpace {
+	fn drop(&mut self) {
+		let MemoryController { active_table, frame_allocator, temporary_page, .. } = controller();
+		assert!(cr3() as usize != self.p4_address(), "address space is dropped while active");
+
+		let mut table_frames = Vec::new();
+		active_table.with(&mut self.table, temporary_page, |mapper| {
+			let p4 = mapper.p4();
+			if let Some(p3) = p4.next_table(USER_P4_INDEX) {
+				for i in 0..ENTRY_COUNT {
+					if let Some(p2) = p3.next_table(i) {
+						for j in 0..ENTRY_COUNT {
+							table_frames.extend(p2[j].pointed_frame());
+						}
+					}
+					table_frames.extend(p3[i].pointed_frame());
+				}
+			}
+			table_frames.extend(p4[USER_P4_INDEX].pointed_frame());
+		});
+
+		for (_, &(number, _)) in self.pages.iter() {
+			frame_allocator.deallocate_frame(Frame { number });
+		}
+		for frame in table_frames {
+			frame_allocator.deallocate_frame(frame);
+		}
+		frame_allocator.deallocate_frame(self.table.p4_frame.clone());
+	}
+}
+
+// a page shared by two regions is writable/executable if any of them is
+fn merge_flags(a: EntryFlags, b: EntryFlags) -> EntryFlags {
+	let mut flags = (a | b) - NO_EXECUTE;
+	if a.contains(NO_EXECUTE) && b.contains(NO_EXECUTE) {
+		flags = flags | NO_EXECUTE;
+	}
+	flags
+}
+
+pub fn switch_to_kernel_table() {
+	let kernel_p4 = controller().kernel_p4;
+	if cr3() as usize != kernel_p4 {
+		unsafe { write_raw_cr3(kernel_p4 as u64) };
+	}
+}
+
 //Allocator
 
 pub struct Locked<A> {
@@ -876,7 +1103,7 @@ impl LinkedListAllocator {
 		}
 	}
 
-	unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+	pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
 		assert_eq!(align_up(addr, mem::align_of::<VarSizeListNode>()), addr);
 		assert!(size >= mem::size_of::<VarSizeListNode>());
 
@@ -6,9 +6,11 @@ use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::task::Wake;
 use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
 
 use crate::cpu;
 use crate::gui;
+use crate::thread;
 
 pub static mut EXECUTOR_PTR: *mut Executor = core::ptr::null_mut();
 
@@ -77,9 +79,16 @@ impl Executor {
 		} = self;
 		while let Some(task_id) = task_queue.pop() {
 			//println!("{:#?}", task_id);
+			// kernel tasks share state with the syscalls of the programs,
+			// so other threads only run between two polls
+			thread::preempt_disable();
+			thread::reap();
 			let task = match tasks.get_mut(&task_id) {
 				Some(task) => task,
-				None => continue
+				None => {
+					thread::preempt_enable();
+					continue
+				}
 			};
 			let waker = waker_cache.entry(task_id).or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
 			let mut context = Context::from_waker(waker);
@@ -92,11 +101,13 @@ impl Executor {
 				Poll::Pending => {}
 			}
 			self.current_task = None;
+			thread::preempt_enable();
 		}
 	}
 
 	pub fn run(&mut self) -> ! {
 		crate::SYSTEM_INITIALIZED.store(true, core::sync::atomic::Ordering::SeqCst);
+		thread::start();
 		loop {
 			self.run_ready_tasks();
 			self.sleep_if_idle();
@@ -107,7 +118,7 @@ impl Executor {
 		cpu::disable_interrupts();
 		if self.task_queue.is_empty() {
 			cpu::enable_interrupts();
-			cpu::hlt();
+			thread::idle();
 		} else {
 			cpu::enable_interrupts();	
 		}
@@ -171,3 +182,26 @@ impl Future for YieldNow {
 pub async fn cooperate() {
 	YieldNow::new().await;
 }
+
+// Runs a disk transfer to the end for callers that can not await, halting until the
+// next interrupt while it is pending. Other threads may run in the meantime, but
+// nothing else of the executor when called from one of its tasks, which would never
+// see a controller some other thread holds become free. Kernel tasks use
+// `AsyncBlockDevice`.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+	let mut future = core::pin::pin!(future);
+	let mut context = Context::from_waker(Waker::noop());
+	let enabled = interrupts::are_enabled();
+	let output = loop {
+		// the interrupt can not slip in between the poll and the hlt
+		interrupts::disable();
+		if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+			break output;
+		}
+		interrupts::enable_and_hlt();
+	};
+	if enabled {
+		interrupts::enable();
+	}
+	output
+}
@@ -1,4 +1,5 @@
 use crate::pci;
+use crate::memory;
 use crate::println;
 
 pub const ETH_TYPE_IPV4: u16 = 0x0800;
@@ -258,6 +259,7 @@ impl E1000 {
 	    let dev = pci::find_device(0x8086, 0x100e).unwrap();
 	    dev.enable();
 	    let bar0 = dev.bar0();
+	    memory::map_mmio(bar0 as usize, 128 * 1024);
 	    let mmio = bar0 as *mut u32;
 	    E1000::init(mmio, ip)
 	}
@@ -0,0 +1,222 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use abi::Errno;
+use crate::fat32::{ AsyncBlockDevice, BlockDevice, BlockFuture };
+
+// Partition tables of a disk. A protective MBR (a single entry of type 0xEE) means
+// the disk uses GPT. Logical partitions of an extended MBR partition are numbered from
+// 5 on, as Linux does. Partitions past 2 TiB are left out, sectors are addressed with
+// 32 bits.
+
+const MBR_ENTRIES: usize = 0x1BE;
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+// Logical partitions followed at most, in case the EBR chain loops
+const MAX_LOGICAL: usize = 64;
+
+pub struct PartitionInfo {
+	// 1-4 for primary MBR partitions, 5 on for logical ones, GPT entry index + 1
+	pub number: usize,
+	pub start: u32,
+	pub sectors: u32,
+	// MBR type or GPT name of the partition
+	pub description: String,
+}
+
+// Partitions of the disk, empty when it has no partition table
+pub fn read_table(device: &mut dyn BlockDevice) -> Vec<PartitionInfo> {
+	let mut mbr = [0u8; 512];
+	device.read_sector(0, &mut mbr);
+	if mbr[510..512] != [0x55, 0xAA] {
+		return Vec::new();
+	}
+
+	let entries: Vec<MbrEntry> = (0..4).map(|index| MbrEntry::parse(&mbr, index)).collect();
+	if entries.iter().any(|entry| entry.kind == MBR_PROTECTIVE) {
+		return read_gpt(device);
+	}
+
+	let mut partitions = Vec::new();
+	for (index, entry) in entries.iter().enumerate() {
+		if entry.kind == 0 || entry.sectors == 0 {
+			continue;
+		}
+		if MBR_EXTENDED.contains(&entry.kind) {
+			read_logical(device, entry.start, &mut partitions);
+			continue;
+		}
+		partitions.push(entry.info(index + 1, 0));
+	}
+	partitions
+}
+
+struct MbrEntry {
+	kind: u8,
+	start: u32,
+	sectors: u32,
+}
+
+impl MbrEntry {
+	// 16 byte entry: 4 type, 8-11 first sector, 12-15 sector count
+	fn parse(sector: &[u8; 512], index: usize) -> MbrEntry {
+		let entry = &sector[MBR_ENTRIES + index * 16..MBR_ENTRIES + (index + 1) * 16];
+		MbrEntry {
+			kind: entry[4],
+			start: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+			sectors: u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]),
+		}
+	}
+
+	fn info(&self, number: usize, base: u32) -> PartitionInfo {
+		PartitionInfo {
+			number,
+			start: base + self.start,
+			sectors: self.sectors,
+			description: format!("MBR type {:#04x}", self.kind),
+		}
+	}
+}
+
+// Follows the chain of extended boot records. The first entry of each is a logical
+// partition relative to the record, the second one the next record relative to the
+// extended partition.
+fn read_logical(device: &mut dyn BlockDevice, extended_start: u32, partitions: &mut Vec<PartitionInfo>) {
+	let mut ebr_start = extended_start;
+	for number in 5..5 + MAX_LOGICAL {
+		let mut ebr = [0u8; 512];
+		device.read_sector(ebr_start, &mut ebr);
+		if ebr[510..512] != [0x55, 0xAA] {
+			return;
+		}
+
+		let logical = MbrEntry::parse(&ebr, 0);
+		if logical.kind != 0 && logical.sectors != 0 {
+			partitions.push(logical.info(number, ebr_start));
+		}
+		let next = MbrEntry::parse(&ebr, 1);
+		if next.kind == 0 || next.start == 0 {
+			return;
+		}
+		ebr_start = extended_start + next.start;
+	}
+}
+
+// GPT header at LBA 1
+// 0-7: signature
+// 72-79: first sector of the entry array
+// 80-83: number of entries
+// 84-87: size of an entry
+// Entry: 0-15 type GUID (zero if unused), 32-39 first sector, 40-47 last sector,
+// 56-127 name in UTF-16
+fn read_gpt(device: &mut dyn BlockDevice) -> Vec<PartitionInfo> {
+	let mut header = [0u8; 512];
+	device.read_sector(1, &mut header);
+	if &header[0..8] != GPT_SIGNATURE {
+		return Vec::new();
+	}
+
+	let read_u32 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+	let read_u64 = |bytes: &[u8]| u64::from_le_bytes(bytes[..8].try_into().unwrap());
+	let entries_lba = read_u64(&header[72..80]);
+	let entry_count = read_u32(&header[80..84]) as usize;
+	let entry_size = read_u32(&header[84..88]) as usize;
+	if entry_size < 128 || 512 % entry_size != 0 || entries_lba > u32::MAX as u64 {
+		return Vec::new();
+	}
+
+	let per_sector = 512 / entry_size;
+	let mut partitions = Vec::new();
+	let mut sector = [0u8; 512];
+	for index in 0..entry_count.min(128) {
+		if index % per_sector == 0 {
+			device.read_sector(entries_lba as u32 + (index / per_sector) as u32, &mut sector);
+		}
+		let entry = &sector[(index % per_sector) * entry_size..][..128];
+		if entry[0..16].iter().all(|&byte| byte == 0) {
+			continue;
+		}
+		let first = read_u64(&entry[32..40]);
+		let last = read_u64(&entry[40..48]);
+		if last < first || last > u32::MAX as u64 {
+			continue;
+		}
+
+		let units: Vec<u16> = entry[56..128].chunks(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).take_while(|&unit| unit != 0).collect();
+		let name = String::from_utf16_lossy(&units);
+		partitions.push(PartitionInfo {
+			number: index + 1,
+			start: first as u32,
+			sectors: (last - first + 1) as u32,
+			description: if name.is_empty() { String::from("GPT partition") } else { name },
+		});
+	}
+	partitions
+}
+
+// A partition as a device of its own, sector 0 is its first sector
+pub struct Partition<D> {
+	device: D,
+	start: u32,
+	sectors: u32,
+}
+
+impl<D> Partition<D> {
+	pub fn new(device: D, start: u32, sectors: u32) -> Self {
+		Partition { device, start, sectors }
+	}
+
+	// Device sector of the first of `count` partition sectors, None past the end
+	fn translate(&self, lba: u32, count: usize) -> Option<u32> {
+		if lba as u64 + count as u64 > self.sectors as u64 {
+			return None;
+		}
+		Some(self.start + lba)
+	}
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+	fn read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) {
+		let Some(sector) = self.translate(lba, 1) else {
+			panic!("Sector {} is past the end of the partition ({} sectors)", lba, self.sectors);
+		};
+		self.device.read_sector(sector, buf);
+	}
+
+	fn try_read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), Errno> {
+		let sector = self.translate(lba, 1).ok_or(Errno::InvalidArgument)?;
+		self.device.try_read_sector(sector, buf)
+	}
+
+	fn write_sector(&mut self, lba: u32, buf: &[u8; 512]) {
+		let Some(sector) = self.translate(lba, 1) else {
+			panic!("Sector {} is past the end of the partition ({} sectors)", lba, self.sectors);
+		};
+		self.device.write_sector(sector, buf);
+	}
+
+	fn flush(&mut self) {
+		self.device.flush();
+	}
+}
+
+impl<D: AsyncBlockDevice> AsyncBlockDevice for Partition<D> {
+	fn read_sectors<'a>(&'a self, lba: u32, buf: &'a mut [u8]) -> BlockFuture<'a> {
+		match self.translate(lba, buf.len() / 512) {
+			Some(sector) => self.device.read_sectors(sector, buf),
+			None => Box::pin(async { Err(Errno::InvalidArgument) }),
+		}
+	}
+
+	fn write_sectors<'a>(&'a self, lba: u32, buf: &'a [u8]) -> BlockFuture<'a> {
+		match self.translate(lba, buf.len() / 512) {
+			Some(sector) => self.device.write_sectors(sector, buf),
+			None => Box::pin(async { Err(Errno::InvalidArgument) }),
+		}
+	}
+}
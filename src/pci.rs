@@ -1,3 +1,7 @@
+use alloc::vec::Vec;
+
+use crate::memory;
+
 const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
 const PCI_CONFIG_DATA: u16 = 0xCFC;
 
@@ -38,12 +42,51 @@ impl PciDevice {
         self.read(0x10) & 0xFFFFFFF0
     }
 
+    // Base address register 0-5, with the flag bits still in it
+    pub fn bar(&self, index: u8) -> u32 {
+        self.read(0x10 + index * 4)
+    }
+
     pub fn enable(&self) {
         let mut cmd = self.read(0x04);
         cmd |= 1 << 1; // memory space
         cmd |= 1 << 2; // bus master
         self.write(0x04, cmd);
     }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    // Memory address of a BAR, with the upper half of a 64 bit one
+    pub fn memory_bar(&self, index: u8) -> u64 {
+        let bar = self.bar(index);
+        let low = (bar & 0xFFFFFFF0) as u64;
+        if bar & 0x6 == 0x4 {
+            low | (self.bar(index + 1) as u64) << 32
+        } else {
+            low
+        }
+    }
+
+    // (id, offset) of every entry of the capability list
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if self.read_u16(0x06) & (1 << 4) == 0 {
+            return capabilities;
+        }
+        let mut offset = self.read_u8(0x34) & 0xFC;
+        // 48 entries fit in the configuration space, more means the list loops
+        while offset != 0 && capabilities.len() < 48 {
+            capabilities.push((self.read_u8(offset), offset));
+            offset = self.read_u8(offset + 1) & 0xFC;
+        }
+        capabilities
+    }
 }
 
 pub fn find_device(vendor: u16, device: u16) -> Option<PciDevice> {
@@ -61,3 +104,306 @@ pub fn find_device(vendor: u16, device: u16) -> Option<PciDevice> {
     }
     None
 }
+
+// First function with the class and subclass, on any bus
+pub fn find_class(class: u8, subclass: u8) -> Option<PciDevice> {
+    for bus in 0..=255 {
+        for slot in 0..32 {
+            for func in 0..8 {
+                let dev = PciDevice { bus, slot, func };
+                if dev.read(0) & 0xffff == 0xffff {
+                    continue;
+                }
+                let class_reg = dev.read(0x08);
+                if (class_reg >> 24) as u8 == class && (class_reg >> 16) as u8 == subclass {
+                    return Some(dev);
+                }
+            }
+        }
+    }
+    None
+}
+
+// Every function on every bus
+pub fn devices() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for slot in 0..32 {
+            for func in 0..8 {
+                let dev = PciDevice { bus, slot, func };
+                if dev.read(0) & 0xffff != 0xffff {
+                    devices.push(dev);
+                }
+            }
+        }
+    }
+    devices
+}
+
+// Virtio devices have the vendor 0x1AF4. Transitional devices have the ID 0x1000 +
+// something that is not the device type (0x1001 for block devices) and can be driven
+// through the legacy I/O registers at BAR0, modern ones 0x1040 + the device type.
+// QEMU's transitional devices also have the vendor capabilities of the modern
+// interface, which is used when it is there.
+
+const VIRTIO_VENDOR: u16 = 0x1AF4;
+
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
+pub const VIRTIO_STATUS_DRIVER: u8 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
+pub const VIRTIO_STATUS_FAILED: u8 = 128;
+
+// Modern devices only work with drivers that accept it
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Vendor capability of the modern interface
+// 3: which structure it points to
+// 4: BAR
+// 8-11: offset in the BAR
+// 12-15: length
+// 16-19: notify offset multiplier, for the notify structure
+const VIRTIO_CAP_COMMON: u8 = 1;
+const VIRTIO_CAP_NOTIFY: u8 = 2;
+const VIRTIO_CAP_ISR: u8 = 3;
+const VIRTIO_CAP_DEVICE: u8 = 4;
+
+// Common configuration of the modern interface
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// Legacy I/O registers, the device configuration follows them at 0x14
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG: u16 = 0x14;
+
+// Where the registers of a virtio device are. The structures of the modern interface
+// are identity mapped.
+#[derive(Clone, Copy)]
+pub enum VirtioTransport {
+    Legacy { io: u16 },
+    Modern { common: usize, notify: usize, notify_multiplier: u32, isr: usize, device: usize },
+}
+
+// Virtio devices of the type (2 for block devices), with the PCI function each is on
+pub fn find_virtio(device_type: u16) -> Vec<(PciDevice, VirtioTransport)> {
+    devices().into_iter().filter_map(|dev| {
+        let id = dev.read(0);
+        let (vendor, device) = ((id & 0xffff) as u16, (id >> 16) as u16);
+        if vendor != VIRTIO_VENDOR {
+            return None;
+        }
+        let transitional = (0x1000..0x1040).contains(&device) && dev.read_u16(0x2E) == device_type;
+        if !transitional && device != 0x1040 + device_type {
+            return None;
+        }
+        let transport = VirtioTransport::new(&dev)?;
+        Some((dev, transport))
+    }).collect()
+}
+
+impl VirtioTransport {
+    fn new(dev: &PciDevice) -> Option<VirtioTransport> {
+        dev.enable();
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        for (id, offset) in dev.capabilities() {
+            if id != 0x09 {
+                continue;
+            }
+            let bar = dev.read_u8(offset + 4);
+            if bar > 5 {
+                continue;
+            }
+            let address = (dev.memory_bar(bar) + dev.read(offset + 8) as u64) as usize;
+            let length = dev.read(offset + 12) as usize;
+            if length == 0 {
+                continue;
+            }
+            memory::map_mmio(address, length);
+            match dev.read_u8(offset + 3) {
+                VIRTIO_CAP_COMMON => common = common.or(Some(address)),
+                VIRTIO_CAP_NOTIFY => notify = notify.or(Some((address, dev.read(offset + 16)))),
+                VIRTIO_CAP_ISR => isr = isr.or(Some(address)),
+                VIRTIO_CAP_DEVICE => device = device.or(Some(address)),
+                _ => {}
+            }
+        }
+
+        if let (Some(common), Some((notify, notify_multiplier)), Some(isr), Some(device)) = (common, notify, isr, device) {
+            return Some(VirtioTransport::Modern { common, notify, notify_multiplier, isr, device });
+        }
+        let bar0 = dev.bar(0);
+        if bar0 & 1 == 1 {
+            dev.write(0x04, dev.read(0x04) | 1 << 0);    // I/O space
+            return Some(VirtioTransport::Legacy { io: (bar0 & 0xFFFC) as u16 });
+        }
+        None
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, VirtioTransport::Legacy { .. })
+    }
+
+    pub fn status(&self) -> u8 {
+        match *self {
+            VirtioTransport::Legacy { io } => unsafe { x86::io::inb(io + LEGACY_DEVICE_STATUS) },
+            VirtioTransport::Modern { common, .. } => mmio_read::<u8>(common + COMMON_DEVICE_STATUS),
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match *self {
+            VirtioTransport::Legacy { io } => unsafe { x86::io::outb(io + LEGACY_DEVICE_STATUS, status) },
+            VirtioTransport::Modern { common, .. } => mmio_write::<u8>(common + COMMON_DEVICE_STATUS, status),
+        }
+    }
+
+    // Resets the device and tells it a driver is there
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+        self.set_status(VIRTIO_STATUS_ACKNOWLEDGE);
+        self.set_status(VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER);
+    }
+
+    // Accepts the features of `wanted` the device offers and returns them
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, &'static str> {
+        match *self {
+            VirtioTransport::Legacy { io } => unsafe {
+                let accepted = x86::io::inl(io + LEGACY_DEVICE_FEATURES) as u64 & wanted;
+                x86::io::outl(io + LEGACY_DRIVER_FEATURES, accepted as u32);
+                Ok(accepted)
+            },
+            VirtioTransport::Modern { common, .. } => {
+                let mut offered = 0u64;
+                for half in 0..2 {
+                    mmio_write::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, half);
+                    offered |= (mmio_read::<u32>(common + COMMON_DEVICE_FEATURE) as u64) << (half * 32);
+                }
+                if offered & VIRTIO_F_VERSION_1 == 0 {
+                    return Err("The device does not support virtio 1.0");
+                }
+                let accepted = offered & (wanted | VIRTIO_F_VERSION_1);
+                for half in 0..2 {
+                    mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, half);
+                    mmio_write::<u32>(common + COMMON_DRIVER_FEATURE, (accepted >> (half * 32)) as u32);
+                }
+                self.set_status(self.status() | VIRTIO_STATUS_FEATURES_OK);
+                if self.status() & VIRTIO_STATUS_FEATURES_OK == 0 {
+                    return Err("The device did not accept the features");
+                }
+                Ok(accepted)
+            }
+        }
+    }
+
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | VIRTIO_STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.set_status(self.status() | VIRTIO_STATUS_FAILED);
+    }
+
+    // Most entries the queue can have, 0 if there is no such queue
+    pub fn queue_max_size(&self, queue: u16) -> u16 {
+        match *self {
+            VirtioTransport::Legacy { io } => unsafe {
+                x86::io::outw(io + LEGACY_QUEUE_SELECT, queue);
+                x86::io::inw(io + LEGACY_QUEUE_SIZE)
+            },
+            VirtioTransport::Modern { common, .. } => {
+                mmio_write::<u16>(common + COMMON_QUEUE_SELECT, queue);
+                mmio_read::<u16>(common + COMMON_QUEUE_SIZE)
+            }
+        }
+    }
+
+    // Hands the physical addresses of the three parts of the queue to the device. A
+    // legacy device only takes the size it has and one contiguous range that starts
+    // on a page, with the used ring on the first page boundary after the available one.
+    pub fn set_queue(&self, queue: u16, size: u16, descriptors: u64, available: u64, used: u64) -> Result<(), &'static str> {
+        match *self {
+            VirtioTransport::Legacy { io } => unsafe {
+                x86::io::outw(io + LEGACY_QUEUE_SELECT, queue);
+                if x86::io::inw(io + LEGACY_QUEUE_SIZE) != size || descriptors % 4096 != 0 || descriptors / 4096 > u32::MAX as u64 {
+                    return Err("The legacy device can not use the queue");
+                }
+                x86::io::outl(io + LEGACY_QUEUE_ADDRESS, (descriptors / 4096) as u32);
+                Ok(())
+            },
+            VirtioTransport::Modern { common, .. } => {
+                mmio_write::<u16>(common + COMMON_QUEUE_SELECT, queue);
+                mmio_write::<u16>(common + COMMON_QUEUE_SIZE, size);
+                mmio_write::<u64>(common + COMMON_QUEUE_DESC, descriptors);
+                mmio_write::<u64>(common + COMMON_QUEUE_DRIVER, available);
+                mmio_write::<u64>(common + COMMON_QUEUE_DEVICE, used);
+                mmio_write::<u16>(common + COMMON_QUEUE_ENABLE, 1);
+                Ok(())
+            }
+        }
+    }
+
+    // Tells the device there are new buffers in the queue
+    pub fn notify(&self, queue: u16) {
+        match *self {
+            VirtioTransport::Legacy { io } => unsafe { x86::io::outw(io + LEGACY_QUEUE_NOTIFY, queue) },
+            VirtioTransport::Modern { common, notify, notify_multiplier, .. } => {
+                mmio_write::<u16>(common + COMMON_QUEUE_SELECT, queue);
+                let offset = mmio_read::<u16>(common + COMMON_QUEUE_NOTIFY_OFF) as usize * notify_multiplier as usize;
+                mmio_write::<u16>(notify + offset, queue);
+            }
+        }
+    }
+
+    // Interrupt status, bit 0 for the queues and bit 1 for a configuration change.
+    // Reading it acknowledges the interrupt.
+    pub fn isr(&self) -> u8 {
+        match *self {
+            VirtioTransport::Legacy { io } => unsafe { x86::io::inb(io + LEGACY_ISR) },
+            VirtioTransport::Modern { isr, .. } => mmio_read::<u8>(isr),
+        }
+    }
+
+    // A byte of the configuration of the device type
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        match *self {
+            VirtioTransport::Legacy { io } => unsafe { x86::io::inb(io + LEGACY_CONFIG + offset as u16) },
+            VirtioTransport::Modern { device, .. } => mmio_read::<u8>(device + offset),
+        }
+    }
+
+    // Little endian, read a byte at a time as legacy devices allow no more
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        (0..8).rev().fold(0u64, |value, byte| value << 8 | self.config_u8(offset + byte) as u64)
+    }
+}
+
+fn mmio_read<T>(address: usize) -> T {
+    unsafe { (address as *const T).read_volatile() }
+}
+
+fn mmio_write<T>(address: usize, value: T) {
+    unsafe { (address as *mut T).write_volatile(value) }
+}
@@ -0,0 +1,271 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{ String, ToString };
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicU64, Ordering };
+use spin::{ Mutex, MutexGuard };
+
+use abi::Errno;
+use crate::fat32;
+use crate::gdt;
+use crate::gui;
+use crate::interrupts::Registers;
+use crate::memory::{ self, AddressSpace, PAGE_SIZE };
+use crate::thread;
+use crate::vfs::FileTable;
+
+pub const USER_HEAP_START: usize = memory::USER_SPACE_START + 0x10_0000_0000;
+pub const USER_HEAP_END: usize = USER_HEAP_START + 0x10_0000_0000;
+pub const USER_STACK_TOP: usize = memory::USER_SPACE_END - PAGE_SIZE;	// keep a guard page above
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+
+// Size limit of the argument and environment blocks together, they live at the top of the stack
+pub const MAX_ARGS_SIZE: usize = 16 * 1024;
+
+// RFLAGS of a fresh program: interrupts enabled, reserved bit 1 set
+const USER_RFLAGS: u64 = 0x202;
+
+pub type Pid = u64;
+
+// Exit status of a program that was killed because of a fault
+pub const KILLED_STATUS: i64 = -1;
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+// Every program that was started and not waited for yet
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+	Running,
+	Exited(i64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Process {
+	pub pid: Pid,
+	pub parent: Option<Pid>,
+	pub name: String,
+	pub state: ProcessState,
+	pub terminal_id: Option<gui::NodeId>,
+	pub thread: thread::ThreadId,
+}
+
+// Syscalls are preempted by the timer like everything else, so the table is locked.
+// Waiting for it gives the time to other threads, as its holder may be one of them.
+fn processes() -> MutexGuard<'static, BTreeMap<Pid, Process>> {
+	loop {
+		if let Some(guard) = PROCESSES.try_lock() {
+			return guard;
+		}
+		thread::yield_now();
+	}
+}
+
+// Register state a thread is resumed with: registers, then the interrupt frame,
+// in the order the interrupt trampolines leave them on the kernel stack
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserContext {
+	pub regs: Registers,
+	pub rip: u64,
+	pub cs: u64,
+	pub rflags: u64,
+	pub rsp: u64,
+	pub ss: u64,
+}
+
+pub struct Program {
+	pub pid: Pid,
+	pub address_space: AddressSpace,
+	entry: usize,
+	stack_pointer: usize,
+	args_pointer: usize,
+	// The heap is mapped from USER_HEAP_START up to here, the program manages it itself
+	heap_end: usize,
+	pub files: FileTable,
+}
+
+impl Program {
+	// `argv` and `env` are blocks of NUL terminated strings, see `abi::RunArgs`
+	pub fn load(elf_data: &[u8], argv: &[u8], env: &[u8]) -> Result<Program, &'static str> {
+		if argv.len() + env.len() > MAX_ARGS_SIZE {
+			return Err("Argument list too long");
+		}
+		let (mut address_space, entry) = fat32::load_elf(elf_data)?;
+
+		let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+		let flags = memory::USER_ACCESSIBLE | memory::WRITABLE | memory::NO_EXECUTE;
+		address_space.map_region(stack_bottom, &[], USER_STACK_SIZE, flags)?;
+
+		// the pages are mapped already, this only copies the block to the top of the stack
+		let (block, args_pointer) = initial_stack(argv, env);
+		let stack_pointer = USER_STACK_TOP - block.len();
+		address_space.map_region(stack_pointer, &block, block.len(), flags)?;
+
+		Ok(Program {
+			pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+			address_space,
+			entry,
+			stack_pointer,
+			args_pointer,
+			heap_end: USER_HEAP_START,
+			files: FileTable::new(),
+		})
+	}
+
+	// Context that starts the program at its entry point in ring 3
+	pub fn initial_context(&self) -> UserContext {
+		let selectors = &gdt::GDT.1;
+		UserContext {
+			rip: self.entry as u64,
+			cs: selectors.user_code_selector.0 as u64,
+			rflags: USER_RFLAGS,
+			rsp: self.stack_pointer as u64,
+			ss: selectors.user_data_selector.0 as u64,
+			regs: Registers { rdi: self.args_pointer, ..Registers::default() },
+		}
+	}
+
+	// Maps zeroed pages at the end of the heap up to `end`, rounded up to a page, and
+	// returns the end of the heap afterwards. The heap never shrinks, an end below the
+	// current one (0 for instance) only asks where it is.
+	pub fn brk(&mut self, end: usize) -> usize {
+		let end = match end.checked_next_multiple_of(PAGE_SIZE) {
+			Some(end) if end > self.heap_end && end <= USER_HEAP_END => end,
+			_ => return self.heap_end,
+		};
+		let flags = memory::USER_ACCESSIBLE | memory::WRITABLE | memory::NO_EXECUTE;
+		if self.address_space.map_region(self.heap_end, &[], end - self.heap_end, flags).is_ok() {
+			self.heap_end = end;
+		}
+		self.heap_end
+	}
+}
+
+fn strings(block: &[u8]) -> impl Iterator<Item = &[u8]> {
+	block.split_inclusive(|&byte| byte == 0).filter(|string| *string != [0])
+}
+
+// Builds the top of the stack of a new program the way the SysV ABI lays it out at
+// process entry: argc, the argv pointers, NULL, the envp pointers, NULL, an empty
+// auxiliary vector and the strings above them. `_start` is an ordinary extern "C"
+// function, so below argc sits a zero return address and the address of argc is
+// also passed in rdi. Returns the block, which ends at USER_STACK_TOP, and that address.
+fn initial_stack(argv: &[u8], env: &[u8]) -> (Vec<u8>, usize) {
+	const AT_NULL: u64 = 0;
+
+	let mut text = Vec::new();
+	let mut argv_offsets = Vec::new();
+	let mut env_offsets = Vec::new();
+	for (block, offsets) in [(argv, &mut argv_offsets), (env, &mut env_offsets)] {
+		for string in strings(block) {
+			offsets.push(text.len());
+			text.extend_from_slice(string);
+			if string.last() != Some(&0) {
+				text.push(0);
+			}
+		}
+	}
+
+	let text_start = (USER_STACK_TOP - text.len()) & !0xF;
+	let words = 1 + argv_offsets.len() + 1 + env_offsets.len() + 1 + 2;
+	let args_pointer = (text_start - words * 8) & !0xF;
+	let start = args_pointer - 8;
+
+	let mut vector: Vec<u64> = Vec::with_capacity(words + 1);
+	vector.push(0);		// return address of _start
+	vector.push(argv_offsets.len() as u64);
+	vector.extend(argv_offsets.iter().map(|&offset| (text_start + offset) as u64));
+	vector.push(0);
+	vector.extend(env_offsets.iter().map(|&offset| (text_start + offset) as u64));
+	vector.push(0);
+	vector.extend([AT_NULL, 0]);
+
+	let mut block = vec![0u8; USER_STACK_TOP - start];
+	for (index, word) in vector.iter().enumerate() {
+		block[index * 8..index * 8 + 8].copy_from_slice(&word.to_ne_bytes());
+	}
+	block[text_start - start..text_start - start + text.len()].copy_from_slice(&text);
+	(block, args_pointer)
+}
+
+// The program whose syscall is being handled, if any
+pub fn current() -> Option<&'static mut Program> {
+	thread::current().and_then(|thread| thread.program.as_deref_mut())
+}
+
+// Loads the program and starts it in a thread of its own, as a child of the running program
+pub fn spawn(elf_data: &[u8], name: &str, argv: &[u8], env: &[u8], terminal_id: Option<gui::NodeId>) -> Result<Pid, &'static str> {
+	let program = Program::load(elf_data, argv, env)?;
+	let pid = program.pid;
+	let parent = current().map(|program| program.pid);
+
+	// the child waits for the table in `exit` until it is in it
+	let mut table = processes();
+	let thread = thread::spawn_program(program, terminal_id);
+	table.insert(pid, Process {
+		pid,
+		parent,
+		name: name.to_string(),
+		state: ProcessState::Running,
+		terminal_id,
+		thread,
+	});
+	Ok(pid)
+}
+
+pub fn getpid() -> Option<Pid> {
+	current().map(|program| program.pid)
+}
+
+pub fn current_name() -> Option<String> {
+	let pid = getpid()?;
+	processes().get(&pid).map(|process| process.name.clone())
+}
+
+// Records the exit status of the running program and ends its thread
+pub fn exit(code: i64) -> ! {
+	if let Some(pid) = getpid() {
+		let mut table = processes();
+
+		// nobody is left to wait for the children of the program
+		table.retain(|_, process| process.parent != Some(pid) || process.state == ProcessState::Running);
+		for process in table.values_mut() {
+			if process.parent == Some(pid) {
+				process.parent = None;
+			}
+		}
+
+		match table.get_mut(&pid) {
+			Some(process) if process.parent.is_some() => process.state = ProcessState::Exited(code),
+			_ => { table.remove(&pid); }
+		}
+	}
+	thread::exit()
+}
+
+// Blocks until the child `pid` of the running program exits and returns its status.
+// The process is removed from the table afterwards, so it can only be waited for once.
+pub fn wait(pid: Pid) -> Result<i64, Errno> {
+	loop {
+		if let Some(code) = try_wait(pid)? {
+			return Ok(code);
+		}
+		thread::yield_now();
+	}
+}
+
+// Like `wait`, but None while the child is still running
+pub fn try_wait(pid: Pid) -> Result<Option<i64>, Errno> {
+	let parent = getpid();
+	let mut table = processes();
+	let process = match table.get(&pid) {
+		Some(process) if process.parent == parent && parent.is_some() => process,
+		_ => return Err(Errno::NoChild),
+	};
+	if let ProcessState::Exited(code) = process.state {
+		table.remove(&pid);
+		return Ok(Some(code));
+	}
+	Ok(None)
+}
@@ -0,0 +1,406 @@
+use alloc::format;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use abi::errno::{ self, Errno };
+use abi::{ CheckArgs, RunArgs, Stat, SyscallArgs, SyscallNumber };
+use crate::disk;
+use crate::framebuffer;
+use crate::keyboard;
+use crate::multitasking;
+use crate::print;
+use crate::println;
+use crate::process;
+use crate::thread;
+use crate::uaccess;
+use crate::vfs::{ self, FileType, Metadata };
+
+type SyscallHandler = fn(&SyscallArgs) -> u64;
+
+// Reads and writes on descriptors move at most this much per call, the program
+// calls again for the rest
+const MAX_IO_CHUNK: usize = 64 * 1024;
+
+// Handlers indexed by `SyscallNumber::index`. Filled from `handler`, whose match has
+// to name every syscall of the ABI crate, so a new syscall without a handler does not build.
+static SYSCALL_TABLE: [SyscallHandler; SyscallNumber::COUNT] = {
+	let mut table = [sys_unknown as SyscallHandler; SyscallNumber::COUNT];
+	let mut index = 0;
+	while index < SyscallNumber::COUNT {
+		table[index] = handler(SyscallNumber::ALL[index]);
+		index += 1;
+	}
+	table
+};
+
+const fn handler(number: SyscallNumber) -> SyscallHandler {
+	match number {
+		SyscallNumber::Write => sys_write,
+		SyscallNumber::Exit => sys_exit,
+		SyscallNumber::Read => sys_read,
+		SyscallNumber::RemoveChar => sys_rm_char,
+		SyscallNumber::Brk => sys_brk,
+		SyscallNumber::CheckFsEntryExists => sys_check_fs_entry_exists,
+		SyscallNumber::GenerateTaskId => sys_gen_task_id,
+		SyscallNumber::ListDir => sys_list_dir,
+		SyscallNumber::MakeDir => sys_mkdir,
+		SyscallNumber::MakeFile => sys_mkfile,
+		SyscallNumber::WriteFile => sys_write_file,
+		SyscallNumber::ReadFile => sys_read_file,
+		SyscallNumber::RemoveDir => sys_rmdir,
+		SyscallNumber::RemoveFile => sys_rmfile,
+		SyscallNumber::Run => sys_run,
+		SyscallNumber::ClearScreen => sys_clear,
+		SyscallNumber::Yield => sys_yield,
+		SyscallNumber::Wait => sys_wait,
+		SyscallNumber::GetPid => sys_getpid,
+		SyscallNumber::Open => sys_open,
+		SyscallNumber::Close => sys_close,
+		SyscallNumber::ReadFd => sys_read_fd,
+		SyscallNumber::WriteFd => sys_write_fd,
+		SyscallNumber::Seek => sys_seek,
+		SyscallNumber::Fstat => sys_fstat,
+		SyscallNumber::Sync => sys_sync,
+		SyscallNumber::Shutdown => sys_shutdown,
+		SyscallNumber::Mount => sys_mount,
+		SyscallNumber::ListDisks => sys_list_disks,
+		SyscallNumber::Stat => sys_stat,
+		SyscallNumber::Rename => sys_rename,
+		SyscallNumber::Check => sys_check,
+	}
+}
+
+pub fn dispatch(number: u64, args: &SyscallArgs) -> u64 {
+	match SyscallNumber::from_number(number) {
+		Some(number) => SYSCALL_TABLE[number.index()](args),
+		None => {
+			println!("Unknown syscall: {}", number);
+			sys_unknown(args)
+		}
+	}
+}
+
+fn sys_unknown(_args: &SyscallArgs) -> u64 {
+	errno::encode(Err(Errno::NoSyscall))
+}
+
+fn sys_write(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).map(|text| {
+		print!("{}", text);
+		0
+	}))
+}
+
+fn sys_exit(args: &SyscallArgs) -> u64 {
+	if process::current().is_some() {
+		process::exit(args.arg1 as i64);
+	}
+	println!("Exit requested outside of a program.");
+	0
+}
+
+fn sys_read(args: &SyscallArgs) -> u64 {
+	let mut input = keyboard::INPUT_BUFFER.lock();
+	let mut encoded = Vec::new();
+	let mut taken = 0;
+	for c in input.iter() {
+		let mut utf8 = [0u8; 4];
+		let bytes = c.encode_utf8(&mut utf8).as_bytes();
+		if encoded.len() + bytes.len() > args.arg2 as usize {
+			break;
+		}
+		encoded.extend_from_slice(bytes);
+		taken += 1;
+	}
+	// input stays in the buffer if the program passed a bad one
+	errno::encode(uaccess::copy_to_user(args.arg1 as usize, &encoded).map(|()| {
+		input.drain(..taken);
+		encoded.len() as u64
+	}))
+}
+
+fn sys_rm_char(_args: &SyscallArgs) -> u64 {
+	if crate::SYSTEM_INITIALIZED.load(Ordering::SeqCst) {
+		if let Some(term_id) = thread::current_terminal() {
+			let mut writer = framebuffer::TerminalWriter { terminal_id: term_id };
+			writer.rm_char();
+		}
+	}
+	else {
+		framebuffer::FB_WRITER.lock().rm_char();
+	}
+	0
+}
+
+// Programs run their allocator themselves in the user space, the kernel only maps the
+// pages of the heap. Returns the end of the heap, which did not move if it could not.
+fn sys_brk(args: &SyscallArgs) -> u64 {
+	process::current().map_or(0, |program| program.brk(args.arg1 as usize) as u64)
+}
+
+fn sys_check_fs_entry_exists(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).map(|path| {
+		vfs::exists(&path) as u64
+	}))
+}
+
+fn sys_gen_task_id(_args: &SyscallArgs) -> u64 {
+	multitasking::TaskId::new().0
+}
+
+fn sys_list_dir(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		// one name per line, names that do not fit are left out
+		let mut listing = Vec::new();
+		for entry in vfs::list_dir(&path)? {
+			let suffix: &[u8] = if entry.file_type == FileType::Directory { b"/\n" } else { b"\n" };
+			if listing.len() + entry.name.len() + suffix.len() > args.arg4 as usize {
+				break;
+			}
+			listing.extend_from_slice(entry.name.as_bytes());
+			listing.extend_from_slice(suffix);
+		}
+		uaccess::copy_to_user(args.arg3 as usize, &listing)?;
+		Ok(listing.len() as u64)
+	}))
+}
+
+fn sys_mkdir(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		vfs::create_dir(&path).map(|()| 0)
+	}))
+}
+
+fn sys_mkfile(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		vfs::create_file(&path).map(|()| 0)
+	}))
+}
+
+fn sys_write_file(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		let data = uaccess::read_bytes(args.arg3 as usize, args.arg4 as usize)?;
+		vfs::write_file(&path, &data).map(|()| 0)
+	}))
+}
+
+fn sys_read_file(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		let bytes = vfs::read_file(&path)?;
+		let copy_len = core::cmp::min(args.arg4 as usize, bytes.len());
+		uaccess::copy_to_user(args.arg3 as usize, &bytes[..copy_len])?;
+		Ok(copy_len as u64)
+	}))
+}
+
+fn sys_rmdir(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		vfs::remove_dir(&path).map(|()| 0)
+	}))
+}
+
+fn sys_rmfile(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		vfs::remove_file(&path).map(|()| 0)
+	}))
+}
+
+fn sys_run(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_struct::<RunArgs>(args.arg1 as usize).and_then(|run| {
+		let path = uaccess::read_str(run.path_ptr as usize, run.path_len as usize)?;
+		if run.argv_len as usize + run.env_len as usize > process::MAX_ARGS_SIZE {
+			return Err(Errno::ArgumentListTooLong);
+		}
+		let argv = uaccess::read_bytes(run.argv_ptr as usize, run.argv_len as usize)?;
+		let env = uaccess::read_bytes(run.env_ptr as usize, run.env_len as usize)?;
+
+		let data = vfs::read_file(&path)?;
+		// the program prints to the terminal of the one that started it
+		process::spawn(&data, &path, &argv, &env, thread::current_terminal()).map_err(|e| {
+			println!("Failed to load program: {}", e);
+			Errno::InvalidExecutable
+		})
+	}))
+}
+
+fn sys_clear(_args: &SyscallArgs) -> u64 {
+	if crate::SYSTEM_INITIALIZED.load(Ordering::SeqCst) {
+		if let Some(term_id) = thread::current_terminal() {
+			let mut writer = framebuffer::TerminalWriter { terminal_id: term_id };
+			writer.clear();
+		}
+	}
+	else {
+		framebuffer::FB_WRITER.lock().clear();
+	}
+	0
+}
+
+fn sys_yield(_args: &SyscallArgs) -> u64 {
+	thread::yield_now();
+	0
+}
+
+fn sys_wait(args: &SyscallArgs) -> u64 {
+	let result = if args.arg3 & abi::WAIT_NOHANG != 0 {
+		process::try_wait(args.arg1)
+	} else {
+		process::wait(args.arg1).map(Some)
+	};
+	errno::encode(result.map(|code| match code {
+		Some(code) => {
+			if args.arg2 != 0 {
+				// the child is reaped either way, a bad pointer only loses its status
+				let _ = uaccess::copy_to_user(args.arg2 as usize, &code.to_ne_bytes());
+			}
+			args.arg1
+		}
+		None => 0,
+	}))
+}
+
+fn sys_getpid(_args: &SyscallArgs) -> u64 {
+	process::getpid().unwrap_or(0)
+}
+
+fn files() -> Result<&'static mut vfs::FileTable, Errno> {
+	process::current().map(|program| &mut program.files).ok_or(Errno::BadFileDescriptor)
+}
+
+fn sys_open(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		let files = files()?;
+		let file = vfs::open(&path, args.arg3)?;
+		files.insert(file)
+	}))
+}
+
+fn sys_close(args: &SyscallArgs) -> u64 {
+	errno::encode(files().and_then(|files| files.remove(args.arg1)).map(|_| 0))
+}
+
+fn sys_read_fd(args: &SyscallArgs) -> u64 {
+	errno::encode(files().and_then(|files| files.get(args.arg1)).and_then(|file| {
+		if file.flags & abi::OPEN_READ == 0 {
+			return Err(Errno::BadFileDescriptor);
+		}
+		let mut data = vec![0u8; (args.arg3 as usize).min(MAX_IO_CHUNK)];
+		let len = file.inode.read_at(file.offset, &mut data)?;
+		uaccess::copy_to_user(args.arg2 as usize, &data[..len])?;
+		file.offset += len as u64;
+		Ok(len as u64)
+	}))
+}
+
+fn sys_write_fd(args: &SyscallArgs) -> u64 {
+	errno::encode(files().and_then(|files| files.get(args.arg1)).and_then(|file| {
+		if file.flags & (abi::OPEN_WRITE | abi::OPEN_APPEND) == 0 {
+			return Err(Errno::BadFileDescriptor);
+		}
+		let data = uaccess::read_bytes(args.arg2 as usize, (args.arg3 as usize).min(MAX_IO_CHUNK))?;
+		if file.flags & abi::OPEN_APPEND != 0 {
+			file.offset = file.inode.metadata().size;
+		}
+		let len = file.inode.write_at(file.offset, &data)?;
+		file.offset += len as u64;
+		Ok(len as u64)
+	}))
+}
+
+fn sys_seek(args: &SyscallArgs) -> u64 {
+	errno::encode(files().and_then(|files| files.get(args.arg1)).and_then(|file| {
+		let base = match args.arg3 {
+			abi::SEEK_SET => 0,
+			abi::SEEK_CUR => file.offset,
+			abi::SEEK_END => file.inode.metadata().size,
+			_ => return Err(Errno::InvalidArgument),
+		};
+		let offset = base.checked_add_signed(args.arg2 as i64).ok_or(Errno::InvalidArgument)?;
+		file.offset = offset;
+		Ok(offset)
+	}))
+}
+
+fn sys_fstat(args: &SyscallArgs) -> u64 {
+	errno::encode(files().and_then(|files| files.get(args.arg1)).and_then(|file| {
+		uaccess::write_struct(args.arg2 as usize, &stat(file.inode.metadata()))?;
+		Ok(0)
+	}))
+}
+
+fn sys_stat(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|path| {
+		let metadata = vfs::resolve(&path)?.metadata();
+		uaccess::write_struct(args.arg3 as usize, &stat(metadata))?;
+		Ok(0)
+	}))
+}
+
+fn sys_rename(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|old| {
+		let new = uaccess::read_str(args.arg3 as usize, args.arg4 as usize)?;
+		vfs::rename(&old, &new).map(|()| 0)
+	}))
+}
+
+fn stat(metadata: Metadata) -> Stat {
+	Stat {
+		file_type: match metadata.file_type {
+			FileType::File => abi::STAT_FILE,
+			FileType::Directory => abi::STAT_DIRECTORY,
+		},
+		size: metadata.size,
+		created: metadata.created,
+		modified: metadata.modified,
+		accessed: metadata.accessed,
+	}
+}
+
+fn sys_sync(_args: &SyscallArgs) -> u64 {
+	errno::encode(vfs::sync().map(|_| 0))
+}
+
+fn sys_shutdown(_args: &SyscallArgs) -> u64 {
+	crate::shutdown();
+}
+
+fn sys_mount(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_str(args.arg1 as usize, args.arg2 as usize).and_then(|name| {
+		let path = uaccess::read_str(args.arg3 as usize, args.arg4 as usize)?;
+		disk::mount(&name, &path).map(|_| 0)
+	}))
+}
+
+fn sys_list_disks(args: &SyscallArgs) -> u64 {
+	// one `name sectors model` line per disk, lines that do not fit are left out
+	let mut listing = Vec::new();
+	for disk in disk::disks() {
+		let line = format!("{} {} {}\n", disk.name, disk.sectors, disk.model);
+		if listing.len() + line.len() > args.arg2 as usize {
+			break;
+		}
+		listing.extend_from_slice(line.as_bytes());
+	}
+	errno::encode(uaccess::copy_to_user(args.arg1 as usize, &listing).map(|()| listing.len() as u64))
+}
+
+fn sys_check(args: &SyscallArgs) -> u64 {
+	errno::encode(uaccess::read_struct::<CheckArgs>(args.arg1 as usize).and_then(|check| {
+		let name = uaccess::read_str(check.disk_ptr as usize, check.disk_len as usize)?;
+		let report = disk::check(&name, check.flags & abi::CHECK_REPAIR != 0)?;
+		// the summary is kept when not all problems fit
+		let summary = format!("{}\n", report.summary());
+		let mut output = Vec::new();
+		for problem in report.problems.iter() {
+			if output.len() + problem.len() + 1 + summary.len() > check.output_len as usize {
+				break;
+			}
+			output.extend_from_slice(problem.as_bytes());
+			output.push(b'\n');
+		}
+		output.extend_from_slice(summary.as_bytes());
+		output.truncate(check.output_len as usize);
+		uaccess::copy_to_user(check.output_ptr as usize, &output)?;
+		Ok(output.len() as u64)
+	}))
+}
@@ -0,0 +1,240 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+
+use crate::cpu;
+use crate::gdt;
+use crate::gui;
+use crate::memory;
+use crate::multitasking;
+use crate::process::{ Program, UserContext };
+
+pub const RESCHEDULE_VECTOR: u8 = 0x81;
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+static mut SCHEDULER: Scheduler = Scheduler::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// Threads are only switched while PREEMPT_COUNT is zero. A timer tick that arrives
+// while it is not sets NEED_RESCHED, and the switch happens in preempt_enable.
+static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+static DEAD_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub u64);
+
+impl ThreadId {
+	pub fn new() -> Self {
+		static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+		ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+	Ready,
+	Dead,
+}
+
+pub struct Thread {
+	pub id: ThreadId,
+	pub state: ThreadState,
+	rsp: usize,							// saved context while the thread does not run
+	kernel_stack: Option<Box<[u8]>>,	// None for the boot thread, which keeps its stack
+	pub program: Option<Box<Program>>,
+	pub terminal_id: Option<gui::NodeId>,
+}
+
+impl Thread {
+	fn kernel_stack_top(&self) -> Option<usize> {
+		self.kernel_stack.as_ref().map(|stack| (stack.as_ptr() as usize + stack.len()) & !0xF)
+	}
+}
+
+struct Scheduler {
+	threads: Vec<Thread>,
+	current: usize,
+}
+
+impl Scheduler {
+	const fn new() -> Scheduler {
+		Scheduler {
+			threads: Vec::new(),
+			current: 0,
+		}
+	}
+
+	// Round robin: the next ready thread after the current one
+	fn next_ready(&self) -> Option<usize> {
+		let count = self.threads.len();
+		(1..=count)
+			.map(|offset| (self.current + offset) % count)
+			.find(|&index| self.threads[index].state == ThreadState::Ready)
+	}
+}
+
+#[allow(static_mut_refs)]
+fn scheduler() -> &'static mut Scheduler {
+	unsafe { &mut SCHEDULER }
+}
+
+// Turns the code that calls it (the kernel executor) into thread 0 and starts switching
+pub fn start() {
+	interrupts::without_interrupts(|| {
+		let scheduler = scheduler();
+		scheduler.threads.insert(0, Thread {
+			id: ThreadId::new(),
+			state: ThreadState::Ready,
+			rsp: 0,
+			kernel_stack: None,
+			program: None,
+			terminal_id: None,
+		});
+		scheduler.current = 0;
+		STARTED.store(true, Ordering::SeqCst);
+	});
+}
+
+pub fn spawn_program(program: Program, terminal_id: Option<gui::NodeId>) -> ThreadId {
+	let context = program.initial_context();
+	let kernel_stack = vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice();
+
+	let mut thread = Thread {
+		id: ThreadId::new(),
+		state: ThreadState::Ready,
+		rsp: 0,
+		kernel_stack: Some(kernel_stack),
+		program: Some(Box::new(program)),
+		terminal_id,
+	};
+
+	// the first switch to the thread pops this context and irets into the program
+	let frame = thread.kernel_stack_top().unwrap() - size_of::<UserContext>();
+	unsafe { (frame as *mut UserContext).write(context) };
+	thread.rsp = frame;
+
+	let id = thread.id;
+	interrupts::without_interrupts(|| scheduler().threads.push(thread));
+	id
+}
+
+// Only meaningful with interrupts disabled, the thread may be switched otherwise
+pub fn current() -> Option<&'static mut Thread> {
+	if !STARTED.load(Ordering::SeqCst) {
+		return None;
+	}
+	let scheduler = scheduler();
+	scheduler.threads.get_mut(scheduler.current)
+}
+
+// Terminal of the running program, or of the running executor task
+pub fn current_terminal() -> Option<gui::NodeId> {
+	if let Some(terminal_id) = current().and_then(|thread| thread.terminal_id) {
+		return Some(terminal_id);
+	}
+
+	let executor = unsafe { &mut *multitasking::EXECUTOR_PTR };
+	executor.current_task
+		.and_then(|task_id| executor.tasks.get(&task_id))
+		.and_then(|task| task.terminal_id)
+}
+
+// Called by the switching trampolines with the stack pointer of the interrupted thread,
+// returns the stack pointer of the thread to resume
+pub fn schedule(rsp: usize) -> usize {
+	if !STARTED.load(Ordering::SeqCst) {
+		return rsp;
+	}
+	if PREEMPT_COUNT.load(Ordering::SeqCst) != 0 {
+		NEED_RESCHED.store(true, Ordering::SeqCst);
+		return rsp;
+	}
+	NEED_RESCHED.store(false, Ordering::SeqCst);
+
+	let scheduler = scheduler();
+	scheduler.threads[scheduler.current].rsp = rsp;
+	let next = match scheduler.next_ready() {
+		Some(next) => next,
+		None => return rsp,
+	};
+	scheduler.current = next;
+
+	let thread = &scheduler.threads[next];
+	if let Some(stack_top) = thread.kernel_stack_top() {
+		gdt::set_kernel_stack(VirtAddr::new(stack_top as u64));
+	}
+	let p4 = match &thread.program {
+		Some(program) => program.address_space.p4_address(),
+		None => memory::controller().kernel_p4,
+	};
+	if cpu::cr3() as usize != p4 {
+		unsafe { cpu::write_raw_cr3(p4 as u64) };
+	}
+
+	thread.rsp
+}
+
+// Gives the rest of the time slice to the other threads
+pub fn yield_now() {
+	unsafe { core::arch::asm!("int {}", const RESCHEDULE_VECTOR) };
+}
+
+// Ends the running thread, its memory is freed later by `reap`
+pub fn exit() -> ! {
+	interrupts::without_interrupts(|| {
+		let scheduler = scheduler();
+		scheduler.threads[scheduler.current].state = ThreadState::Dead;
+		DEAD_THREADS.fetch_add(1, Ordering::SeqCst);
+	});
+	loop {
+		yield_now();
+	}
+}
+
+// Waits for an interrupt, or lets the other threads run if some are ready
+pub fn idle() {
+	let others_ready = interrupts::without_interrupts(|| {
+		let scheduler = scheduler();
+		STARTED.load(Ordering::SeqCst) && scheduler.next_ready().is_some_and(|next| next != scheduler.current)
+	});
+	if others_ready {
+		yield_now();
+	} else {
+		cpu::hlt();
+	}
+}
+
+// Frees dead threads. Not done by the scheduler itself, as it runs in interrupt
+// context and may have interrupted a holder of the heap lock.
+pub fn reap() {
+	if DEAD_THREADS.load(Ordering::SeqCst) == 0 {
+		return;
+	}
+
+	let dead: Vec<Thread> = interrupts::without_interrupts(|| {
+		let scheduler = scheduler();
+		let current_id = scheduler.threads[scheduler.current].id;
+		let (dead, alive) = core::mem::take(&mut scheduler.threads)
+			.into_iter()
+			.partition(|thread| thread.state == ThreadState::Dead);
+		scheduler.threads = alive;
+		scheduler.current = scheduler.threads.iter().position(|thread| thread.id == current_id).unwrap();
+		DEAD_THREADS.store(0, Ordering::SeqCst);
+		dead
+	});
+	drop(dead);
+}
+
+pub fn preempt_disable() {
+	PREEMPT_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn preempt_enable() {
+	if PREEMPT_COUNT.fetch_sub(1, Ordering::SeqCst) == 1 && NEED_RESCHED.load(Ordering::SeqCst) {
+		yield_now();
+	}
+}
@@ -1,6 +1,9 @@
 use core::arch::asm;
 use x86::io::{inb, outb};
 use core::sync::atomic::{ AtomicU32 };
+use x86_64::instructions::interrupts;
+
+use abi::DateTime;
 
 pub const TICKS_PER_SEC: u32 = 18;
 pub const TICKS_PER_MIN: u32 = TICKS_PER_SEC * 60;
@@ -25,6 +28,13 @@ pub unsafe fn init() {
     YEAR    = rtc.year;
 }
 
+// The time kept by the timer interrupt, read in one piece
+pub fn now() -> DateTime {
+	interrupts::without_interrupts(|| unsafe {
+		DateTime { year: YEAR, month: MONTH, day: DAY, hour: HOURS, minute: MINUTES, second: SECONDS }
+	})
+}
+
 pub unsafe fn resync_from_cmos() {
 	init();
 }
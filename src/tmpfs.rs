@@ -0,0 +1,303 @@
+use alloc::collections::btree_map::{ BTreeMap, Entry };
+use alloc::string::{ String, ToString };
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+use spin::{ Mutex, MutexGuard };
+
+use abi::{ DateTime, Errno };
+use crate::thread;
+use crate::time;
+use crate::vfs::{ self, DirEntry, FileType, Inode, Metadata };
+
+// File system that keeps everything on the kernel heap and is gone after a reboot.
+// Names are case sensitive. A removed file lives on while it is still open.
+
+// Bytes the files of one file system may hold together
+const MAX_BYTES: usize = 16 * 1024 * 1024;
+
+// What an entry costs besides its name: the inode and its node in the directory
+const ENTRY_BYTES: usize = 128;
+
+const MAX_NAME_LEN: usize = 255;
+
+pub struct TmpFs {
+	root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+	pub fn new() -> Self {
+		let used = Arc::new(AtomicUsize::new(0));
+		TmpFs { root: TmpInode::new(FileType::Directory, used) }
+	}
+
+	// The directory at the path relative to the root
+	fn directory(&self, path: &str) -> Result<Arc<TmpInode>, Errno> {
+		let mut directory = self.root.clone();
+		for component in path.split('/').filter(|component| !component.is_empty()) {
+			directory = directory.child(component)?;
+		}
+		if directory.file_type != FileType::Directory {
+			return Err(Errno::NotADirectory);
+		}
+		Ok(directory)
+	}
+}
+
+impl vfs::FileSystem for TmpFs {
+	fn root(&self) -> Arc<dyn Inode> {
+		self.root.clone()
+	}
+
+	fn rename(&self, old: &str, new: &str) -> Result<(), Errno> {
+		let (old_parent, old_name) = old.rsplit_once('/').ok_or(Errno::InvalidArgument)?;
+		let (new_parent, new_name) = new.rsplit_once('/').ok_or(Errno::InvalidArgument)?;
+		check_name(new_name)?;
+		let old_parent = self.directory(old_parent)?;
+		let new_parent = self.directory(new_parent)?;
+
+		// the new name is charged while the move may still fail
+		self.root.charge(new_name.len())?;
+		let moved = if Arc::ptr_eq(&old_parent, &new_parent) {
+			let mut state = old_parent.lock();
+			move_entry(&mut state, old_name, None, new_name)
+		} else {
+			// directories are locked in the order of their addresses, so two renames
+			// between the same pair cannot wait for each other
+			let (mut old_state, mut new_state) = if Arc::as_ptr(&old_parent) < Arc::as_ptr(&new_parent) {
+				let old_state = old_parent.lock();
+				(old_state, new_parent.lock())
+			} else {
+				let new_state = new_parent.lock();
+				(old_parent.lock(), new_state)
+			};
+			move_entry(&mut old_state, old_name, Some(&mut new_state), new_name)
+		};
+		match moved {
+			Ok(()) => self.root.release(old_name.len()),
+			Err(e) => {
+				self.root.release(new_name.len());
+				return Err(e);
+			}
+		}
+
+		old_parent.touch();
+		new_parent.touch();
+		Ok(())
+	}
+}
+
+struct TmpInode {
+	file_type: FileType,
+	created: DateTime,
+	state: Mutex<State>,
+	// Bytes held by the files and entries of the file system
+	used: Arc<AtomicUsize>,
+}
+
+struct State {
+	// Content of a file, empty for a directory
+	data: Vec<u8>,
+	// Entries of a directory
+	children: BTreeMap<String, Arc<TmpInode>>,
+	modified: DateTime,
+	accessed: DateTime,
+}
+
+impl TmpInode {
+	fn new(file_type: FileType, used: Arc<AtomicUsize>) -> Arc<TmpInode> {
+		let now = time::now();
+		Arc::new(TmpInode {
+			file_type,
+			created: now,
+			state: Mutex::new(State { data: Vec::new(), children: BTreeMap::new(), modified: now, accessed: now }),
+			used,
+		})
+	}
+
+	// Gives the time to other threads while waiting
+	fn lock(&self) -> MutexGuard<'_, State> {
+		loop {
+			if let Some(guard) = self.state.try_lock() {
+				return guard;
+			}
+			thread::yield_now();
+		}
+	}
+
+	fn child(&self, name: &str) -> Result<Arc<TmpInode>, Errno> {
+		if self.file_type != FileType::Directory {
+			return Err(Errno::NotADirectory);
+		}
+		self.lock().children.get(name).cloned().ok_or(Errno::NotFound)
+	}
+
+	fn touch(&self) {
+		let now = time::now();
+		let mut state = self.lock();
+		state.modified = now;
+		state.accessed = now;
+	}
+
+	// Counts the bytes as used, if the file system may still hold them
+	fn charge(&self, bytes: usize) -> Result<(), Errno> {
+		self.used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+			used.checked_add(bytes).filter(|&total| total <= MAX_BYTES)
+		}).map(|_| ()).map_err(|_| Errno::NoSpace)
+	}
+
+	fn release(&self, bytes: usize) {
+		self.used.fetch_sub(bytes, Ordering::SeqCst);
+	}
+
+	// Sets the size of the file, within what the file system may still hold
+	fn resize(&self, state: &mut State, size: usize) -> Result<(), Errno> {
+		let old = state.data.len();
+		if size > old {
+			self.charge(size - old)?;
+		} else {
+			self.release(old - size);
+		}
+		state.data.resize(size, 0);
+		Ok(())
+	}
+}
+
+impl Drop for TmpInode {
+	fn drop(&mut self) {
+		let bytes = self.state.get_mut().data.len();
+		self.release(bytes);
+	}
+}
+
+// Moves an entry to `new_name` in the directory `to`, or in the same directory
+fn move_entry(from: &mut State, old_name: &str, to: Option<&mut State>, new_name: &str) -> Result<(), Errno> {
+	match to {
+		Some(to) => match to.children.entry(new_name.to_string()) {
+			Entry::Occupied(_) => Err(Errno::AlreadyExists),
+			Entry::Vacant(vacant) => {
+				vacant.insert(from.children.remove(old_name).ok_or(Errno::NotFound)?);
+				Ok(())
+			}
+		},
+		None => {
+			if from.children.contains_key(new_name) {
+				return Err(Errno::AlreadyExists);
+			}
+			let inode = from.children.remove(old_name).ok_or(Errno::NotFound)?;
+			from.children.insert(new_name.to_string(), inode);
+			Ok(())
+		}
+	}
+}
+
+fn check_name(name: &str) -> Result<(), Errno> {
+	if name.is_empty() || name == "." || name == ".." || name.contains(|c| c == '/' || c == '\0') {
+		return Err(Errno::InvalidArgument);
+	}
+	if name.len() > MAX_NAME_LEN {
+		return Err(Errno::NameTooLong);
+	}
+	Ok(())
+}
+
+impl Inode for TmpInode {
+	fn metadata(&self) -> Metadata {
+		let state = self.lock();
+		Metadata {
+			file_type: self.file_type,
+			size: state.data.len() as u64,
+			created: self.created,
+			modified: state.modified,
+			accessed: state.accessed,
+		}
+	}
+
+	fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+		Ok(self.child(name)?)
+	}
+
+	fn list(&self) -> Result<Vec<DirEntry>, Errno> {
+		if self.file_type != FileType::Directory {
+			return Err(Errno::NotADirectory);
+		}
+		Ok(self.lock().children.iter()
+			.map(|(name, inode)| DirEntry { name: name.clone(), file_type: inode.file_type })
+			.collect())
+	}
+
+	fn create(&self, name: &str, file_type: FileType) -> Result<(), Errno> {
+		if self.file_type != FileType::Directory {
+			return Err(Errno::NotADirectory);
+		}
+		check_name(name)?;
+		let mut state = self.lock();
+		if state.children.contains_key(name) {
+			return Err(Errno::AlreadyExists);
+		}
+		self.charge(ENTRY_BYTES + name.len())?;
+		state.children.insert(name.to_string(), TmpInode::new(file_type, self.used.clone()));
+		state.modified = time::now();
+		Ok(())
+	}
+
+	fn remove(&self, name: &str) -> Result<(), Errno> {
+		let child = self.child(name)?;
+		if !child.lock().children.is_empty() {
+			return Err(Errno::DirectoryNotEmpty);
+		}
+		let mut state = self.lock();
+		if state.children.remove(name).is_some() {
+			self.release(ENTRY_BYTES + name.len());
+		}
+		state.modified = time::now();
+		Ok(())
+	}
+
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+		if self.file_type == FileType::Directory {
+			return Err(Errno::IsADirectory);
+		}
+		let mut state = self.lock();
+		state.accessed = time::now();
+		let size = state.data.len() as u64;
+		if offset >= size {
+			return Ok(0);
+		}
+		let len = (size - offset).min(buf.len() as u64) as usize;
+		buf[..len].copy_from_slice(&state.data[offset as usize..offset as usize + len]);
+		Ok(len)
+	}
+
+	fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+		if self.file_type == FileType::Directory {
+			return Err(Errno::IsADirectory);
+		}
+		if data.is_empty() {
+			return Ok(0);
+		}
+		let end = offset.checked_add(data.len() as u64).filter(|&end| end <= MAX_BYTES as u64).ok_or(Errno::FileTooLarge)? as usize;
+		let mut state = self.lock();
+		if end > state.data.len() {
+			self.resize(&mut state, end)?;
+		}
+		state.data[offset as usize..end].copy_from_slice(data);
+		let now = time::now();
+		state.modified = now;
+		state.accessed = now;
+		Ok(data.len())
+	}
+
+	fn truncate(&self, size: u64) -> Result<(), Errno> {
+		if self.file_type == FileType::Directory {
+			return Err(Errno::IsADirectory);
+		}
+		if size > MAX_BYTES as u64 {
+			return Err(Errno::FileTooLarge);
+		}
+		let mut state = self.lock();
+		self.resize(&mut state, size as usize)?;
+		state.modified = time::now();
+		Ok(())
+	}
+}
@@ -0,0 +1,70 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use abi::Errno;
+use crate::memory::{ self, PAGE_SIZE };
+use crate::process;
+
+// Syscalls get raw addresses from the program. Before the kernel touches them they are
+// checked against the address space of the running program: the whole range has to be
+// in the user space and mapped, and writable if the kernel writes to it.
+fn check_range(address: usize, len: usize, write: bool) -> Result<(), Errno> {
+	let program = process::current().ok_or(Errno::BadAddress)?;
+	if len == 0 {
+		return Ok(());
+	}
+
+	let end = address.checked_add(len).ok_or(Errno::BadAddress)?;
+	if address < memory::USER_SPACE_START || end > memory::USER_SPACE_END {
+		return Err(Errno::BadAddress);
+	}
+
+	let mut page = address & !(PAGE_SIZE - 1);
+	while page < end {
+		let accessible = if write {
+			program.address_space.is_writable(page)
+		} else {
+			program.address_space.is_mapped(page)
+		};
+		if !accessible {
+			return Err(Errno::BadAddress);
+		}
+		page += PAGE_SIZE;
+	}
+	Ok(())
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Errno> {
+	check_range(src, dst.len(), false)?;
+	unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+	Ok(())
+}
+
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Errno> {
+	check_range(dst, src.len(), true)?;
+	unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+	Ok(())
+}
+
+pub fn read_bytes(src: usize, len: usize) -> Result<Vec<u8>, Errno> {
+	check_range(src, len, false)?;
+	let mut data = vec![0u8; len];
+	copy_from_user(&mut data, src)?;
+	Ok(data)
+}
+
+pub fn read_str(src: usize, len: usize) -> Result<String, Errno> {
+	String::from_utf8(read_bytes(src, len)?).map_err(|_| Errno::InvalidArgument)
+}
+
+// Reads one of the argument structs of the ABI crate, which are plain integers only
+pub fn read_struct<T: Copy>(src: usize) -> Result<T, Errno> {
+	check_range(src, core::mem::size_of::<T>(), false)?;
+	Ok(unsafe { core::ptr::read_unaligned(src as *const T) })
+}
+
+pub fn write_struct<T: Copy>(dst: usize, value: &T) -> Result<(), Errno> {
+	check_range(dst, core::mem::size_of::<T>(), true)?;
+	unsafe { core::ptr::write_unaligned(dst as *mut T, *value) };
+	Ok(())
+}
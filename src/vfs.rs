@@ -0,0 +1,415 @@
+use alloc::string::{ String, ToString };
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{ Mutex, MutexGuard };
+
+use abi::{ DateTime, Errno };
+use abi::{ OPEN_APPEND, OPEN_CREATE, OPEN_TRUNCATE, OPEN_WRITE };
+use crate::thread;
+
+// File systems are mounted at absolute paths. A path belongs to the mount with the
+// longest matching prefix and is resolved from its root one component at a time,
+// after `.` and `..` were taken out of it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+	File,
+	Directory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+	pub file_type: FileType,
+	pub size: u64,
+	// As far as the file system keeps them, unknown ones are zero
+	pub created: DateTime,
+	pub modified: DateTime,
+	pub accessed: DateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+	pub name: String,
+	pub file_type: FileType,
+}
+
+// A file or directory of a mounted file system
+pub trait Inode {
+	fn metadata(&self) -> Metadata;
+
+	// The entry `name` of this directory
+	fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno>;
+
+	// Entries of this directory without `.` and `..`
+	fn list(&self) -> Result<Vec<DirEntry>, Errno>;
+
+	fn create(&self, name: &str, file_type: FileType) -> Result<(), Errno>;
+
+	// Removes a file or an empty directory of this directory
+	fn remove(&self, name: &str) -> Result<(), Errno>;
+
+	// Reads from `offset` of this file, returns the number of bytes read
+	fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno>;
+
+	// Writes at `offset` of this file, which grows as needed
+	fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Errno>;
+
+	fn truncate(&self, size: u64) -> Result<(), Errno>;
+}
+
+pub trait FileSystem {
+	fn root(&self) -> Arc<dyn Inode>;
+
+	// Writes what is still only in memory to the underlying device
+	fn sync(&self) -> Result<(), Errno> {
+		Ok(())
+	}
+
+	// Syncs and leaves the file system in the state of one that is not mounted
+	fn unmount(&self) -> Result<(), Errno> {
+		self.sync()
+	}
+
+	// Moves the entry at `old` to `new`, both relative to the root of the file system.
+	// The parent of `new` is a directory. `new` was found not to exist, unless it only
+	// differs from `old` in case, which may be the same entry on some file systems.
+	fn rename(&self, _old: &str, _new: &str) -> Result<(), Errno> {
+		Err(Errno::NotSupported)
+	}
+}
+
+#[derive(Clone)]
+struct Mount {
+	path: String,
+	fs: Arc<dyn FileSystem>,
+}
+
+// The file systems are not Send, they are only ever used from threads of this CPU
+struct Mounts(Vec<Mount>);
+
+unsafe impl Send for Mounts {}
+
+static MOUNTS: Mutex<Mounts> = Mutex::new(Mounts(Vec::new()));
+
+// Syscalls are preempted by the timer and block on disks, so the table is locked. It is
+// only held to read or change the table, never while a file system is used.
+fn mounts() -> MutexGuard<'static, Mounts> {
+	loop {
+		if let Some(guard) = MOUNTS.try_lock() {
+			return guard;
+		}
+		thread::yield_now();
+	}
+}
+
+impl core::ops::Deref for Mounts {
+	type Target = Vec<Mount>;
+
+	fn deref(&self) -> &Vec<Mount> {
+		&self.0
+	}
+}
+
+impl core::ops::DerefMut for Mounts {
+	fn deref_mut(&mut self) -> &mut Vec<Mount> {
+		&mut self.0
+	}
+}
+
+// Checks that a file system can be mounted at the path: the root while nothing is
+// mounted there, otherwise a directory nothing is mounted on yet. Returns the path
+// normalized.
+pub fn check_mount_point(path: &str) -> Result<String, Errno> {
+	let path = normalize(path)?;
+	if mounts().iter().any(|mount| mount.path == path) {
+		return Err(Errno::Busy);
+	}
+	if path != "/" && resolve(&path)?.metadata().file_type != FileType::Directory {
+		return Err(Errno::NotADirectory);
+	}
+	Ok(path)
+}
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), Errno> {
+	let path = check_mount_point(path)?;
+	// the table was not locked while the directory was looked up
+	let mut mounts = mounts();
+	if mounts.iter().any(|mount| mount.path == path) {
+		return Err(Errno::Busy);
+	}
+	mounts.push(Mount { path, fs });
+	Ok(())
+}
+
+// Syncs every mounted file system, the first error is returned once all were tried
+pub fn sync() -> Result<(), Errno> {
+	let mounted: Vec<Mount> = mounts().to_vec();
+	let mut result = Ok(());
+	for mount in mounted {
+		let synced = mount.fs.sync();
+		if result.is_ok() {
+			result = synced;
+		}
+	}
+	result
+}
+
+// Unmounts every file system, for turning the machine off. The first error is
+// returned once all were tried.
+pub fn unmount_all() -> Result<(), Errno> {
+	let mounted: Vec<Mount> = mounts().drain(..).collect();
+	let mut result = Ok(());
+	for mount in mounted {
+		let unmounted = mount.fs.unmount();
+		if result.is_ok() {
+			result = unmounted;
+		}
+	}
+	result
+}
+
+// Absolute path without `.`, `..`, empty components and trailing slash
+pub fn normalize(path: &str) -> Result<String, Errno> {
+	if !path.starts_with('/') {
+		return Err(Errno::InvalidArgument);
+	}
+	let mut components: Vec<&str> = Vec::new();
+	for component in path.split('/') {
+		match component {
+			"" | "." => {}
+			".." => { components.pop(); }
+			component => components.push(component),
+		}
+	}
+	let mut normalized = String::new();
+	for component in components {
+		normalized.push('/');
+		normalized.push_str(component);
+	}
+	if normalized.is_empty() {
+		normalized.push('/');
+	}
+	Ok(normalized)
+}
+
+fn is_under(path: &str, mount_path: &str) -> bool {
+	mount_path == "/" || path == mount_path || path.strip_prefix(mount_path).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// Mount a normalized path is on and the path relative to its root
+fn find_mount(path: &str) -> Result<(Mount, &str), Errno> {
+	let mount = mounts().iter()
+		.filter(|mount| is_under(path, &mount.path))
+		.max_by_key(|mount| mount.path.len())
+		.cloned()
+		.ok_or(Errno::NotFound)?;
+	let relative = if mount.path == "/" { path } else { &path[mount.path.len()..] };
+	Ok((mount, relative))
+}
+
+fn is_mount_point(path: &str) -> bool {
+	mounts().iter().any(|mount| mount.path == path)
+}
+
+pub fn resolve(path: &str) -> Result<Arc<dyn Inode>, Errno> {
+	let path = normalize(path)?;
+	let (mount, relative) = find_mount(&path)?;
+	let mut inode = mount.fs.root();
+	for component in relative.split('/').filter(|component| !component.is_empty()) {
+		if inode.metadata().file_type != FileType::Directory {
+			return Err(Errno::NotADirectory);
+		}
+		inode = inode.lookup(component)?;
+	}
+	Ok(inode)
+}
+
+// Directory the entry at the path is in, and the name of the entry
+fn resolve_parent(path: &str) -> Result<(Arc<dyn Inode>, String), Errno> {
+	let path = normalize(path)?;
+	if is_mount_point(&path) {
+		return Err(Errno::Busy);
+	}
+	let (parent, name) = path.rsplit_once('/').unwrap();
+	let parent = resolve(if parent.is_empty() { "/" } else { parent })?;
+	if parent.metadata().file_type != FileType::Directory {
+		return Err(Errno::NotADirectory);
+	}
+	Ok((parent, name.to_string()))
+}
+
+pub fn exists(path: &str) -> bool {
+	resolve(path).is_ok()
+}
+
+// Entries of the directory, including the file systems mounted in it
+pub fn list_dir(path: &str) -> Result<Vec<DirEntry>, Errno> {
+	let path = normalize(path)?;
+	let inode = resolve(&path)?;
+	if inode.metadata().file_type != FileType::Directory {
+		return Err(Errno::NotADirectory);
+	}
+
+	let mut entries = inode.list()?;
+	for mount in mounts().iter() {
+		let name = match mount.path.rsplit_once('/') {
+			Some((parent, name)) if !name.is_empty() && (parent == path || (parent.is_empty() && path == "/")) => name,
+			_ => continue,
+		};
+		if !entries.iter().any(|entry| entry.name == name) {
+			entries.push(DirEntry { name: name.to_string(), file_type: FileType::Directory });
+		}
+	}
+	Ok(entries)
+}
+
+fn create(path: &str, file_type: FileType) -> Result<(), Errno> {
+	let (parent, name) = resolve_parent(path)?;
+	if parent.lookup(&name).is_ok() {
+		return Err(Errno::AlreadyExists);
+	}
+	parent.create(&name, file_type)
+}
+
+pub fn create_dir(path: &str) -> Result<(), Errno> {
+	create(path, FileType::Directory)
+}
+
+pub fn create_file(path: &str) -> Result<(), Errno> {
+	create(path, FileType::File)
+}
+
+fn remove(path: &str, file_type: FileType) -> Result<(), Errno> {
+	let (parent, name) = resolve_parent(path)?;
+	// the inode of the entry is gone before it is removed, file systems may refuse to
+	// remove entries that have one
+	let found = parent.lookup(&name)?.metadata().file_type;
+	match (found, file_type) {
+		(FileType::Directory, FileType::File) => Err(Errno::IsADirectory),
+		(FileType::File, FileType::Directory) => Err(Errno::NotADirectory),
+		_ => parent.remove(&name),
+	}
+}
+
+pub fn remove_dir(path: &str) -> Result<(), Errno> {
+	remove(path, FileType::Directory)
+}
+
+pub fn remove_file(path: &str) -> Result<(), Errno> {
+	remove(path, FileType::File)
+}
+
+// Moves a file or directory within its file system. Nothing is replaced, the new path
+// must not exist.
+pub fn rename(old: &str, new: &str) -> Result<(), Errno> {
+	let old = normalize(old)?;
+	let new = normalize(new)?;
+	// mount points and what has a file system mounted in it stay where they are
+	if mounts().iter().any(|mount| is_under(&mount.path, &old)) {
+		return Err(Errno::Busy);
+	}
+	resolve(&old)?;
+	if old == new {
+		return Ok(());
+	}
+	if is_under(&new, &old) {
+		return Err(Errno::InvalidArgument);
+	}
+	let (parent, name) = resolve_parent(&new)?;
+	if parent.lookup(&name).is_ok() && !new.eq_ignore_ascii_case(&old) {
+		return Err(Errno::AlreadyExists);
+	}
+
+	let (old_mount, old_relative) = find_mount(&old)?;
+	let (new_mount, new_relative) = find_mount(&new)?;
+	if old_mount.path != new_mount.path {
+		return Err(Errno::CrossDevice);
+	}
+	old_mount.fs.rename(old_relative, new_relative)
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, Errno> {
+	let inode = resolve(path)?;
+	let metadata = inode.metadata();
+	if metadata.file_type == FileType::Directory {
+		return Err(Errno::IsADirectory);
+	}
+	let mut data = vec![0u8; metadata.size as usize];
+	let len = inode.read_at(0, &mut data)?;
+	data.truncate(len);
+	Ok(data)
+}
+
+// Replaces the content of the file
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), Errno> {
+	let inode = resolve(path)?;
+	if inode.metadata().file_type == FileType::Directory {
+		return Err(Errno::IsADirectory);
+	}
+	inode.truncate(0)?;
+	inode.write_at(0, data)?;
+	Ok(())
+}
+
+pub type Fd = u64;
+
+const MAX_OPEN_FILES: usize = 64;
+
+// A file opened by a program, with the offset its reads and writes continue at
+pub struct OpenFile {
+	pub inode: Arc<dyn Inode>,
+	pub offset: u64,
+	pub flags: u64,
+}
+
+// Files opened by a program, a descriptor is the index into it
+pub struct FileTable {
+	files: Vec<Option<OpenFile>>,
+}
+
+impl FileTable {
+	pub fn new() -> FileTable {
+		FileTable { files: Vec::new() }
+	}
+
+	// Stores the file under the lowest free descriptor
+	pub fn insert(&mut self, file: OpenFile) -> Result<Fd, Errno> {
+		if let Some(fd) = self.files.iter().position(|slot| slot.is_none()) {
+			self.files[fd] = Some(file);
+			return Ok(fd as Fd);
+		}
+		if self.files.len() == MAX_OPEN_FILES {
+			return Err(Errno::TooManyOpenFiles);
+		}
+		self.files.push(Some(file));
+		Ok((self.files.len() - 1) as Fd)
+	}
+
+	pub fn get(&mut self, fd: Fd) -> Result<&mut OpenFile, Errno> {
+		self.files.get_mut(fd as usize).and_then(|slot| slot.as_mut()).ok_or(Errno::BadFileDescriptor)
+	}
+
+	pub fn remove(&mut self, fd: Fd) -> Result<OpenFile, Errno> {
+		self.files.get_mut(fd as usize).and_then(|slot| slot.take()).ok_or(Errno::BadFileDescriptor)
+	}
+}
+
+// Opens the file with the `OPEN_*` flags of the ABI crate
+pub fn open(path: &str, flags: u64) -> Result<OpenFile, Errno> {
+	let inode = match resolve(path) {
+		Err(Errno::NotFound) if flags & OPEN_CREATE != 0 => {
+			create_file(path)?;
+			resolve(path)?
+		}
+		result => result?,
+	};
+
+	let write = flags & (OPEN_WRITE | OPEN_APPEND) != 0;
+	if write && inode.metadata().file_type == FileType::Directory {
+		return Err(Errno::IsADirectory);
+	}
+	if write && flags & OPEN_TRUNCATE != 0 {
+		inode.truncate(0)?;
+	}
+	Ok(OpenFile { inode, offset: 0, flags })
+}
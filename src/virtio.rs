@@ -0,0 +1,172 @@
+use alloc::vec::Vec;
+use core::ptr::{ addr_of_mut, NonNull };
+use core::sync::atomic::{ fence, AtomicUsize, Ordering };
+
+use crate::memory;
+use crate::pci::VirtioTransport;
+
+// Split virtqueues, shared by the virtio drivers. A queue is three rings in one
+// physical range: the descriptor table, the available ring the driver puts chains of
+// descriptors on and the used ring the device returns them in. The used ring starts on
+// a page boundary, which is what legacy devices expect.
+
+// Entries of a queue at most, less than QEMU's devices offer when they are modern.
+// Legacy devices have to be given the size they ask for.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+// Queues of all devices together
+const MAX_QUEUES: usize = 8;
+
+// 256 descriptors and the available ring fill two pages, the used ring the third
+const QUEUE_PAGES: usize = 3;
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+#[repr(C)]
+struct Descriptor {
+	address: u64,
+	length: u32,
+	flags: u16,
+	next: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+	id: u32,
+	length: u32,
+}
+
+#[repr(C, align(4096))]
+struct QueueMemory([u8; QUEUE_PAGES * 4096]);
+
+static mut QUEUE_MEMORY: [QueueMemory; MAX_QUEUES] = [const { QueueMemory([0; QUEUE_PAGES * 4096]) }; MAX_QUEUES];
+static QUEUES_USED: AtomicUsize = AtomicUsize::new(0);
+
+// A buffer of a chain, by physical address. `writable` ones are written by the device.
+pub struct Buffer {
+	pub address: u64,
+	pub length: u32,
+	pub writable: bool,
+}
+
+pub struct Virtqueue {
+	index: u16,
+	size: u16,
+	descriptors: NonNull<Descriptor>,
+	// flags, index, ring of `size` descriptor heads, used event
+	available: NonNull<u16>,
+	// flags and index, then the ring
+	used: NonNull<u16>,
+	free: Vec<u16>,
+	next_available: u16,
+	last_used: u16,
+}
+
+// Only touched by the driver holding the queue
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+	// Sets up queue `index` of the device, before the driver tells it it is ready
+	pub fn new(transport: &VirtioTransport, index: u16) -> Result<Virtqueue, &'static str> {
+		let max_size = transport.queue_max_size(index);
+		if max_size == 0 {
+			return Err("The device has no such queue");
+		}
+		let size = max_size.min(MAX_QUEUE_SIZE);
+		let slot = QUEUES_USED.fetch_add(1, Ordering::SeqCst);
+		if slot >= MAX_QUEUES {
+			return Err("Out of virtqueues");
+		}
+
+		let base = unsafe { addr_of_mut!(QUEUE_MEMORY[slot].0) } as *mut u8;
+		let physical = physical_address(base as usize).ok_or("The virtqueue is not mapped")?;
+		let available_offset = size as usize * 16;
+		let used_offset = (available_offset + 6 + size as usize * 2).next_multiple_of(4096);
+		transport.set_queue(index, size, physical, physical + available_offset as u64, physical + used_offset as u64)?;
+
+		let free = (0..size).rev().collect();
+		unsafe {
+			Ok(Virtqueue {
+				index,
+				size,
+				descriptors: NonNull::new_unchecked(base as *mut Descriptor),
+				available: NonNull::new_unchecked(base.add(available_offset) as *mut u16),
+				used: NonNull::new_unchecked(base.add(used_offset) as *mut u16),
+				free,
+				next_available: 0,
+				last_used: 0,
+			})
+		}
+	}
+
+	// Puts the buffers on the available ring as one chain and returns its head, None
+	// when there are not enough free descriptors. The device only sees it once notified.
+	pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+		if buffers.is_empty() || buffers.len() > self.free.len() {
+			return None;
+		}
+
+		let chain: Vec<u16> = (0..buffers.len()).map(|_| self.free.pop().unwrap()).collect();
+		for (position, buffer) in buffers.iter().enumerate() {
+			let mut flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+			let next = match chain.get(position + 1) {
+				Some(&next) => {
+					flags |= DESCRIPTOR_NEXT;
+					next
+				}
+				None => 0,
+			};
+			let descriptor = Descriptor { address: buffer.address, length: buffer.length, flags, next };
+			unsafe { self.descriptors.as_ptr().add(chain[position] as usize).write_volatile(descriptor) };
+		}
+
+		let head = chain[0];
+		let ring = self.available.as_ptr();
+		unsafe {
+			ring.add(2 + (self.next_available % self.size) as usize).write_volatile(head);
+			// the entry has to be there before the device sees the new index
+			fence(Ordering::SeqCst);
+			self.next_available = self.next_available.wrapping_add(1);
+			ring.add(1).write_volatile(self.next_available);
+		}
+		fence(Ordering::SeqCst);
+		Some(head)
+	}
+
+	pub fn notify(&self, transport: &VirtioTransport) {
+		transport.notify(self.index);
+	}
+
+	// Next chain the device is done with: its head and the number of bytes written to
+	// it. Its descriptors are free again.
+	pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+		let ring = self.used.as_ptr();
+		let device_index = unsafe { ring.add(1).read_volatile() };
+		if device_index == self.last_used {
+			return None;
+		}
+		fence(Ordering::SeqCst);
+
+		let slot = (self.last_used % self.size) as usize;
+		let element = unsafe { (ring.add(2) as *const UsedElement).add(slot).read_volatile() };
+		self.last_used = self.last_used.wrapping_add(1);
+
+		let head = element.id as u16;
+		let mut descriptor = head;
+		loop {
+			self.free.push(descriptor);
+			let entry = unsafe { self.descriptors.as_ptr().add(descriptor as usize).read_volatile() };
+			if entry.flags & DESCRIPTOR_NEXT == 0 {
+				break;
+			}
+			descriptor = entry.next;
+		}
+		Some((head, element.length))
+	}
+}
+
+// Physical address of kernel memory, for handing it to a device
+pub fn physical_address(address: usize) -> Option<u64> {
+	memory::controller().active_table.translate(address).map(|address| address as u64)
+}
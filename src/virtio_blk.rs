@@ -0,0 +1,336 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::{ addr_of, addr_of_mut };
+use core::sync::atomic::{ AtomicBool, Ordering };
+use core::task::{ Context, Poll };
+use futures_util::task::AtomicWaker;
+use spin::{ Mutex, Once };
+
+use abi::Errno;
+use crate::fat32::{ AsyncBlockDevice, BlockDevice, BlockFuture };
+use crate::interrupts::set_irq_handler;
+use crate::multitasking;
+use crate::pci::{ self, VirtioTransport };
+use crate::println;
+use crate::virtio::{ self, Buffer, Virtqueue };
+
+// Driver of virtio block devices (virtio-blk-pci). A request is a chain of a header,
+// the data and a status byte the device writes, on the single queue of the device.
+// Each device has one request in flight at a time. Completion is signalled by the
+// interrupt of the device when it has a line the IDT has a stub for, otherwise the
+// used ring is polled.
+
+const DEVICE_TYPE_BLOCK: u16 = 2;
+
+// Feature bits
+const BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_T_FLUSH: u32 = 4;
+
+const BLK_S_OK: u8 = 0;
+
+// Configuration: 0-7 capacity in 512 byte sectors
+const CONFIG_CAPACITY: usize = 0;
+
+// Devices driven
+const MAX_DEVICES: usize = 4;
+
+// Sectors moved by one request, also the size of the DMA buffers
+const MAX_SECTORS: usize = 64;
+
+#[repr(C)]
+struct RequestHeader {
+	kind: u32,
+	reserved: u32,
+	sector: u64,
+}
+
+// What a request hands to the device, one per device
+#[repr(C, align(4096))]
+struct RequestMemory {
+	data: [u8; MAX_SECTORS * 512],
+	header: RequestHeader,
+	status: u8,
+}
+
+static mut REQUEST_MEMORY: [RequestMemory; MAX_DEVICES] = [const { RequestMemory {
+	data: [0; MAX_SECTORS * 512],
+	header: RequestHeader { kind: 0, reserved: 0, sector: 0 },
+	status: 0,
+} }; MAX_DEVICES];
+
+// What the IRQ handler and the requests of a device share
+struct DeviceState {
+	transport: Once<VirtioTransport>,
+	queue: Mutex<Option<Virtqueue>>,
+	irq_received: AtomicBool,
+	waker: AtomicWaker,
+	// Held by the request using the device, its queue and its DMA memory
+	busy: AtomicBool,
+	flush: AtomicBool,
+}
+
+impl DeviceState {
+	const fn new() -> Self {
+		DeviceState {
+			transport: Once::new(),
+			queue: Mutex::new(None),
+			irq_received: AtomicBool::new(false),
+			waker: AtomicWaker::new(),
+			busy: AtomicBool::new(false),
+			flush: AtomicBool::new(false),
+		}
+	}
+}
+
+static DEVICES: [DeviceState; MAX_DEVICES] = [const { DeviceState::new() }; MAX_DEVICES];
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+// A device found on the PCI bus
+pub struct Drive {
+	pub device: VirtioBlkDevice,
+	pub model: String,
+	pub sectors: u64,
+}
+
+#[derive(Copy, Clone)]
+pub struct VirtioBlkDevice {
+	index: usize,
+}
+
+// Sets up every virtio block device
+pub fn detect() -> Vec<Drive> {
+	let mut drives = Vec::new();
+	let mut lines = Vec::new();
+	for (dev, transport) in pci::find_virtio(DEVICE_TYPE_BLOCK) {
+		if drives.len() == MAX_DEVICES {
+			println!("virtio-blk: only {} devices are supported", MAX_DEVICES);
+			break;
+		}
+		let index = drives.len();
+		match init(index, &transport) {
+			Ok(()) => {
+				lines.push(dev.read_u8(0x3C));
+				drives.push(Drive {
+					device: VirtioBlkDevice { index },
+					model: String::from(if transport.is_legacy() { "VirtIO block device (legacy)" } else { "VirtIO block device" }),
+					sectors: transport.config_u64(CONFIG_CAPACITY),
+				});
+			}
+			Err(e) => {
+				transport.fail();
+				println!("virtio-blk: {}", e);
+			}
+		}
+	}
+
+	// one handler serves every device, they may share a line
+	if !drives.is_empty() && lines.iter().all(|line| matches!(line, 9 | 10 | 11)) {
+		INTERRUPTS.store(true, Ordering::SeqCst);
+		lines.sort();
+		lines.dedup();
+		for &line in lines.iter() {
+			set_irq_handler(line, handle_irq);
+		}
+	} else if !drives.is_empty() {
+		println!("virtio-blk: no usable interrupt line, polling the disks");
+	}
+	drives
+}
+
+fn init(index: usize, transport: &VirtioTransport) -> Result<(), &'static str> {
+	transport.reset();
+	let features = transport.negotiate(BLK_F_FLUSH)?;
+	let queue = Virtqueue::new(transport, 0)?;
+	let state = &DEVICES[index];
+	state.transport.call_once(|| *transport);
+	*state.queue.lock() = Some(queue);
+	state.flush.store(features & BLK_F_FLUSH != 0, Ordering::SeqCst);
+	transport.driver_ok();
+	Ok(())
+}
+
+impl VirtioBlkDevice {
+	// Name in the style of Linux: vda, vdb, ...
+	pub fn name(&self) -> String {
+		format!("vd{}", (b'a' + self.index as u8) as char)
+	}
+
+	pub async fn read(&self, lba: u32, buf: &mut [u8]) -> Result<(), Errno> {
+		if buf.len() % 512 != 0 {
+			return Err(Errno::InvalidArgument);
+		}
+		let _lock = DeviceLock::acquire(self.index).await;
+		for (index, chunk) in buf.chunks_mut(MAX_SECTORS * 512).enumerate() {
+			let lba = lba as u64 + (index * MAX_SECTORS) as u64;
+			self.request(BLK_T_IN, lba, chunk.len()).await?;
+			chunk.copy_from_slice(&self.memory().data[..chunk.len()]);
+		}
+		Ok(())
+	}
+
+	pub async fn write(&self, lba: u32, buf: &[u8]) -> Result<(), Errno> {
+		if buf.len() % 512 != 0 {
+			return Err(Errno::InvalidArgument);
+		}
+		let _lock = DeviceLock::acquire(self.index).await;
+		for (index, chunk) in buf.chunks(MAX_SECTORS * 512).enumerate() {
+			let lba = lba as u64 + (index * MAX_SECTORS) as u64;
+			self.memory().data[..chunk.len()].copy_from_slice(chunk);
+			self.request(BLK_T_OUT, lba, chunk.len()).await?;
+		}
+		Ok(())
+	}
+
+	// Has the device write its cache to the disk, if it has one it tells about
+	pub async fn flush(&self) -> Result<(), Errno> {
+		if !DEVICES[self.index].flush.load(Ordering::SeqCst) {
+			return Ok(());
+		}
+		let _lock = DeviceLock::acquire(self.index).await;
+		self.request(BLK_T_FLUSH, 0, 0).await
+	}
+
+	fn memory(&self) -> &'static mut RequestMemory {
+		unsafe { &mut *addr_of_mut!(REQUEST_MEMORY[self.index]) }
+	}
+
+	// Sends a request for `length` bytes of the data buffer and waits for it
+	async fn request(&self, kind: u32, sector: u64, length: usize) -> Result<(), Errno> {
+		let state = &DEVICES[self.index];
+		let transport = state.transport.get().ok_or(Errno::IoError)?;
+		let memory = self.memory();
+		memory.header = RequestHeader { kind, reserved: 0, sector };
+		memory.status = 0xFF;
+
+		let physical = |address: *const u8| virtio::physical_address(address as usize).ok_or(Errno::BadAddress);
+		let header = physical(addr_of!(memory.header) as *const u8)?;
+		let data = physical(memory.data.as_ptr())?;
+		let status = physical(addr_of!(memory.status))?;
+		let mut buffers = Vec::new();
+		buffers.push(Buffer { address: header, length: size_of::<RequestHeader>() as u32, writable: false });
+		if length > 0 {
+			buffers.push(Buffer { address: data, length: length as u32, writable: kind == BLK_T_IN });
+		}
+		buffers.push(Buffer { address: status, length: 1, writable: true });
+
+		state.irq_received.store(false, Ordering::SeqCst);
+		{
+			let mut queue = state.queue.lock();
+			let queue = queue.as_mut().ok_or(Errno::IoError)?;
+			queue.add(&buffers).ok_or(Errno::Busy)?;
+			queue.notify(transport);
+		}
+
+		loop {
+			if state.queue.lock().as_mut().and_then(|queue| queue.pop_used()).is_some() {
+				break;
+			}
+			if INTERRUPTS.load(Ordering::SeqCst) {
+				Irq(self.index).await;
+			} else {
+				core::hint::spin_loop();
+			}
+		}
+
+		let status = unsafe { addr_of!(memory.status).read_volatile() };
+		if status != BLK_S_OK {
+			println!("{}: request {} at sector {} failed with status {}", self.name(), kind, sector, status);
+			return Err(Errno::IoError);
+		}
+		Ok(())
+	}
+}
+
+impl BlockDevice for VirtioBlkDevice {
+	fn read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) {
+		if let Err(e) = self.try_read_sector(lba, buf) {
+			panic!("{}: read of sector {}: {}", self.name(), lba, e);
+		}
+	}
+
+	fn try_read_sector(&mut self, lba: u32, buf: &mut [u8; 512]) -> Result<(), Errno> {
+		multitasking::block_on(self.read(lba, &mut buf[..]))
+	}
+
+	fn write_sector(&mut self, lba: u32, buf: &[u8; 512]) {
+		if let Err(e) = multitasking::block_on(self.write(lba, &buf[..])) {
+			panic!("{}: write of sector {}: {}", self.name(), lba, e);
+		}
+	}
+
+	fn flush(&mut self) {
+		if let Err(e) = multitasking::block_on(VirtioBlkDevice::flush(self)) {
+			panic!("{}: flush: {}", self.name(), e);
+		}
+	}
+}
+
+impl AsyncBlockDevice for VirtioBlkDevice {
+	fn read_sectors<'a>(&'a self, lba: u32, buf: &'a mut [u8]) -> BlockFuture<'a> {
+		Box::pin(self.read(lba, buf))
+	}
+
+	fn write_sectors<'a>(&'a self, lba: u32, buf: &'a [u8]) -> BlockFuture<'a> {
+		Box::pin(self.write(lba, buf))
+	}
+}
+
+// Reading the interrupt status acknowledges it, so every device is asked
+fn handle_irq() {
+	for state in DEVICES.iter() {
+		if let Some(transport) = state.transport.get() {
+			if transport.isr() & 1 != 0 {
+				state.irq_received.store(true, Ordering::SeqCst);
+				state.waker.wake();
+			}
+		}
+	}
+}
+
+// Exclusive use of a device, released when dropped
+struct DeviceLock(usize);
+
+impl DeviceLock {
+	async fn acquire(index: usize) -> DeviceLock {
+		while DEVICES[index].busy.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+			multitasking::cooperate().await;
+		}
+		DeviceLock(index)
+	}
+}
+
+impl Drop for DeviceLock {
+	fn drop(&mut self) {
+		DEVICES[self.0].busy.store(false, Ordering::SeqCst);
+	}
+}
+
+// Resolves once the device interrupted
+struct Irq(usize);
+
+impl Future for Irq {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+		let state = &DEVICES[self.0];
+		if state.irq_received.swap(false, Ordering::SeqCst) {
+			return Poll::Ready(());
+		}
+
+		state.waker.register(&cx.waker());
+		if state.irq_received.swap(false, Ordering::SeqCst) {
+			state.waker.take();
+			Poll::Ready(())
+		} else {
+			Poll::Pending
+		}
+	}
+}
@@ -3,14 +3,14 @@
 
 extern crate alloc;
 
-use TEST::std::{ syscall, exit, sleep, multitasking };
+use TEST::std::{ exit, sleep, multitasking, env };
 use TEST::{ print, println };
 
 #[unsafe(no_mangle)]
-pub extern "C" fn _start() {
-    let mut task = multitasking::Task::new(user(), Some(5));
-    syscall::spawn_task((&mut task as *mut multitasking::Task) as u64);
-    exit();
+pub extern "C" fn _start(args_pointer: *const u64) -> ! {
+    env::init(args_pointer);
+    multitasking::spawn(multitasking::Task::new(user(), Some(5)));
+    multitasking::run();
 }
 
 async fn user() {
@@ -19,5 +19,5 @@ async fn user() {
         multitasking::cooperate().await;
         sleep(2000);
     }
-    exit();
+    exit(0);
 }
@@ -3,17 +3,18 @@
 
 extern crate alloc;
 
-use somnia::std::{ multitasking, syscall, exit };
+use somnia::std::{ multitasking, exit, env };
+use somnia::std::fs::{ self, File };
 use somnia::{ print, println };
 use alloc::vec::Vec;
 use alloc::format;
 use alloc::string::{ ToString, String };
 
 #[unsafe(no_mangle)]
-pub extern "C" fn _start() {
-    let mut task = multitasking::Task::new(user_main(), Some(5));
-    syscall::spawn_task((&mut task as *mut multitasking::Task) as u64);
-    exit();
+pub extern "C" fn _start(args_pointer: *const u64) -> ! {
+    env::init(args_pointer);
+    multitasking::spawn(multitasking::Task::new(user_main(), Some(5)));
+    multitasking::run();
 }
 
 
@@ -21,18 +22,20 @@ async fn user_main() {
     let txt = "SOMNIA shell 0.1";
     println!("{}", txt);
     print!(">");
-    let mut buf: Vec<char> = Vec::new();
+    let mut buf = [0u8; 64];
     let mut current_dir: String  = "/".to_string();
 	let mut input: String = "".to_string();
 	let mut current_command_buffer: Vec<char> = Vec::new();
+	// programs started with '&', with their paths
+	let mut jobs: Vec<(u64, String)> = Vec::new();
 
 	current_command_buffer.push('>');
     
     loop {
-    	somnia::std::read((&mut buf as *mut Vec<char>) as u64);
+    	let len = somnia::std::read(&mut buf).unwrap_or(0);
     	multitasking::cooperate().await;
 
-		for i in buf.clone() {
+		for i in core::str::from_utf8(&buf[..len]).unwrap_or("").chars() {
 			if i == '\n' {
 				println!("");
 				print!(">");
@@ -54,10 +57,10 @@ async fn user_main() {
 			}
 		}
 
-		buf.clear();
 		if input == "" {
 			continue;
 		}
+		reap_jobs(&mut jobs);
 
 		let parts = input.split(" ").collect::<Vec<&str>>();
 
@@ -80,7 +83,7 @@ async fn user_main() {
     				new_path = normalize_path(&cd);
     			}
     			
-    			if somnia::std::check_fs_entry_exists(&new_path) == 1 || &new_path == "/"{
+    			if somnia::std::check_fs_entry_exists(&new_path) == Ok(true) || &new_path == "/"{
   		  			current_dir = new_path;
     			}
     			else {
@@ -90,12 +93,23 @@ async fn user_main() {
     		},
     		
     		&"ls" => {
-    			let mut dir_contents:Vec<String> = Vec::new();
-    			somnia::std::ls(&current_dir, (&mut dir_contents as *mut Vec<String>) as u64);
-    			for i in dir_contents {
-    				print!("{}  ", i);
+    			let long = parts.get(1) == Some(&"-l");
+    			let mut dir_contents = [0u8; 2048];
+    			match somnia::std::ls(&current_dir, &mut dir_contents) {
+    				Ok(len) => {
+    					for i in core::str::from_utf8(&dir_contents[..len]).unwrap_or("").lines() {
+    						if long {
+    							print_long(&parse_path(&current_dir, i), i);
+    						} else {
+    							print!("{}  ", i);
+    						}
+    					}
+    					if !long {
+    						println!("");
+    					}
+    				}
+    				Err(e) => println!("ls: {}", e),
     			}
-    			println!("");
     			print!(">");
     		},
 
@@ -108,16 +122,14 @@ async fn user_main() {
     			}
     			
     			let mut new_path = parse_path(&current_dir, &mut parts[1].to_string());
-    			if somnia::std::check_fs_entry_exists(&new_path) == 0 {
-    				if parts[0] == "mkdir" {
-    					somnia::std::mkdir(&new_path);
-    				}
-    				else {
-    					somnia::std::mkfile(&new_path);
-    				}
+    			let result = if parts[0] == "mkdir" {
+    				somnia::std::mkdir(&new_path)
     			}
     			else {
-    				println!("dir or file with such name already exists");
+    				somnia::std::mkfile(&new_path)
+    			};
+    			if let Err(e) = result {
+    				println!("{}: {}", parts[0], e);
     				print!(">");
     			}
     		},
@@ -131,16 +143,14 @@ async fn user_main() {
     			}
     			
     			let mut path = parse_path(&current_dir, &mut parts[1].to_string());
-    			if somnia::std::check_fs_entry_exists(&path) == 1 {
-    				if parts[0] == "rmdir" {
-    					somnia::std::rmdir(&path);
-    				}
-    				else {
-    					somnia::std::rmfile(&path);
-    				}
+    			let result = if parts[0] == "rmdir" {
+    				somnia::std::rmdir(&path)
     			}
     			else {
-    				println!("dir or file with such name does not exist");
+    				somnia::std::rmfile(&path)
+    			};
+    			if let Err(e) = result {
+    				println!("{}: {}", parts[0], e);
     				print!(">");
     			}
     		},
@@ -160,12 +170,38 @@ async fn user_main() {
     			}
     			
 				let mut new_path = parse_path(&current_dir, &mut parts[1].to_string());
-    			if somnia::std::check_fs_entry_exists(&new_path) == 0 {
-    				somnia::std::mkfile(&new_path);
+    			if somnia::std::check_fs_entry_exists(&new_path) == Ok(false) {
+    				let _ = somnia::std::mkfile(&new_path);
     			}
 
     			let mut content = input.split("\"").collect::<Vec<&str>>()[1];
-    			somnia::std::write_file(&new_path, &content);
+    			if let Err(e) = somnia::std::write_file(&new_path, &content) {
+    				println!("write: {}", e);
+    				print!(">");
+    			}
+    		},
+
+    		&"mv" | &"cp" => {
+    			if parts.len() < 3 {
+    				println!("specify source and destination like '{} my_file /bin', a directory keeps the name", parts[0]);
+    				print!(">");
+    				input = "".to_string();
+    				continue
+    			}
+
+    			let source = parse_path(&current_dir, parts[1]);
+    			let destination = target_path(&source, &parse_path(&current_dir, parts[2]));
+    			let result = if destination == source {
+    				Err(somnia::std::Errno::InvalidArgument)
+    			} else if parts[0] == "mv" {
+    				fs::rename(&source, &destination)
+    			} else {
+    				fs::copy(&source, &destination).map(|_| ())
+    			};
+    			if let Err(e) = result {
+    				println!("{}: {}", parts[0], e);
+    			}
+    			print!(">");
     		},
 
     		&"read" => {
@@ -177,18 +213,70 @@ async fn user_main() {
     			}
 
     			let mut new_path = parse_path(&current_dir, &mut parts[1].to_string());
-				if somnia::std::check_fs_entry_exists(&new_path) == 1 {
-					let mut buffer = [0u8; 2024];
-					let buff_ptr = buffer.as_mut_ptr() as u64;
-					let len = somnia::std::read_file(&new_path, buff_ptr, buffer.len() as u64);
-					println!("{}", core::str::from_utf8(&buffer[..len as usize]).unwrap_or("[invalid utf8]"));
+				let mut content = Vec::new();
+				match File::open(&new_path).and_then(|mut file| file.read_to_end(&mut content)) {
+					Ok(_) => println!("{}", core::str::from_utf8(&content).unwrap_or("[invalid utf8]")),
+					Err(e) => println!("read: {}", e),
+				}
+    			print!(">");
+    		}
+
+    		&"disks" => {
+    			let mut listing = [0u8; 1024];
+    			match somnia::std::list_disks(&mut listing) {
+    				Ok(len) => {
+    					for line in core::str::from_utf8(&listing[..len]).unwrap_or("").lines() {
+    						let mut fields = line.splitn(3, ' ');
+    						let (name, sectors, model) = (fields.next().unwrap_or(""), fields.next().unwrap_or("0"), fields.next().unwrap_or(""));
+    						println!("{}  {} MiB  {}", name, sectors.parse::<u64>().unwrap_or(0) / 2048, model);
+    					}
+    				}
+    				Err(e) => println!("disks: {}", e),
+    			}
+    			print!(">");
+    		},
+
+    		&"mount" => {
+    			if parts.len() < 3 {
+    				println!("specify disk and directory like 'mount hdc /data', 'disks' lists the disks");
     				print!(">");
+    				input = "".to_string();
+    				continue
     			}
-    			else {
-    				println!("file not found");
+
+    			let path = parse_path(&current_dir, parts[2]);
+    			if let Err(e) = somnia::std::mount(parts[1], &path) {
+    				println!("mount: {}", e);
+    			}
+    			print!(">");
+    		},
+
+    		&"fsck" => {
+    			if parts.len() < 2 {
+    				println!("specify the disk like 'fsck hdc', add '-r' to repair it");
     				print!(">");
+    				input = "".to_string();
+    				continue
     			}
-    		}
+
+    			let mut report = [0u8; 4096];
+    			match somnia::std::check_disk(parts[1], parts.get(2) == Some(&"-r"), &mut report) {
+    				Ok(len) => print!("{}", core::str::from_utf8(&report[..len]).unwrap_or("")),
+    				Err(e) => println!("fsck: {}", e),
+    			}
+    			print!(">");
+    		},
+
+    		&"sync" => {
+    			if let Err(e) = somnia::std::sync() {
+    				println!("sync: {}", e);
+    			}
+    			print!(">");
+    		},
+
+    		&"shutdown" => {
+    			somnia::std::shutdown();
+    		},
 
     		&"clear" => {
 				somnia::std::clear_screen();
@@ -197,20 +285,28 @@ async fn user_main() {
 
     		&"run" => {
    				if parts.len() < 2 {
-    				println!("specify file name like 'run my_file'");
+    				println!("specify file name like 'run my_file arg1 arg2', add '&' to not wait for it");
     				print!(">");
     				input = "".to_string();
     				continue
     			}
     			
     			let mut path = parse_path(&current_dir, &mut parts[1].to_string());
-    			if somnia::std::check_fs_entry_exists(&path) == 1 {
-    				somnia::std::run(&path);
-    			}
-    			else {
-    				println!("file with such name does not exist");
-    				print!(">");
+    			let background = parts.last() == Some(&"&");
+    			let args_end = if background { parts.len() - 1 } else { parts.len() };
+    			let args = if args_end > 2 { &parts[2..args_end] } else { &[][..] };
+    			match somnia::std::run(&path, args) {
+    				Ok(pid) if background => {
+    					println!("[{}] {}", pid, path);
+    					jobs.push((pid, path.clone()));
+    				},
+    				Ok(pid) => match somnia::std::wait(pid) {
+    					Ok(status) => println!("{} exited with status {}", path, status),
+    					Err(e) => println!("wait: {}", e),
+    				},
+    				Err(e) => println!("run: {}", e),
     			}
+    			print!(">");
     		},
     		
   			_ => { 
@@ -221,7 +317,41 @@ async fn user_main() {
 
     	input = "".to_string();
     }
-    exit();
+    exit(0);
+}
+
+// Reaps the programs started with '&' that have exited, so the kernel can forget them
+fn reap_jobs(jobs: &mut Vec<(u64, String)>) {
+    jobs.retain(|(pid, path)| match somnia::std::try_wait(*pid) {
+        Ok(Some(status)) => {
+            println!("[{}] {} exited with status {}", pid, path, status);
+            false
+        }
+        Ok(None) => true,
+        Err(_) => false,
+    });
+}
+
+// Where `mv` and `cp` put the source: into the destination if that is a directory
+fn target_path(source: &str, destination: &str) -> String {
+    let is_directory = fs::metadata(destination).is_ok_and(|stat| stat.file_type == fs::STAT_DIRECTORY);
+    if !is_directory {
+        return destination.to_string();
+    }
+    let name = source.rsplit('/').next().unwrap_or(source);
+    if destination == "/" { format!("/{}", name) } else { format!("{}/{}", destination, name) }
+}
+
+// One line of `ls -l`: type, size, last write and name
+fn print_long(path: &str, name: &str) {
+    match fs::metadata(path) {
+        Ok(stat) => {
+            let kind = if stat.file_type == fs::STAT_DIRECTORY { 'd' } else { '-' };
+            let modified = if stat.modified.is_known() { format!("{}", stat.modified) } else { "-".to_string() };
+            println!("{} {:>10}  {:<19}  {}", kind, stat.size, modified, name);
+        }
+        Err(e) => println!("? {:>10}  {:<19}  {} ({})", "", "", name, e),
+    }
 }
 
 fn parse_path(current_dir: &str, name: &str) -> String {
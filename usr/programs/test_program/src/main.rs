@@ -3,15 +3,15 @@
 
 extern crate alloc;
 
-use TEST::std::{ syscall, exit, sleep, multitasking };
+use TEST::std::{ exit, sleep, multitasking, env };
 use TEST::{ print, println };
 
 #[unsafe(no_mangle)]
-pub extern "C" fn _start() {
+pub extern "C" fn _start(args_pointer: *const u64) -> ! {
+    env::init(args_pointer);
     println!("test");
-    let mut task = multitasking::Task::new(user(), Some(5));
-    syscall::spawn_task((&mut task as *mut multitasking::Task) as u64);
-    exit();
+    multitasking::spawn(multitasking::Task::new(user(), Some(5)));
+    multitasking::run();
 }
 
 async fn user() {
@@ -22,5 +22,5 @@ async fn user() {
         multitasking::cooperate().await;
         sleep(2000);
     }
-    exit();
+    exit(0);
 }
@@ -0,0 +1,60 @@
+// Arguments and environment of the program. The kernel leaves them at the top of the
+// stack in the SysV layout (argc, argv pointers, NULL, envp pointers, NULL) and passes
+// the address of argc to `_start`, which hands it to `init`. The strings stay there
+// for the whole life of the program, so they are handed out as 'static.
+
+static mut ARGS_POINTER: *const u64 = core::ptr::null();
+
+// Must be called first thing in `_start` with the pointer it received
+pub fn init(args_pointer: *const u64) {
+    unsafe { ARGS_POINTER = args_pointer };
+}
+
+unsafe fn c_str(pointer: *const u8) -> &'static str {
+    unsafe {
+        let mut len = 0;
+        while *pointer.add(len) != 0 {
+            len += 1;
+        }
+        core::str::from_utf8(core::slice::from_raw_parts(pointer, len)).unwrap_or("")
+    }
+}
+
+// The strings of a NULL terminated pointer array
+fn strings(mut pointer: *const u64) -> impl Iterator<Item = &'static str> {
+    core::iter::from_fn(move || unsafe {
+        if pointer.is_null() || *pointer == 0 {
+            return None;
+        }
+        let string = c_str(*pointer as *const u8);
+        pointer = pointer.add(1);
+        Some(string)
+    })
+}
+
+// Arguments of the program, the first one is its path
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let args_pointer = unsafe { ARGS_POINTER };
+    if args_pointer.is_null() {
+        return strings(core::ptr::null());
+    }
+    strings(unsafe { args_pointer.add(1) })
+}
+
+// `KEY=VALUE` entries of the environment, as they were passed to the program
+pub(crate) fn environment() -> impl Iterator<Item = &'static str> {
+    let args_pointer = unsafe { ARGS_POINTER };
+    if args_pointer.is_null() {
+        return strings(core::ptr::null());
+    }
+    let argc = unsafe { *args_pointer } as usize;
+    strings(unsafe { args_pointer.add(1 + argc + 1) })
+}
+
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> {
+    environment().filter_map(|entry| entry.split_once('='))
+}
+
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find(|&(name, _)| name == key).map(|(_, value)| value)
+}
@@ -0,0 +1,114 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use abi::Errno;
+pub use abi::{ DateTime, Stat, OPEN_APPEND, OPEN_CREATE, OPEN_READ, OPEN_TRUNCATE, OPEN_WRITE, STAT_DIRECTORY, STAT_FILE };
+use crate::std::syscall;
+
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+// An open file, closed when dropped
+pub struct File {
+    fd: u64,
+}
+
+impl File {
+    // Opens an existing file for reading
+    pub fn open(path: &str) -> Result<File, Errno> {
+        File::open_with(path, OPEN_READ)
+    }
+
+    // Opens the file for writing, it is created if needed and emptied otherwise
+    pub fn create(path: &str) -> Result<File, Errno> {
+        File::open_with(path, OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE)
+    }
+
+    // Opens the file for writing at its end, it is created if needed
+    pub fn append(path: &str) -> Result<File, Errno> {
+        File::open_with(path, OPEN_APPEND | OPEN_CREATE)
+    }
+
+    pub fn open_with(path: &str, flags: u64) -> Result<File, Errno> {
+        syscall::open(path, flags).map(|fd| File { fd })
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        syscall::read_fd(self.fd, buffer)
+    }
+
+    // Reads the rest of the file, returns the number of bytes added to the vector
+    pub fn read_to_end(&mut self, data: &mut Vec<u8>) -> Result<usize, Errno> {
+        let start = data.len();
+        let mut chunk = [0u8; 512];
+        loop {
+            let len = self.read(&mut chunk)?;
+            if len == 0 {
+                return Ok(data.len() - start);
+            }
+            data.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, Errno> {
+        syscall::write_fd(self.fd, data)
+    }
+
+    pub fn write_all(&mut self, mut data: &[u8]) -> Result<(), Errno> {
+        while !data.is_empty() {
+            let len = self.write(data)?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    pub fn seek(&mut self, position: SeekFrom) -> Result<u64, Errno> {
+        match position {
+            SeekFrom::Start(offset) => syscall::seek(self.fd, offset as i64, abi::SEEK_SET),
+            SeekFrom::Current(offset) => syscall::seek(self.fd, offset, abi::SEEK_CUR),
+            SeekFrom::End(offset) => syscall::seek(self.fd, offset, abi::SEEK_END),
+        }
+    }
+
+    pub fn metadata(&self) -> Result<Stat, Errno> {
+        syscall::fstat(self.fd)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = syscall::close(self.fd);
+    }
+}
+
+// Metadata of the file or directory at the path, without opening it
+pub fn metadata(path: &str) -> Result<Stat, Errno> {
+    syscall::stat(path)
+}
+
+// Moves a file or directory within its file system, `to` must not exist
+pub fn rename(from: &str, to: &str) -> Result<(), Errno> {
+    syscall::rename(from, to)
+}
+
+// Copies the content of a file to `to`, which is created or replaced. Returns the
+// number of bytes copied.
+pub fn copy(from: &str, to: &str) -> Result<u64, Errno> {
+    let mut source = File::open(from)?;
+    if source.metadata()?.file_type == STAT_DIRECTORY {
+        return Err(Errno::IsADirectory);
+    }
+    let mut destination = File::create(to)?;
+    let mut chunk = vec![0u8; 16 * 1024];
+    let mut copied = 0;
+    loop {
+        let len = source.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(copied);
+        }
+        destination.write_all(&chunk[..len])?;
+        copied += len as u64;
+    }
+}
@@ -1,4 +1,6 @@
 pub mod syscall;
+pub mod env;
+pub mod fs;
 pub mod io;
 pub mod mem;
 pub mod sysalloc;
@@ -6,5 +8,7 @@ pub mod time;
 pub mod multitasking;
 
 pub use syscall::*;
+pub use abi::errno;
+pub use abi::Errno;
 pub use sysalloc::SysAllocator;
 pub use time::sleep;
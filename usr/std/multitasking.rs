@@ -1,8 +1,13 @@
 use core::{ future::Future, pin::Pin };
-use core::task::{ Context, Poll };
+use core::task::{ Context, Poll, Waker };
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use crate::std::syscall;
 
+// Tasks spawned since the last round of `run`. They run in the address space of the
+// program, the kernel only sees the program itself.
+static mut SPAWNED: Vec<Task> = Vec::new();
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskId(pub u64);
 
@@ -61,3 +66,28 @@ impl Future for YieldNow {
 pub async fn cooperate() {
 	YieldNow::new().await;
 }
+
+pub fn spawn(task: Task) {
+	#[allow(static_mut_refs)]
+	unsafe { SPAWNED.push(task) };
+}
+
+// Polls the tasks of the program until all of them are done, giving the CPU
+// back to the kernel after every round. Exits the program at the end.
+pub fn run() -> ! {
+	let mut context = Context::from_waker(Waker::noop());
+	let mut tasks: Vec<Task> = Vec::new();
+	loop {
+		#[allow(static_mut_refs)]
+		tasks.append(unsafe { &mut SPAWNED });
+		if tasks.is_empty() {
+			break;
+		}
+
+		tasks.retain_mut(|task| task.poll(&mut context).is_pending());
+		syscall::yield_now();
+	}
+
+	syscall::exit(0);
+	unreachable!();
+}
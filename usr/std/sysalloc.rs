@@ -1,14 +1,107 @@
 use core::alloc::{GlobalAlloc, Layout};
-use crate::std::syscall::{syscall_alloc, syscall_dealloc};
+use core::mem;
+use core::ptr;
+use spin::Mutex;
+use crate::std::syscall::brk;
+
+// The heap of the program: a list of free regions kept in the regions themselves, in
+// pages the kernel maps at the end of the heap when asked to. Only the program reads
+// and writes the list, so overwriting a freed block breaks nothing but the program.
+
+// Bytes the heap grows by at least
+const HEAP_GROW: usize = 64 * 1024;
+
+struct FreeRegion {
+    size: usize,
+    next: *mut FreeRegion,
+}
+
+struct Heap {
+    head: *mut FreeRegion,
+    // End of the mapped heap, 0 until the kernel was asked for it
+    end: usize,
+}
+
+// The list is only reached through the lock
+unsafe impl Send for Heap {}
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap { head: ptr::null_mut(), end: 0 });
+
+impl Heap {
+    unsafe fn add_free_region(&mut self, address: usize, size: usize) {
+        let region = address as *mut FreeRegion;
+        unsafe { region.write(FreeRegion { size, next: self.head }) };
+        self.head = region;
+    }
+
+    // Takes the first region the allocation fits in off the list and returns where the
+    // allocation starts. What is left behind it goes back on the list.
+    unsafe fn take(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut link: *mut *mut FreeRegion = &mut self.head;
+        unsafe {
+            while !(*link).is_null() {
+                let region = *link;
+                let region_end = region as usize + (*region).size;
+                let start = (region as usize).next_multiple_of(align);
+                let fits = start.checked_add(size).filter(|&end| end <= region_end).map(|end| region_end - end);
+                // a rest too small to hold a region would be lost
+                if let Some(rest) = fits.filter(|&rest| rest == 0 || rest >= mem::size_of::<FreeRegion>()) {
+                    *link = (*region).next;
+                    if rest > 0 {
+                        self.add_free_region(region_end - rest, rest);
+                    }
+                    return Some(start);
+                }
+                link = &mut (*region).next;
+            }
+        }
+        None
+    }
+
+    // Has the kernel map at least `needed` more bytes and puts them on the list
+    fn grow(&mut self, needed: usize) -> bool {
+        if self.end == 0 {
+            self.end = brk(0) as usize;
+        }
+        let start = self.end;
+        let end = brk(start.saturating_add(needed.max(HEAP_GROW)) as u64) as usize;
+        if end <= start {
+            return false;
+        }
+        self.end = end;
+        unsafe { self.add_free_region(start, end - start) };
+        true
+    }
+}
+
+// Every block can hold a region once it is freed
+fn size_align(layout: Layout) -> Option<(usize, usize)> {
+    let layout = layout.align_to(mem::align_of::<FreeRegion>()).ok()?.pad_to_align();
+    Some((layout.size().max(mem::size_of::<FreeRegion>()), layout.align()))
+}
 
 pub struct SysAllocator;
 
 unsafe impl GlobalAlloc for SysAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        syscall_alloc(layout.size() as u64, layout.align() as u64) as *mut u8
+        let Some((size, align)) = size_align(layout) else {
+            return ptr::null_mut();
+        };
+        let mut heap = HEAP.lock();
+        unsafe {
+            if let Some(start) = heap.take(size, align) {
+                return start as *mut u8;
+            }
+            if !heap.grow(size.saturating_add(align)) {
+                return ptr::null_mut();
+            }
+            heap.take(size, align).map_or(ptr::null_mut(), |start| start as *mut u8)
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        syscall_dealloc(ptr as u64, layout.size() as u64, layout.align() as u64)
+        if let Some((size, _)) = size_align(layout) {
+            unsafe { HEAP.lock().add_free_region(ptr as usize, size) };
+        }
     }
 }
@@ -1,100 +1,127 @@
-#[repr(u64)]
-pub enum SyscallNumber {
-    Write = 1,
-    Exit = 2,
-    Read = 3,
-    RemoveChar = 4,
-    SpawnTask = 5,
-    Alloc = 6,
-    Dealloc = 7,
-    CheckFsEntryExists = 8,
-    GenerateTaskId = 9,
-    ListDir = 10,
-    MakeDir = 11,
-    MakeFile = 12,
-    WriteFile = 13,
-    ReadFile = 14,
-    RemoveDir = 15,
-    RemoveFile = 16,
-    Run = 17,
-    ClearScreen = 18,
-}
+use alloc::vec::Vec;
+use abi::errno::{ self, Errno };
+use abi::{ CheckArgs, RunArgs, Stat };
+pub use abi::SyscallNumber;
+use crate::std::env;
 
 pub fn syscall(n: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> u64 {
     let ret: u64;
     unsafe {
         core::arch::asm!(
-            "int 0x80",
+            "syscall",
             in("rax") n,
             in("rdi") arg1,
             in("rsi") arg2,
             in("rdx") arg3,
             in("r8") arg4,
             lateout("rax") ret,
+            // rip and rflags of the program are saved there
+            lateout("rcx") _,
+            lateout("r11") _,
             options(nostack)
         );
     }
     ret
 }
 
-pub fn exit() {
-    syscall(SyscallNumber::Exit as u64, 0, 0, 0, 0);
+pub fn exit(code: i64) {
+    syscall(SyscallNumber::Exit as u64, code as u64, 0, 0, 0);
+}
+
+// Blocks until the child exits and returns its exit status
+pub fn wait(pid: u64) -> Result<i64, Errno> {
+    let mut status: i64 = 0;
+    errno::decode(syscall(SyscallNumber::Wait as u64, pid, (&mut status as *mut i64) as u64, 0, 0))?;
+    Ok(status)
+}
+
+// Exit status of the child if it has exited, None while it is still running
+pub fn try_wait(pid: u64) -> Result<Option<i64>, Errno> {
+    let mut status: i64 = 0;
+    let result = errno::decode(syscall(SyscallNumber::Wait as u64, pid, (&mut status as *mut i64) as u64, abi::WAIT_NOHANG, 0))?;
+    Ok((result != 0).then_some(status))
 }
 
-pub fn syscall_alloc(size: u64, align: u64) -> u64 {
-    syscall(SyscallNumber::Alloc as u64, size, align, 0, 0)
+pub fn getpid() -> u64 {
+    syscall(SyscallNumber::GetPid as u64, 0, 0, 0, 0)
 }
 
-pub fn syscall_dealloc(ptr: u64, size: u64, align: u64) {
-    syscall(SyscallNumber::Dealloc as u64, ptr, size, align, 0);
+// Has the kernel map the heap up to `end` and returns where it ends now, 0 only asks
+pub fn brk(end: u64) -> u64 {
+    syscall(SyscallNumber::Brk as u64, end, 0, 0, 0)
 }
 
-pub fn spawn_task(task_addr: u64) {
-    syscall(SyscallNumber::SpawnTask as u64, task_addr, 0, 0, 0);
+// Gives the CPU to the other tasks of the kernel, returns when the program is scheduled again
+pub fn yield_now() {
+    syscall(SyscallNumber::Yield as u64, 0, 0, 0, 0);
 }
 
-pub fn read(buffer: u64) -> u64 {
-    syscall(SyscallNumber::Read as u64, buffer, 0, 0, 0)
+// Fills the buffer with UTF-8 encoded keyboard input, returns the number of bytes written
+pub fn read(buffer: &mut [u8]) -> Result<usize, Errno> {
+    errno::decode(syscall(SyscallNumber::Read as u64, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0, 0)).map(|len| len as usize)
 }
 
 pub fn rm_char() -> u64 {
     syscall(SyscallNumber::RemoveChar as u64, 0, 0, 0, 0)
 }
 
-pub fn check_fs_entry_exists(path: &str) -> u64 {
-	syscall(SyscallNumber::CheckFsEntryExists as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)
+pub fn check_fs_entry_exists(path: &str) -> Result<bool, Errno> {
+	errno::decode(syscall(SyscallNumber::CheckFsEntryExists as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)).map(|exists| exists == 1)
 }
 
-pub fn ls(path: &str, buffer: u64) -> u64 {
-	syscall(SyscallNumber::ListDir as u64, path.as_ptr() as u64, path.len() as u64, buffer, 0)
+// Fills the buffer with the names of the directory entries, one per line
+pub fn ls(path: &str, buffer: &mut [u8]) -> Result<usize, Errno> {
+	errno::decode(syscall(SyscallNumber::ListDir as u64, path.as_ptr() as u64, path.len() as u64, buffer.as_mut_ptr() as u64, buffer.len() as u64)).map(|len| len as usize)
 }
 
-pub fn mkdir(path: &str) -> u64 {
-	syscall(SyscallNumber::MakeDir as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)
+pub fn mkdir(path: &str) -> Result<(), Errno> {
+	errno::decode(syscall(SyscallNumber::MakeDir as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)).map(|_| ())
 }
 
-pub fn mkfile(path: &str) -> u64 {
-	syscall(SyscallNumber::MakeFile as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)
+pub fn mkfile(path: &str) -> Result<(), Errno> {
+	errno::decode(syscall(SyscallNumber::MakeFile as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)).map(|_| ())
 }
 
-pub fn write_file(path: &str, content: &str) -> u64 {
-	syscall(SyscallNumber::WriteFile as u64, path.as_ptr() as u64, path.len() as u64, content.as_ptr() as u64, content.len() as u64)
+pub fn write_file(path: &str, content: &str) -> Result<(), Errno> {
+	errno::decode(syscall(SyscallNumber::WriteFile as u64, path.as_ptr() as u64, path.len() as u64, content.as_ptr() as u64, content.len() as u64)).map(|_| ())
 }
 
-pub fn read_file(path: &str, buffer: u64, len: u64) -> u64 {
-	syscall(SyscallNumber::ReadFile as u64, path.as_ptr() as u64, path.len() as u64, buffer, len)
+// Reads the start of the file into the buffer, returns the number of bytes read
+pub fn read_file(path: &str, buffer: &mut [u8]) -> Result<usize, Errno> {
+	errno::decode(syscall(SyscallNumber::ReadFile as u64, path.as_ptr() as u64, path.len() as u64, buffer.as_mut_ptr() as u64, buffer.len() as u64)).map(|len| len as usize)
 }
 
-pub fn rmdir(path: &str) -> u64 {
-	syscall(SyscallNumber::RemoveDir as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)
+pub fn rmdir(path: &str) -> Result<(), Errno> {
+	errno::decode(syscall(SyscallNumber::RemoveDir as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)).map(|_| ())
 }
 
-pub fn rmfile(path: &str) -> u64 {
-	syscall(SyscallNumber::RemoveFile as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)
+pub fn rmfile(path: &str) -> Result<(), Errno> {
+	errno::decode(syscall(SyscallNumber::RemoveFile as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)).map(|_| ())
 }
 
-pub fn run(path: &str) -> u64 {
-	syscall(SyscallNumber::Run as u64, path.as_ptr() as u64, path.len() as u64, 0, 0)
+// Starts the program as a child with the arguments, the path is passed as the first
+// one. The child gets the environment of the running program. Returns its pid.
+pub fn run(path: &str, args: &[&str]) -> Result<u64, Errno> {
+    let mut argv = Vec::new();
+    for arg in core::iter::once(path).chain(args.iter().copied()) {
+        argv.extend_from_slice(arg.as_bytes());
+        argv.push(0);
+    }
+    let mut env = Vec::new();
+    for entry in env::environment() {
+        env.extend_from_slice(entry.as_bytes());
+        env.push(0);
+    }
+
+    let run_args = RunArgs {
+        path_ptr: path.as_ptr() as u64,
+        path_len: path.len() as u64,
+        argv_ptr: argv.as_ptr() as u64,
+        argv_len: argv.len() as u64,
+        env_ptr: env.as_ptr() as u64,
+        env_len: env.len() as u64,
+    };
+    errno::decode(syscall(SyscallNumber::Run as u64, (&run_args as *const RunArgs) as u64, 0, 0, 0))
 }
 
 pub fn get_task_id() -> u64 {
@@ -105,3 +132,78 @@ pub fn clear_screen() -> u64 {
     syscall(SyscallNumber::ClearScreen as u64, 0, 0, 0, 0)
 }
 
+
+// Opens the file with the `OPEN_*` flags and returns its descriptor
+pub fn open(path: &str, flags: u64) -> Result<u64, Errno> {
+    errno::decode(syscall(SyscallNumber::Open as u64, path.as_ptr() as u64, path.len() as u64, flags, 0))
+}
+
+pub fn close(fd: u64) -> Result<(), Errno> {
+    errno::decode(syscall(SyscallNumber::Close as u64, fd, 0, 0, 0)).map(|_| ())
+}
+
+// Reads from the offset of the descriptor, which moves past the bytes read
+pub fn read_fd(fd: u64, buffer: &mut [u8]) -> Result<usize, Errno> {
+    errno::decode(syscall(SyscallNumber::ReadFd as u64, fd, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0)).map(|len| len as usize)
+}
+
+pub fn write_fd(fd: u64, data: &[u8]) -> Result<usize, Errno> {
+    errno::decode(syscall(SyscallNumber::WriteFd as u64, fd, data.as_ptr() as u64, data.len() as u64, 0)).map(|len| len as usize)
+}
+
+// Moves the offset of the descriptor relative to `SEEK_*`, returns the new offset
+pub fn seek(fd: u64, offset: i64, whence: u64) -> Result<u64, Errno> {
+    errno::decode(syscall(SyscallNumber::Seek as u64, fd, offset as u64, whence, 0))
+}
+
+pub fn fstat(fd: u64) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    errno::decode(syscall(SyscallNumber::Fstat as u64, fd, (&mut stat as *mut Stat) as u64, 0, 0))?;
+    Ok(stat)
+}
+
+// Type, size and times of the file or directory at the path
+pub fn stat(path: &str) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    errno::decode(syscall(SyscallNumber::Stat as u64, path.as_ptr() as u64, path.len() as u64, (&mut stat as *mut Stat) as u64, 0))?;
+    Ok(stat)
+}
+
+// Moves a file or directory within its file system, the new path must not exist
+pub fn rename(old: &str, new: &str) -> Result<(), Errno> {
+    errno::decode(syscall(SyscallNumber::Rename as u64, old.as_ptr() as u64, old.len() as u64, new.as_ptr() as u64, new.len() as u64)).map(|_| ())
+}
+
+// Writes everything the kernel still holds in memory to the disks
+pub fn sync() -> Result<(), Errno> {
+    errno::decode(syscall(SyscallNumber::Sync as u64, 0, 0, 0, 0)).map(|_| ())
+}
+
+// Syncs the file systems and turns the machine off
+pub fn shutdown() -> ! {
+    syscall(SyscallNumber::Shutdown as u64, 0, 0, 0, 0);
+    unreachable!()
+}
+
+// Mounts the FAT32 volume of a disk (as listed by `list_disks`) at the path
+pub fn mount(disk: &str, path: &str) -> Result<(), Errno> {
+    errno::decode(syscall(SyscallNumber::Mount as u64, disk.as_ptr() as u64, disk.len() as u64, path.as_ptr() as u64, path.len() as u64)).map(|_| ())
+}
+
+// Fills the buffer with a `name sectors model` line per disk
+pub fn list_disks(buffer: &mut [u8]) -> Result<usize, Errno> {
+    errno::decode(syscall(SyscallNumber::ListDisks as u64, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0, 0)).map(|len| len as usize)
+}
+
+// Checks the FAT32 volume of a disk, repairing it with `repair`. Fills the buffer with a
+// line per problem found and a summary line.
+pub fn check_disk(disk: &str, repair: bool, buffer: &mut [u8]) -> Result<usize, Errno> {
+    let check_args = CheckArgs {
+        disk_ptr: disk.as_ptr() as u64,
+        disk_len: disk.len() as u64,
+        output_ptr: buffer.as_mut_ptr() as u64,
+        output_len: buffer.len() as u64,
+        flags: if repair { abi::CHECK_REPAIR } else { 0 },
+    };
+    errno::decode(syscall(SyscallNumber::Check as u64, (&check_args as *const CheckArgs) as u64, 0, 0, 0)).map(|len| len as usize)
+}